@@ -0,0 +1,313 @@
+use std::{any::Any, collections::HashMap, fmt, sync::{Arc, Mutex}, time::Duration};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+
+use crate::{clock::{self, Clock}, event::{self, Event}, runtime::{self, Inbox, Runtime, WorkerState}, watchdog::Health};
+
+/*
+ * An actor is a worker whose handler owns some state and is only reached through its
+ * mailbox, a bounded channel of letters. A letter sent with `tell` carries just the msg, one
+ * sent with `ask` also carries a channel for the reply. The actor handles one letter at a time
+ * on its own thread so its state needs no locking.
+ * A Registry starts actors under unique names and hands out refs to them by name, from any
+ * thread including other actors that were given a clone of it.
+ */
+
+/// Private state along with how it handles msgs
+pub trait Actor: Send + 'static {
+    type Msg: Send + 'static;
+    type Reply: Send + 'static;
+    /// handle a msg, the reply goes back to an `ask` and is dropped for a `tell`
+    fn handle(&mut self, msg: Self::Msg) -> Self::Reply;
+}
+
+/// Why an `ask` got no reply
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AskError {
+    /// the mailbox stayed full or no reply came in time
+    Timeout,
+    /// the actor was stopped
+    Stopped,
+}
+
+impl fmt::Display for AskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AskError::Timeout => write!(f, "no reply in time"),
+            AskError::Stopped => write!(f, "actor is stopped"),
+        }
+    }
+}
+
+impl std::error::Error for AskError {}
+
+struct Letter<A: Actor> {
+    msg: A::Msg,
+    reply: Option<Sender<A::Reply>>, // None for a tell
+}
+
+/// Address of a running actor, cheap to clone and share between threads
+pub struct ActorRef<A: Actor> {
+    nm: String,
+    tx: Sender<Letter<A>>,
+    clock: Arc<dyn Clock>, // times out sends and asks
+}
+
+impl<A: Actor> Clone for ActorRef<A> {
+    fn clone(&self) -> Self {
+        Self { nm: self.nm.clone(), tx: self.tx.clone(), clock: self.clock.clone() }
+    }
+}
+
+impl<A: Actor> ActorRef<A> {
+    pub fn nm(&self) -> &str { &self.nm }
+
+    /// put the msg in the mailbox without waiting for it to be handled,
+    /// false if the actor is stopped or its mailbox stayed full for the send timeout
+    pub fn tell(&self, msg: A::Msg) -> bool {
+        self.post(Letter { msg, reply: None }, Duration::from_millis(100)).is_ok()
+    }
+
+    /// put the msg in the mailbox and wait for the reply, all within the timeout
+    pub fn ask(&self, msg: A::Msg, timeout: Duration) -> Result<A::Reply, AskError> {
+        let deadline = self.clock.now() + timeout;
+        let (t, r) = crossbeam_channel::bounded(1);
+        self.post(Letter { msg, reply: Some(t) }, timeout)?;
+        let left = deadline.saturating_duration_since(self.clock.now());
+        clock::recv_timeout(&*self.clock, &r, left, || false).map_err(|error| match error {
+            RecvTimeoutError::Timeout => AskError::Timeout,
+            // dropped unanswered when the actor stopped
+            RecvTimeoutError::Disconnected => AskError::Stopped,
+        })
+    }
+
+    fn post(&self, letter: Letter<A>, timeout: Duration) -> Result<(), AskError> {
+        clock::send_timeout(&*self.clock, &self.tx, letter, timeout).map_err(|error| {
+            event::emit(Event::Error { source: format!("actor '{}'", self.nm), error: error.to_string() });
+            if error.is_disconnected() { AskError::Stopped } else { AskError::Timeout }
+        })
+    }
+}
+
+/// Letters waiting for an actor
+struct Mailbox<A: Actor> {
+    rx: Receiver<Letter<A>>,
+    clock: Arc<dyn Clock>, // times out waits
+    disconnected: bool, // every ref is gone and nothing is left
+}
+
+impl<A: Actor> Inbox for Mailbox<A> {
+    type Item = Letter<A>;
+
+    fn recv(&mut self, worker: &WorkerState, timeout: Duration) -> Option<Letter<A>> {
+        match runtime::recv_gated(&*self.clock, &self.rx, timeout, &[&worker.pause], || worker.stopped()) {
+            Ok(letter) => {
+                worker.took_job();
+                Some(letter)
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                self.disconnected = true;
+                None
+            },
+            Err(RecvTimeoutError::Timeout) => None,
+        }
+    }
+
+    fn closed(&self) -> bool { self.disconnected }
+}
+
+struct Entry {
+    addr: Box<dyn Any + Send + Sync>, // the actor's ActorRef
+    runtime: Runtime,
+}
+
+impl Entry {
+    /// an actor stopped from its own thread is only told to exit, joining would wait on itself
+    fn stop(self) {
+        if self.runtime.is_current() {
+            self.runtime.interrupt();
+        } else {
+            self.runtime.stop();
+        }
+    }
+}
+
+/// Running actors by name, clones share the same actors
+#[derive(Clone)]
+pub struct Registry {
+    actors: Arc<Mutex<HashMap<String, Entry>>>,
+    clock: Arc<dyn Clock>, // given to every actor started
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::with_clock(clock::real())
+    }
+
+    /// create a registry whose actors time their mailboxes, tells and asks by the clock
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self { actors: Arc::default(), clock }
+    }
+
+    /// start the actor under the name with a mailbox of `capacity` letters,
+    /// None if the name is already taken
+    pub fn spawn<A: Actor>(&self, nm: &str, mut actor: A, capacity: usize) -> Option<ActorRef<A>> {
+        let mut actors = self.actors.lock().unwrap();
+        if actors.contains_key(nm) {
+            return None;
+        }
+        let (tx, rx) = crossbeam_channel::bounded(capacity.max(1));
+        let addr = ActorRef { nm: nm.to_string(), tx, clock: self.clock.clone() };
+        let mailbox = Mailbox::<A> { rx, clock: self.clock.clone(), disconnected: false };
+        // the actor's handler can't be cancelled
+        let state = WorkerState::new(Health::new(nm, || {}));
+        let runtime = Runtime::run(state, mailbox, move |_, _, letter: Letter<A>| {
+            let reply = actor.handle(letter.msg);
+            if let Some(t) = letter.reply {
+                // the asker may have given up already
+                let _ = t.try_send(reply);
+            }
+        });
+        actors.insert(nm.to_string(), Entry { addr: Box::new(addr.clone()), runtime });
+        Some(addr)
+    }
+
+    /// ref to the named actor, None if there is none or it is a different kind of actor
+    pub fn lookup<A: Actor>(&self, nm: &str) -> Option<ActorRef<A>> {
+        self.actors.lock().unwrap().get(nm)?.addr.downcast_ref::<ActorRef<A>>().cloned()
+    }
+
+    /// names of the running actors
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.actors.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// msgs handled by the named actor so far
+    pub fn get_cnt(&self, nm: &str) -> Option<u32> {
+        Some(self.actors.lock().unwrap().get(nm)?.runtime.get_cnt())
+    }
+
+    /// stop the named actor once it is done with its current msg, letters still in its mailbox are dropped
+    /// false if there is no such actor, an actor stopping itself isn't waited for
+    pub fn stop(&self, nm: &str) -> bool {
+        let Some(entry) = self.actors.lock().unwrap().remove(nm) else { return false };
+        entry.stop();
+        true
+    }
+
+    /// stop every actor
+    pub fn shutdown(&self) {
+        let actors: Vec<Entry> = self.actors.lock().unwrap().drain().map(|(_, e)| e).collect();
+        for entry in actors {
+            entry.stop();
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Keeps a running total
+#[cfg(test)]
+struct Counter(u32);
+
+#[cfg(test)]
+impl Actor for Counter {
+    type Msg = u32;
+    type Reply = u32;
+
+    fn handle(&mut self, msg: u32) -> u32 {
+        self.0 += msg;
+        self.0
+    }
+}
+
+#[test]
+fn test_tell_and_ask() {
+    let registry = Registry::new();
+    let counter = registry.spawn("counter", Counter(0), 4).unwrap();
+    assert!(counter.tell(5));
+    assert!(counter.tell(3));
+    // handled in the order sent, so the ask sees both tells
+    assert_eq!(counter.ask(0, Duration::from_secs(1)), Ok(8));
+    assert_eq!(registry.get_cnt("counter"), Some(3));
+    registry.shutdown();
+    assert!(!counter.tell(1));
+    assert_eq!(counter.ask(0, Duration::from_secs(1)), Err(AskError::Stopped));
+}
+
+#[test]
+fn test_ask_timeout() {
+    struct Slow;
+    impl Actor for Slow {
+        type Msg = ();
+        type Reply = ();
+        fn handle(&mut self, _: ()) {
+            std::thread::sleep(Duration::from_millis(200));
+        }
+    }
+
+    let registry = Registry::new();
+    let slow = registry.spawn("slow", Slow, 1).unwrap();
+    assert_eq!(slow.ask((), Duration::from_millis(50)), Err(AskError::Timeout));
+    // the mailbox is free again once it took the first letter
+    assert_eq!(slow.ask((), Duration::from_secs(1)), Ok(()));
+    registry.shutdown();
+}
+
+#[test]
+fn test_registry_lookup() {
+    /// Adds to the counter it looks up by name
+    struct Adder(Registry);
+    impl Actor for Adder {
+        type Msg = u32;
+        type Reply = Option<u32>;
+        fn handle(&mut self, msg: u32) -> Option<u32> {
+            self.0.lookup::<Counter>("counter")?.ask(msg, Duration::from_secs(1)).ok()
+        }
+    }
+
+    let registry = Registry::new();
+    registry.spawn("counter", Counter(10), 4).unwrap();
+    // names are unique
+    assert!(registry.spawn("counter", Counter(0), 4).is_none());
+    let adder = registry.spawn("adder", Adder(registry.clone()), 4).unwrap();
+    assert_eq!(registry.names(), vec!["adder".to_string(), "counter".to_string()]);
+    assert_eq!(adder.ask(5, Duration::from_secs(1)), Ok(Some(15)));
+    // looked up by name and kind
+    assert_eq!(registry.lookup::<Counter>("counter").unwrap().ask(1, Duration::from_secs(1)), Ok(16));
+    assert!(registry.lookup::<Adder>("counter").is_none());
+    assert!(registry.lookup::<Counter>("nobody").is_none());
+    assert!(registry.stop("counter"));
+    assert!(!registry.stop("counter"));
+    assert!(registry.lookup::<Counter>("counter").is_none());
+    assert_eq!(adder.ask(1, Duration::from_secs(1)), Ok(None));
+    registry.shutdown();
+}
+
+#[test]
+fn test_actor_stops_itself() {
+    /// Stops itself on the msg it is told to quit with
+    struct Quitter(Registry);
+    impl Actor for Quitter {
+        type Msg = bool;
+        type Reply = bool;
+        fn handle(&mut self, quit: bool) -> bool {
+            quit && self.0.stop("quitter")
+        }
+    }
+
+    let registry = Registry::new();
+    let quitter = registry.spawn("quitter", Quitter(registry.clone()), 4).unwrap();
+    assert_eq!(quitter.ask(false, Duration::from_secs(1)), Ok(false));
+    // returns rather than wait on its own thread
+    assert_eq!(quitter.ask(true, Duration::from_secs(1)), Ok(true));
+    assert!(registry.names().is_empty());
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(quitter.ask(false, Duration::from_secs(1)), Err(AskError::Stopped));
+}
@@ -1,65 +1,70 @@
 use crossbeam_channel::Sender;
-use std::{sync::{Arc, Mutex, atomic::AtomicBool}, thread::JoinHandle, time::Duration};
+use std::{sync::Arc, time::Duration};
+
+use crate::{clock::{self, Clock, RealClock}, event::{self, Event}, runtime::{ChannelInbox, Dispatch, FanOut, Runtime, ThreadOptions, WorkerState, sync::AtomicBool}, single::CancelToken, watchdog::{Health, Watched}};
+
+mod log;
+mod tcp;
+
+pub use log::OffsetLog;
+pub use tcp::TcpReceiver;
 
 pub struct Worker {
-    tx: Sender<(String, usize)>,
-    rec_cnt: Arc<Mutex<u32>>, // counter of messages received
-    handle: JoinHandle<()>, // worker thread handle
-    interrupt: Arc<AtomicBool>, // signal to thread to exit
+    tx: Option<Sender<(String, usize)>>, // None when the worker reads from a log instead
+    runtime: Runtime, // worker thread, its counter and stop signal
+    clock: Arc<dyn Clock>, // times out sends
+}
+
+/// state of a worker whose current msg is cancelled through the flag
+fn job_state(nm: &str) -> (WorkerState, Arc<AtomicBool>) {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let cancel_ = cancel.clone();
+    (WorkerState::new(Health::new(nm, move || cancel_.store(true, std::sync::atomic::Ordering::SeqCst))), cancel)
+}
+
+/// pretend to do lengthy work, cut short if the msg is cancelled
+fn delay(cancel: &Arc<AtomicBool>, clock: &Arc<dyn Clock>) {
+    CancelToken::new(vec![cancel.clone()], clock.clone()).sleep(Duration::from_secs(2));
 }
 
 impl Worker {
     pub fn new(nm : String, do_delay: bool) -> Self {
-        let (t, r) = crossbeam_channel::bounded(1);
-        let ctr = Arc::new(Mutex::new(0));
-        let intr = Arc::new(AtomicBool::new(false));
-        let intr_ = intr.clone();
-        let retval = Self {
-            tx: t,
-            rec_cnt: ctr.clone(),
-            interrupt: intr_,
-            handle: std::thread::spawn(move || {
-                println!("Creating worker: {:?}", nm);
-                let chk_stop = intr.clone();
-                loop {
-                    let message = r.recv_timeout(Duration::from_millis(500));
-                    match message {
-                        Ok(msg) => {
-                            println!("  worker '{}' | received msg # {} : {}", nm, msg.1, msg.0);
-                            {
-                                // increment rec counter
-                                let mut n = ctr.lock().unwrap();
-                                *n += 1;
-                            }
-                        },
-                        Err(_) => {
-                            // recv timeout
-                            if chk_stop.load(std::sync::atomic::Ordering::SeqCst) {
-                                // thread signalled to stop
-                                break;
-                            }
-                            continue;
-                        }
-                    }
-                    if do_delay {
-                        // pretend to do lengthy work
-                        std::thread::sleep(Duration::from_secs(2));
-                    }
-                }
-            }),
-        };
+        Self::with_clock(nm, do_delay, Arc::new(RealClock))
+    }
 
-        retval
+    /// create a worker that times its polling, sends to it and its delay by the clock
+    pub fn with_clock(nm : String, do_delay: bool, clock: Arc<dyn Clock>) -> Self {
+        Self::with_options(nm, do_delay, clock, ThreadOptions::default())
+    }
+
+    /// create a worker like `with_clock` whose thread is started with the options
+    pub fn with_options(nm : String, do_delay: bool, clock: Arc<dyn Clock>, thread: ThreadOptions) -> Self {
+        let (t, r) = crossbeam_channel::bounded(1);
+        let (mut state, cancel) = job_state(&nm);
+        state.thread = thread;
+        let clock_ = clock.clone();
+        let runtime = Runtime::run(state, ChannelInbox::new(r, clock.clone()), move |_, _, _| {
+            cancel.store(false, std::sync::atomic::Ordering::SeqCst);
+            if do_delay {
+                delay(&cancel, &clock_);
+            }
+        });
+        Self { tx: Some(t), runtime, clock }
     }
 
     pub fn send(&self, msg: (String,usize)) -> bool {
-        println!("  main        | sending msg # {} : {}", msg.1, msg.0);
-        let result = self.tx.send_timeout((msg.0, msg.1), Duration::from_millis(100));
+        let Some(tx) = &self.tx else {
+            event::emit(Event::SendFailed { msg, error: "worker reads from a log".to_string() });
+            return false;
+        };
+        event::emit(Event::MessageSent { msg: msg.clone() });
+        let result = clock::send_timeout(&*self.clock, tx, (msg.0, msg.1), Duration::from_millis(100));
         let mut retval = true;
         match result {
             Ok(_) => {},
             Err(error) => {
-                println!("Send error: {}", error);
+                let reason = error.to_string();
+                event::emit(Event::SendFailed { msg: error.into_inner(), error: reason });
                 retval = false;
                 std::thread::yield_now(); // free up thread to give workers a chance to catchup
             }
@@ -68,36 +73,88 @@ impl Worker {
     }
 
     /// get the number of msgs recvd by worker
-    pub fn get_cnt(&self) -> u32 { *self.rec_cnt.lock().unwrap() }
+    pub fn get_cnt(&self) -> u32 { self.runtime.get_cnt() }
+
+    /// stop taking msgs until resumed, msgs sent meanwhile queue up to the worker's capacity
+    pub fn pause(&self) { self.runtime.pause() }
+
+    pub fn resume(&self) { self.runtime.resume() }
+
+    pub fn is_paused(&self) -> bool { self.runtime.is_paused() }
 
     /// signal the worker to stop and wait until it does
     pub fn stop(self) {
-        self.interrupt.store(true, std::sync::atomic::Ordering::SeqCst);
-        self.handle.join().expect("Failed to join thread");
+        self.runtime.stop();
     }
 }
 
+impl Watched for Worker {
+    fn health(&self) -> Arc<Health> { self.runtime.state().health.clone() }
+
+    fn retire(&self) {
+        self.runtime.interrupt();
+        self.runtime.state().health.cancel_job();
+    }
+
+    fn is_finished(&self) -> bool { self.runtime.is_finished() }
+
+    fn stop(self) { Worker::stop(self) }
+}
+
 pub struct WorkerManager {
     workers: Vec<Worker>,
+    fan_out: FanOut, // hands every msg to the queue of each worker that has one
+    log: Option<Arc<OffsetLog>>, // every msg sent is appended here for subscribers
 }
 
 impl WorkerManager {
     pub fn new() -> Self {
         Self {
-            workers: Vec::new()
+            workers: Vec::new(),
+            fan_out: FanOut::new(1),
+            log: None,
+        }
+    }
+
+    /// create a manager that appends every msg to the log, for workers created with `Worker::subscribe`
+    pub fn with_log(log: Arc<OffsetLog>) -> Self {
+        Self {
+            workers: Vec::new(),
+            fan_out: FanOut::new(1),
+            log: Some(log),
         }
     }
 
     pub fn add(&mut self, wrk: Worker) {
+        if let Some(tx) = &wrk.tx {
+            self.fan_out.adopt(tx.clone());
+        }
         self.workers.push(wrk);
     }
 
+    /// every worker gets the msg, false if it couldn't be logged or didn't reach them all in time
     pub fn send(&self, msg: (String,usize)) -> bool {
         let mut result = true;
+        if let Some(log) = &self.log {
+            if let Err(error) = log.append(&msg) {
+                event::emit(Event::SendFailed { msg: msg.clone(), error: error.to_string() });
+                result = false;
+            }
+        }
+        self.fan_out.dispatch(msg) && result
+    }
+
+    /// pause every worker, a log keeps taking msgs for subscribers to catch up on once resumed
+    pub fn pause(&self) {
+        for wrk in self.workers.iter() {
+            wrk.pause();
+        }
+    }
+
+    pub fn resume(&self) {
         for wrk in self.workers.iter() {
-            result = result && wrk.send(msg.clone());
+            wrk.resume();
         }
-        result
     }
 
     pub fn chk_msg_counts(&self, expected: u32) -> bool {
@@ -110,11 +167,16 @@ impl WorkerManager {
     }
 }
 
+impl Default for WorkerManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Drop for WorkerManager {
     fn drop(&mut self) {
         while !self.workers.is_empty() {
             let w = self.workers.remove(0);
-            println!("Stopping worker");
             w.stop();
         }
     }
@@ -134,3 +196,33 @@ fn test_broadcast() {
     std::thread::sleep(Duration::from_secs(1));
     assert_eq!(workers.chk_msg_counts(10), true);
 }
+
+#[test]
+fn test_pause_manager() {
+    let mut workers = WorkerManager::new();
+    for n in 1..=2 {
+        workers.add(Worker::new(format!("Worker {n}"), false));
+    }
+    workers.pause();
+    // each worker's queue holds one msg while paused
+    assert!(workers.send(("this is the msg".to_string(), 1)));
+    assert!(!workers.send(("this is the msg".to_string(), 2)));
+    assert!(workers.chk_msg_counts(0));
+    workers.resume();
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(workers.chk_msg_counts(1));
+}
+
+#[test]
+fn test_fan_out_past_full_worker() {
+    let mut workers = WorkerManager::new();
+    let full = Worker::new("Worker 1".to_string(), false);
+    full.pause();
+    workers.add(full);
+    workers.add(Worker::new("Worker 2".to_string(), false));
+    assert!(workers.send(("this is the msg".to_string(), 1)));
+    // worker 1 has no room for msg 2 but worker 2 still gets it
+    assert!(!workers.send(("this is the msg".to_string(), 2)));
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(workers.workers.iter().map(|w| w.get_cnt()).collect::<Vec<_>>(), vec![0, 2]);
+}
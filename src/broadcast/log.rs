@@ -0,0 +1,519 @@
+use std::{collections::{BTreeMap, VecDeque}, fs::{self, File, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}, sync::{Arc, Condvar, Mutex, atomic::Ordering}, time::Duration};
+
+use crate::{clock::{self, Clock, RealClock}, event::{self, Event}, record::{Reader, frame, put_str, read_frames, sync_dir}, runtime::{Inbox, Runtime, WorkerState, take_through}};
+
+use super::Worker;
+
+/// Msgs a segment holds before a new one is started
+const SEGMENT_MSGS: u64 = 1024;
+
+/// How often a wait on the clock checks it in real time
+const CHECK: Duration = Duration::from_millis(10);
+
+/*
+ * Every msg broadcast is appended to the log and given the next offset, starting at 0.
+ * The log is split into segment files named by the offset of their first msg. Once every
+ * subscriber has committed past the end of a segment it is deleted, so only the active
+ * segment and those some subscriber still has to read are kept. A subscriber that hasn't
+ * committed yet holds the msgs from where it started until it commits or stops.
+ * Subscribers' commits are appended to `offsets`, the last one for a subscriber wins. The
+ * file is rewritten with only the latest commits once it holds mostly stale ones.
+ * A record that is whole but can't be decoded fails the open, the tail of a record torn by
+ * a crash is reported and dropped.
+ *
+ * Msg payload: seq u64, text
+ * Offset payload: offset u64, subscriber name
+ */
+
+struct LogState {
+    segments: VecDeque<u64>, // first offset of each segment, the last one is written to
+    active: File,
+    msgs: VecDeque<(String, usize)>, // msgs still kept, from offset `first`
+    first: u64,
+    committed: BTreeMap<String, u64>, // subscriber -> offset of the last msg it processed
+    started: BTreeMap<String, u64>, // subscriber yet to commit -> offset it started from, not persisted
+    offsets: File,
+    commits: usize, // records in the offsets file
+}
+
+impl LogState {
+    fn next_offset(&self) -> u64 { self.first + self.msgs.len() as u64 }
+}
+
+/// Append-only log of broadcast msgs that subscribers read from by offset
+/// msgs every subscriber has processed are dropped a segment at a time
+pub struct OffsetLog {
+    dir: PathBuf,
+    segment_msgs: u64,
+    state: Mutex<LogState>,
+    appended: Condvar, // wakes subscribers waiting for new msgs
+}
+
+impl OffsetLog {
+    /// open the log in the directory, creating it if needed
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        Self::with_segment_msgs(dir.as_ref(), SEGMENT_MSGS)
+    }
+
+    fn with_segment_msgs(dir: &Path, segment_msgs: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut segments = segment_files(dir)?;
+        segments.sort();
+        if segments.is_empty() {
+            create_segment(dir, 0)?;
+            sync_dir(dir);
+            segments.push(0);
+        }
+        let mut msgs = VecDeque::new();
+        for first in segments.iter() {
+            msgs.extend(read_log(&segment_path(dir, *first), decode_msg)?);
+        }
+        let active = OpenOptions::new().append(true).open(segment_path(dir, *segments.last().unwrap()))?;
+
+        let offsets = dir.join("offsets");
+        let commits = if offsets.exists() { read_log(&offsets, decode_offset)? } else { Vec::new() };
+        let state = LogState {
+            first: segments[0],
+            segments: segments.into(),
+            active,
+            msgs,
+            commits: commits.len(),
+            committed: commits.into_iter().collect(),
+            started: BTreeMap::new(),
+            offsets: OpenOptions::new().create(true).append(true).open(&offsets)?,
+        };
+        Ok(Self { dir: dir.to_path_buf(), segment_msgs, state: Mutex::new(state), appended: Condvar::new() })
+    }
+
+    /// write a msg to the end of the log, returns its offset
+    pub fn append(&self, msg: &(String, usize)) -> io::Result<u64> {
+        let mut p = Vec::new();
+        p.extend((msg.1 as u64).to_le_bytes());
+        put_str(&mut p, &msg.0);
+        let mut state = self.state.lock().unwrap();
+        let offset = state.next_offset();
+        if offset - state.segments.back().unwrap() >= self.segment_msgs {
+            // roll over to a new segment
+            state.active = create_segment(&self.dir, offset)?;
+            state.segments.push_back(offset);
+            sync_dir(&self.dir);
+        }
+        state.active.write_all(&frame(p))?;
+        state.active.sync_data()?;
+        state.msgs.push_back(msg.clone());
+        self.appended.notify_all();
+        Ok(offset)
+    }
+
+    /// offset the next msg appended will get
+    pub fn next_offset(&self) -> u64 {
+        self.state.lock().unwrap().next_offset()
+    }
+
+    /// offset of the oldest msg still kept
+    pub fn first_offset(&self) -> u64 {
+        self.state.lock().unwrap().first
+    }
+
+    /// msg at the offset, waiting up to the timeout for it to be appended
+    /// None for a msg that was dropped once every subscriber processed it
+    pub fn read(&self, offset: u64, timeout: Duration) -> Option<(String, usize)> {
+        self.read_by(offset, &RealClock, timeout)
+    }
+
+    /// msg at the offset like `read`, waiting until the timeout passes on the clock
+    pub fn read_by(&self, offset: u64, clock: &dyn Clock, timeout: Duration) -> Option<(String, usize)> {
+        let timer = clock.after(timeout);
+        loop {
+            match self.get(offset) {
+                Some(msg) => return msg,
+                None if timer.rx().try_recv().is_ok() => return None,
+                None => self.wait_appended(offset, CHECK),
+            }
+        }
+    }
+
+    /// the msg at the offset if it has been appended, Some(None) once it was dropped
+    fn get(&self, offset: u64) -> Option<Option<(String, usize)>> {
+        let state = self.state.lock().unwrap();
+        if offset < state.first {
+            return Some(None);
+        }
+        state.msgs.get((offset - state.first) as usize).map(|msg| Some(msg.clone()))
+    }
+
+    /// wait a little in real time for the msg at the offset to be appended, returns straight away if it's there
+    fn wait_appended(&self, offset: u64, wait: Duration) {
+        let state = self.state.lock().unwrap();
+        if !(state.first..state.next_offset()).contains(&offset) {
+            let _ = self.appended.wait_timeout(state, wait).unwrap();
+        }
+    }
+
+    /// record that the subscriber has processed everything up to and including the offset
+    /// segments every subscriber is done with are deleted
+    pub fn commit(&self, subscriber: &str, offset: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.offsets.write_all(&encode_offset(subscriber, offset))?;
+        state.offsets.sync_data()?;
+        state.commits += 1;
+        state.committed.insert(subscriber.to_string(), offset);
+        state.started.remove(subscriber);
+        if state.commits > 2 * state.committed.len() + 64 {
+            self.compact_offsets(&mut state)?;
+        }
+        self.drop_processed(&mut state)
+    }
+
+    /// offset a subscriber reads from first, after the last one it committed or else the oldest msg kept,
+    /// which is then kept for it until it commits or leaves
+    fn start(&self, subscriber: &str) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        if let Some(offset) = state.committed.get(subscriber) {
+            return offset + 1;
+        }
+        let first = state.first;
+        state.started.insert(subscriber.to_string(), first);
+        first
+    }
+
+    /// a subscriber stopped, msgs it started on but never committed are no longer kept for it
+    fn leave(&self, subscriber: &str) {
+        self.state.lock().unwrap().started.remove(subscriber);
+    }
+
+    /// offset of the last msg the subscriber processed, None if it never committed one
+    pub fn committed(&self, subscriber: &str) -> Option<u64> {
+        self.state.lock().unwrap().committed.get(subscriber).copied()
+    }
+
+    /// rewrite the offsets file with only the latest commit of each subscriber
+    fn compact_offsets(&self, state: &mut LogState) -> io::Result<()> {
+        let bytes: Vec<u8> = state.committed.iter().flat_map(|(nm, offset)| encode_offset(nm, *offset)).collect();
+        // write aside and rename over so the offsets are never half written
+        let tmp = self.dir.join("offsets.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bytes)?;
+        file.sync_data()?;
+        let path = self.dir.join("offsets");
+        fs::rename(&tmp, &path)?;
+        sync_dir(&self.dir);
+        state.offsets = OpenOptions::new().append(true).open(&path)?;
+        state.commits = state.committed.len();
+        Ok(())
+    }
+
+    /// delete the segments before the one holding the oldest msg a subscriber still has to read
+    fn drop_processed(&self, state: &mut LogState) -> io::Result<()> {
+        let Some(oldest) = state.committed.values().map(|o| o + 1).chain(state.started.values().copied()).min() else { return Ok(()) };
+        let mut removed = false;
+        while state.segments.len() > 1 && state.segments[1] <= oldest {
+            let first = state.segments.pop_front().unwrap();
+            fs::remove_file(segment_path(&self.dir, first))?;
+            let next = state.segments[0];
+            state.msgs.drain(..(next - state.first) as usize);
+            state.first = next;
+            removed = true;
+        }
+        if removed {
+            sync_dir(&self.dir);
+        }
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, first: u64) -> PathBuf {
+    dir.join(format!("{first:020}.log"))
+}
+
+fn create_segment(dir: &Path, first: u64) -> io::Result<File> {
+    OpenOptions::new().create(true).write(true).truncate(true).open(segment_path(dir, first))
+}
+
+/// first offset of every segment file in the directory
+fn segment_files(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "log") {
+            if let Some(first) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                files.push(first);
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn decode_msg(payload: &[u8]) -> Option<(String, usize)> {
+    let mut r = Reader(payload);
+    let seq = r.u64()? as usize;
+    Some((r.string()?, seq))
+}
+
+fn encode_offset(subscriber: &str, offset: u64) -> Vec<u8> {
+    let mut p = Vec::new();
+    p.extend(offset.to_le_bytes());
+    put_str(&mut p, subscriber);
+    frame(p)
+}
+
+fn decode_offset(payload: &[u8]) -> Option<(String, u64)> {
+    let mut r = Reader(payload);
+    let offset = r.u64()?;
+    Some((r.string()?, offset))
+}
+
+/// every record in the file, failing on one that is whole but can't be decoded
+/// the tail of a record torn by a crash is reported and cut off so new records follow the last whole one
+fn read_log<T>(path: &Path, decode: fn(&[u8]) -> Option<T>) -> io::Result<Vec<T>> {
+    let bytes = fs::read(path)?;
+    let (frames, valid) = read_frames(&bytes);
+    let records = frames.into_iter().map(|p| {
+        decode(p).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("corrupt record in {}", path.display())))
+    }).collect::<io::Result<Vec<T>>>()?;
+    if valid < bytes.len() {
+        event::emit(Event::Error { source: "log".to_string(), error: format!("dropped {} bytes of a torn record in {}", bytes.len() - valid, path.display()) });
+        OpenOptions::new().write(true).open(path)?.set_len(valid as u64)?;
+    }
+    Ok(records)
+}
+
+impl Worker {
+    /// create a worker that reads msgs from the log under its name
+    /// it starts after the last offset it committed, or from the oldest msg kept the first time,
+    /// and commits each msg's offset once it is processed
+    pub fn subscribe(nm: String, log: &Arc<OffsetLog>, do_delay: bool) -> Self {
+        Self::subscribe_with_clock(nm, log, do_delay, clock::real())
+    }
+
+    /// create a subscriber that times its waits for msgs and its delay by the clock
+    pub fn subscribe_with_clock(nm: String, log: &Arc<OffsetLog>, do_delay: bool, clock: Arc<dyn Clock>) -> Self {
+        let (state, cancel) = super::job_state(&nm);
+        let reader = LogReader { log: log.clone(), nm: nm.clone(), offset: log.start(&nm), clock: clock.clone() };
+        let clock_ = clock.clone();
+        let runtime = Runtime::run(state, reader, move |worker, reader, offset| {
+            cancel.store(false, Ordering::SeqCst);
+            if do_delay {
+                super::delay(&cancel, &clock_);
+            }
+            if let Err(error) = reader.log.commit(&worker.nm, offset) {
+                event::emit(Event::Error { source: format!("worker '{}'", worker.nm), error: format!("commit error: {}", error) });
+            }
+        });
+        Self { tx: None, runtime, clock }
+    }
+}
+
+/// A subscriber's place in the log
+struct LogReader {
+    log: Arc<OffsetLog>,
+    nm: String, // of the subscriber
+    offset: u64, // of the next msg to read
+    clock: Arc<dyn Clock>, // times out waits
+}
+
+impl Drop for LogReader {
+    fn drop(&mut self) {
+        self.log.leave(&self.nm);
+    }
+}
+
+impl Inbox for LogReader {
+    /// offset of the msg read
+    type Item = u64;
+
+    fn recv(&mut self, worker: &WorkerState, timeout: Duration) -> Option<u64> {
+        let timer = self.clock.after(timeout);
+        loop {
+            if let Some(Some(Some(msg))) = take_through(&[&worker.pause], || self.log.get(self.offset)) {
+                worker.took(&msg);
+                self.offset += 1;
+                return Some(self.offset - 1);
+            }
+            if timer.rx().try_recv().is_ok() || worker.stopped() {
+                return None;
+            }
+            // wait a little for the next msg, or for the worker to be resumed
+            if worker.pause.is_paused() {
+                std::thread::sleep(CHECK);
+            } else {
+                self.log.wait_appended(self.offset, CHECK);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_dir(nm: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rust_chan_bcast_{nm}_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn test_offsets() {
+    let dir = test_dir("offsets");
+    let log = OffsetLog::open(&dir).unwrap();
+    assert_eq!(log.next_offset(), 0);
+    assert_eq!(log.append(&("this is the msg".to_string(), 1)).unwrap(), 0);
+    assert_eq!(log.append(&("this is the msg".to_string(), 2)).unwrap(), 1);
+    assert_eq!(log.read(1, Duration::ZERO), Some(("this is the msg".to_string(), 2)));
+    assert_eq!(log.read(2, Duration::from_millis(10)), None);
+    assert_eq!(log.committed("sub"), None);
+    log.commit("sub", 0).unwrap();
+    drop(log);
+
+    let log = OffsetLog::open(&dir).unwrap();
+    assert_eq!(log.next_offset(), 2);
+    assert_eq!(log.committed("sub"), Some(0));
+    assert_eq!(log.append(&("this is the msg".to_string(), 3)).unwrap(), 2);
+    drop(log);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_retention() {
+    let dir = test_dir("retention");
+    let log = OffsetLog::with_segment_msgs(&dir, 2).unwrap();
+    for i in 0..5 {
+        assert_eq!(log.append(&("this is the msg".to_string(), i)).unwrap(), i as u64);
+    }
+    log.commit("b", 0).unwrap();
+    log.commit("a", 2).unwrap();
+    // b still has to read offset 1
+    assert_eq!(log.first_offset(), 0);
+    log.commit("b", 3).unwrap();
+    // every subscriber is past the first segment
+    assert_eq!(log.first_offset(), 2);
+    assert_eq!(log.read(1, Duration::ZERO), None);
+    assert_eq!(log.read(2, Duration::ZERO), Some(("this is the msg".to_string(), 2)));
+    assert_eq!(segment_files(&dir).unwrap().len(), 2);
+    for _ in 0..100 {
+        log.commit("a", 4).unwrap();
+    }
+    drop(log);
+
+    let log = OffsetLog::open(&dir).unwrap();
+    // once a caught up too nothing before the last segment was needed
+    assert_eq!((log.first_offset(), log.next_offset()), (4, 5));
+    assert_eq!((log.committed("a"), log.committed("b")), (Some(4), Some(3)));
+    // the offsets file was compacted rather than holding every commit
+    assert!(log.state.lock().unwrap().commits < 70);
+    drop(log);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_corrupt_log() {
+    let dir = test_dir("corrupt");
+    let log = OffsetLog::open(&dir).unwrap();
+    log.append(&("this is the msg".to_string(), 1)).unwrap();
+    drop(log);
+    let path = segment_path(&dir, 0);
+    // a torn tail is dropped and the log carries on after the last whole msg
+    let mut torn = fs::read(&path).unwrap();
+    torn.extend(&frame(vec![0; 20])[..10]);
+    fs::write(&path, &torn).unwrap();
+    let log = OffsetLog::open(&dir).unwrap();
+    assert_eq!(log.append(&("this is the msg".to_string(), 2)).unwrap(), 1);
+    drop(log);
+    let log = OffsetLog::open(&dir).unwrap();
+    assert_eq!(log.read(1, Duration::ZERO), Some(("this is the msg".to_string(), 2)));
+    drop(log);
+    // a whole record that doesn't decode fails the open
+    let mut bad = fs::read(&path).unwrap();
+    bad.extend(frame(vec![1, 2, 3]));
+    fs::write(&path, &bad).unwrap();
+    assert_eq!(OffsetLog::open(&dir).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_subscribers_resume() {
+    use super::WorkerManager;
+
+    let dir = test_dir("resume");
+    let log = Arc::new(OffsetLog::open(&dir).unwrap());
+    let mut workers = WorkerManager::with_log(log.clone());
+    for n in 1..=2 {
+        workers.add(Worker::subscribe(format!("Worker {n}"), &log, false));
+    }
+    for i in 1..=5 {
+        assert!(workers.send(("this is the msg".to_string(), i)));
+    }
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(workers.chk_msg_counts(5));
+    drop(workers);
+    drop(log);
+
+    // restarted with more msgs sent while the subscribers were down
+    let log = Arc::new(OffsetLog::open(&dir).unwrap());
+    let mut workers = WorkerManager::with_log(log.clone());
+    for i in 6..=8 {
+        assert!(workers.send(("this is the msg".to_string(), i)));
+    }
+    for n in 1..=2 {
+        workers.add(Worker::subscribe(format!("Worker {n}"), &log, false));
+    }
+    // a new subscriber starts from the beginning
+    workers.add(Worker::subscribe("Worker 3".to_string(), &log, false));
+    std::thread::sleep(Duration::from_millis(200));
+    let cnts: Vec<u32> = workers.workers.iter().map(|w| w.get_cnt()).collect();
+    assert_eq!(cnts, vec![3, 3, 8]);
+    assert_eq!(log.committed("Worker 1"), Some(7));
+    drop(workers);
+    drop(log);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_slow_subscriber_keeps_msgs() {
+    let dir = test_dir("slow");
+    let log = Arc::new(OffsetLog::with_segment_msgs(&dir, 2).unwrap());
+    // yet to commit anything when the others move on
+    let slow = Worker::subscribe("Slow".to_string(), &log, false);
+    slow.pause();
+    let fast = Worker::subscribe("Fast".to_string(), &log, false);
+    for i in 0..6 {
+        log.append(&("this is the msg".to_string(), i)).unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(log.committed("Fast"), Some(5));
+    assert_eq!(log.first_offset(), 0);
+    slow.resume();
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(slow.get_cnt(), 6);
+    // both are past the first two segments
+    assert_eq!(log.first_offset(), 4);
+    slow.stop();
+    fast.stop();
+    drop(log);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_read_on_clock() {
+    let dir = test_dir("clock");
+    let log = Arc::new(OffsetLog::open(&dir).unwrap());
+    let clock = Arc::new(clock::MockClock::new());
+    let (log_, clock_) = (log.clone(), clock.clone());
+    let reader = std::thread::spawn(move || log_.read_by(0, &*clock_, Duration::from_secs(60)));
+    clock.wait_for_timer(Duration::from_secs(60));
+    // a minute of real time never passes
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(!reader.is_finished());
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(reader.join().unwrap(), None);
+
+    // a subscriber's delay is on its clock too
+    let wrk = Worker::subscribe_with_clock("Clock".to_string(), &log, true, clock.clone());
+    log.append(&("this is the msg".to_string(), 1)).unwrap();
+    clock.wait_for_timer(Duration::from_secs(62));
+    assert_eq!(log.committed("Clock"), None);
+    clock.advance(Duration::from_secs(2));
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(log.committed("Clock"), Some(0));
+    wrk.stop();
+    drop(log);
+    fs::remove_dir_all(&dir).unwrap();
+}
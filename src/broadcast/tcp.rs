@@ -0,0 +1,165 @@
+use std::{io, net::{SocketAddr, TcpListener, ToSocketAddrs}, sync::{Arc, atomic::Ordering}, thread::JoinHandle, time::Duration};
+
+use crate::{event::{self, Event}, net::{self, Caller, NetOptions, POLL, invalid}, clock::RealClock, runtime::{self, Runtime, WorkerState}, single::HandlerResult, watchdog::Health};
+
+use super::Worker;
+
+/*
+ * A broadcast worker on another host is reached through a stand-in worker added to the
+ * WorkerManager, which hands each msg it receives over TCP and waits for the far end to
+ * run it. The far end listens with a TcpReceiver.
+ */
+
+/// Runs broadcast msgs sent from a WorkerManager on another host
+pub struct TcpReceiver {
+    runtime: Runtime, // accepts connections, each served on a thread of its own
+    addr: SocketAddr,
+}
+
+impl TcpReceiver {
+    /// listen on the address and run the handler on every msg, port 0 picks a free port
+    pub fn bind<F>(nm: String, addr: impl ToSocketAddrs, opts: NetOptions, handler: F) -> io::Result<Self>
+    where F: Fn(&(String, usize)) -> HandlerResult + Send + Sync + 'static {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let handler = Arc::new(handler);
+        // jobs on the far end can't be cancelled
+        let state = WorkerState::new(Health::new(&nm, || {}));
+        let runtime = Runtime::spawn(state, move |state| {
+            let mut conns: Vec<JoinHandle<()>> = Vec::new();
+            while !state.stopped() {
+                match net::tcp_accept(&listener) {
+                    Ok(Some(conn)) => {
+                        let (state, opts, handler) = (state.clone(), opts.clone(), handler.clone());
+                        conns.push(std::thread::spawn(move || {
+                            let result = net::Link::new(conn, &opts).and_then(|mut link| net::serve_jobs(&mut link, &state, &*handler));
+                            if let Err(error) = result {
+                                event::emit(Event::Disconnected { worker: state.nm.clone(), error: error.to_string() });
+                            }
+                        }));
+                    },
+                    Ok(None) => std::thread::sleep(POLL),
+                    Err(error) => {
+                        event::emit(Event::Error { source: format!("worker '{}'", nm), error: format!("accept error: {}", error) });
+                        std::thread::sleep(POLL);
+                    },
+                }
+                conns.retain(|c| !c.is_finished());
+            }
+            for c in conns {
+                let _ = c.join();
+            }
+        });
+        Ok(Self { runtime, addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr { self.addr }
+
+    /// get the number of msgs recvd
+    pub fn get_cnt(&self) -> u32 { self.runtime.get_cnt() }
+
+    /// stop listening and drop every connection
+    pub fn stop(self) {
+        self.runtime.stop();
+    }
+}
+
+impl Worker {
+    /// create a worker that passes every msg on to a TcpReceiver on another host
+    /// its count goes up once the far end has run a msg, and a msg caught by a lost connection
+    /// is sent again once the connection is reopened with backoff
+    pub fn connect_tcp(nm: String, addr: SocketAddr, opts: NetOptions) -> io::Result<Self> {
+        let first = net::tcp_connect(addr, &opts)?;
+        let (t, r) = crossbeam_channel::bounded::<(String, usize)>(1);
+        let (state, cancel) = super::job_state(&nm);
+        let runtime = Runtime::spawn(state, move |state| {
+            let mut pending = None;
+            net::reconnecting(&nm, first, || net::tcp_connect(addr, &opts), &opts, &state.interrupt, |link| {
+                let mut caller = Caller::new(link);
+                while !state.stopped() {
+                    let msg = match pending.take() {
+                        Some(msg) => msg,
+                        None => match runtime::recv_gated(&RealClock, &r, POLL, &[&state.pause], || state.stopped()) {
+                            Ok(msg) => msg,
+                            Err(_) => {
+                                // idle, keep up with heartbeats
+                                if caller.link.recv(Duration::ZERO)?.is_some() {
+                                    return Err(invalid("expected a heartbeat"));
+                                }
+                                continue;
+                            },
+                        },
+                    };
+                    event::emit(Event::MessageReceived { worker: nm.clone(), msg: msg.clone() });
+                    cancel.store(false, Ordering::SeqCst);
+                    state.health.start();
+                    let called = caller.call(&msg, || state.stopped() || cancel.load(Ordering::SeqCst));
+                    state.health.done();
+                    match called {
+                        Ok(result) => {
+                            if let Err(error) = result {
+                                event::emit(Event::MessageFailed { worker: nm.clone(), msg: msg.clone(), error });
+                            }
+                            if !state.stopped() {
+                                // increment rec counter
+                                let mut n = state.rec_cnt.lock().unwrap();
+                                *n += 1;
+                            }
+                        },
+                        Err(error) => {
+                            pending = Some(msg);
+                            return Err(error);
+                        },
+                    }
+                }
+                Ok(())
+            });
+        });
+        Ok(Self { tx: Some(t), runtime, clock: crate::clock::real() })
+    }
+}
+
+#[cfg(test)]
+fn fast_opts() -> NetOptions {
+    NetOptions {
+        heartbeat: Duration::from_millis(50),
+        dead_after: Duration::from_millis(300),
+        reconnect: crate::single::Backoff::Fixed(Duration::from_millis(50)),
+    }
+}
+
+#[test]
+fn test_tcp_broadcast() {
+    use super::WorkerManager;
+
+    let receivers: Vec<TcpReceiver> = (1..=2)
+        .map(|n| TcpReceiver::bind(format!("Remote {n}"), "127.0.0.1:0", fast_opts(), |_| Ok(())).unwrap())
+        .collect();
+    let mut workers = WorkerManager::new();
+    for r in receivers.iter() {
+        workers.add(Worker::connect_tcp(format!("Worker {}", r.local_addr()), r.local_addr(), fast_opts()).unwrap());
+    }
+    workers.add(Worker::new("Worker local".to_string(), false));
+    for i in 1..=5 {
+        assert!(workers.send(("this is the msg".to_string(), i)));
+    }
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(workers.chk_msg_counts(5));
+    assert!(receivers.iter().all(|r| r.get_cnt() == 5));
+
+    // a receiver restarts and the msg sent while it was down still reaches it
+    let mut receivers = receivers;
+    let addr = receivers[0].local_addr();
+    receivers.remove(0).stop();
+    assert!(workers.send(("this is the msg".to_string(), 6)));
+    std::thread::sleep(Duration::from_millis(200));
+    receivers.insert(0, TcpReceiver::bind("Remote 1".to_string(), addr, fast_opts(), |_| Ok(())).unwrap());
+    std::thread::sleep(Duration::from_millis(500));
+    assert!(workers.chk_msg_counts(6));
+    assert_eq!(receivers[0].get_cnt(), 1);
+    drop(workers);
+    for r in receivers {
+        r.stop();
+    }
+}
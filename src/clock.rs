@@ -0,0 +1,214 @@
+use std::{sync::{Arc, Condvar, Mutex, RwLock, Weak}, time::{Duration, Instant}};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Select, SendTimeoutError, Sender};
+
+/*
+ * Waits in buses and workers go through a Clock so tests can swap in a MockClock and move
+ * time along by hand. Every timed wait is a select on a Timer's channel, which a real clock
+ * fires on its own and a mock clock fires when advanced past it.
+ */
+
+/// How often a wait on a clock checks whether it should give up early, in real time
+const POLL: Duration = Duration::from_millis(10);
+
+/// Fires once a clock has passed its deadline
+pub struct Timer {
+    rx: Receiver<Instant>,
+    _live: Arc<()>, // lets a mock clock tell when nobody is waiting on the timer any more
+}
+
+impl Timer {
+    pub fn rx(&self) -> &Receiver<Instant> { &self.rx }
+
+    /// block until the timer fires
+    pub fn wait(&self) {
+        let _ = self.rx.recv();
+    }
+}
+
+/// Source of the time for buses and workers
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    /// a timer that fires once `d` has passed on this clock
+    fn after(&self, d: Duration) -> Timer;
+}
+
+/// The system clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant { Instant::now() }
+
+    fn after(&self, d: Duration) -> Timer {
+        Timer { rx: crossbeam_channel::after(d), _live: Arc::new(()) }
+    }
+}
+
+pub(crate) fn real() -> Arc<dyn Clock> { Arc::new(RealClock) }
+
+/// A clock that can be swapped for another, everything holding the cell follows the swap
+/// waits already started keep the timer they got
+pub(crate) struct ClockCell(RwLock<Arc<dyn Clock>>);
+
+impl ClockCell {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Arc<Self> {
+        Arc::new(Self(RwLock::new(clock)))
+    }
+
+    pub(crate) fn set(&self, clock: Arc<dyn Clock>) {
+        *self.0.write().unwrap() = clock;
+    }
+}
+
+impl Clock for ClockCell {
+    fn now(&self) -> Instant { self.0.read().unwrap().now() }
+
+    fn after(&self, d: Duration) -> Timer { self.0.read().unwrap().after(d) }
+}
+
+struct MockTimer {
+    due: Duration, // since the clock's start
+    tx: Sender<Instant>,
+    live: Weak<()>,
+}
+
+#[derive(Default)]
+struct MockState {
+    elapsed: Duration,
+    timers: Vec<MockTimer>,
+}
+
+/// A clock that only moves when advanced, for tests that shouldn't depend on real time
+/// waits on it never time out by themselves, so a send or recv that would have timed out
+/// blocks until the test advances the clock
+pub struct MockClock {
+    start: Instant,
+    state: Mutex<MockState>,
+    timer_added: Condvar,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self { start: Instant::now(), state: Mutex::default(), timer_added: Condvar::new() }
+    }
+
+    /// time passed on the clock since it was made
+    pub fn elapsed(&self) -> Duration { self.state.lock().unwrap().elapsed }
+
+    /// move the clock on, firing every timer it passes
+    pub fn advance(&self, d: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.elapsed += d;
+        let (now, start) = (state.elapsed, self.start);
+        state.timers.retain(|t| {
+            if t.due > now {
+                return t.live.strong_count() > 0;
+            }
+            let _ = t.tx.try_send(start + t.due);
+            false
+        });
+    }
+
+    /// block, in real time, until something is waiting on a timer due at `at` since the clock's start
+    /// lets a test advance the clock only once a worker or sender has got as far as waiting
+    pub fn wait_for_timer(&self, at: Duration) {
+        let mut state = self.state.lock().unwrap();
+        while !state.timers.iter().any(|t| t.due == at && t.live.strong_count() > 0) {
+            // a timer being dropped isn't signalled so check again now and then
+            state = self.timer_added.wait_timeout(state, POLL).unwrap().0;
+        }
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant { self.start + self.elapsed() }
+
+    fn after(&self, d: Duration) -> Timer {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let live = Arc::new(());
+        let mut state = self.state.lock().unwrap();
+        let due = state.elapsed + d;
+        if d.is_zero() {
+            let _ = tx.try_send(self.start + due);
+        } else {
+            state.timers.push(MockTimer { due, tx, live: Arc::downgrade(&live) });
+            self.timer_added.notify_all();
+        }
+        Timer { rx, _live: live }
+    }
+}
+
+/// wait for a msg until `timeout` has passed on the clock,
+/// `give_up` is checked every so often in real time and ends the wait early when true
+pub(crate) fn recv_timeout<T>(clock: &dyn Clock, rx: &Receiver<T>, timeout: Duration, give_up: impl Fn() -> bool) -> Result<T, RecvTimeoutError> {
+    let timer = clock.after(timeout);
+    loop {
+        let mut sel = Select::new();
+        let r = sel.recv(rx);
+        sel.recv(timer.rx());
+        let oper = match sel.select_timeout(POLL) {
+            Ok(oper) => oper,
+            Err(_) if give_up() => return Err(RecvTimeoutError::Timeout),
+            Err(_) => continue,
+        };
+        if oper.index() == r {
+            return oper.recv(rx).map_err(|_| RecvTimeoutError::Disconnected);
+        }
+        let _ = oper.recv(timer.rx());
+        return Err(RecvTimeoutError::Timeout);
+    }
+}
+
+/// send a msg, giving up if there's no room before `timeout` has passed on the clock
+pub(crate) fn send_timeout<T>(clock: &dyn Clock, tx: &Sender<T>, msg: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+    let timer = clock.after(timeout);
+    let mut sel = Select::new();
+    let s = sel.send(tx);
+    sel.recv(timer.rx());
+    let oper = sel.select();
+    if oper.index() == s {
+        return oper.send(tx, msg).map_err(|e| SendTimeoutError::Disconnected(e.into_inner()));
+    }
+    let _ = oper.recv(timer.rx());
+    Err(SendTimeoutError::Timeout(msg))
+}
+
+#[test]
+fn test_mock_timers() {
+    let clock = MockClock::new();
+    let early = clock.after(Duration::from_millis(100));
+    let late = clock.after(Duration::from_millis(300));
+    assert!(early.rx().try_recv().is_err());
+    clock.advance(Duration::from_millis(100));
+    assert!(early.rx().try_recv().is_ok());
+    assert!(late.rx().try_recv().is_err());
+    clock.advance(Duration::from_millis(250));
+    assert!(late.rx().try_recv().is_ok());
+    assert_eq!(clock.elapsed(), Duration::from_millis(350));
+    assert!(clock.after(Duration::ZERO).rx().try_recv().is_ok());
+}
+
+#[test]
+fn test_mock_timeouts() {
+    let clock = MockClock::new();
+    let (tx, rx) = crossbeam_channel::bounded(1);
+    assert!(send_timeout(&clock, &tx, 1, Duration::from_secs(60)).is_ok());
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            clock.wait_for_timer(Duration::from_secs(60));
+            clock.advance(Duration::from_secs(60));
+        });
+        // a full minute on the clock, passed in no time at all
+        assert!(matches!(send_timeout(&clock, &tx, 2, Duration::from_secs(60)), Err(SendTimeoutError::Timeout(2))));
+    });
+    assert_eq!(recv_timeout(&clock, &rx, Duration::from_secs(60), || false), Ok(1));
+    // a wait that is given up on ends without the clock moving
+    assert_eq!(recv_timeout(&clock, &rx, Duration::from_secs(60), || true), Err(RecvTimeoutError::Timeout));
+}
@@ -0,0 +1,161 @@
+use std::fmt;
+
+use crate::record::{Reader, put_str};
+
+/// Why bytes couldn't be turned back into a msg
+#[derive(Clone, Debug, PartialEq)]
+pub enum CodecError {
+    /// bytes ended early or didn't hold what was expected
+    Malformed(String),
+    /// written by a newer major version than this one understands
+    UnsupportedVersion { major: u8, minor: u8 },
+    /// written with a different codec, holds its id
+    WrongCodec(u8),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Malformed(what) => write!(f, "malformed msg: {what}"),
+            CodecError::UnsupportedVersion { major, minor } => write!(f, "unsupported envelope version {major}.{minor}"),
+            CodecError::WrongCodec(id) => write!(f, "msg was written with codec {id}"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Turns msgs into bytes and back for sending them between processes
+pub trait Codec<T> {
+    /// written in envelopes so a msg isn't decoded with the wrong codec
+    fn id(&self) -> u8;
+    fn encode(&self, msg: &T, buf: &mut Vec<u8>);
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// Compact binary format for the built-in msg, little endian seq u64 then text len u32 and utf8
+/// bytes after the text are ignored so later versions can add fields on the end
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BinaryCodec;
+
+impl Codec<(String, usize)> for BinaryCodec {
+    fn id(&self) -> u8 { 1 }
+
+    fn encode(&self, msg: &(String, usize), buf: &mut Vec<u8>) {
+        buf.extend((msg.1 as u64).to_le_bytes());
+        put_str(buf, &msg.0);
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<(String, usize), CodecError> {
+        let mut r = Reader(bytes);
+        let seq = r.u64().ok_or_else(|| CodecError::Malformed("missing seq".to_string()))?;
+        let text = r.string().ok_or_else(|| CodecError::Malformed("missing or invalid text".to_string()))?;
+        Ok((text, seq as usize))
+    }
+}
+
+/// JSON format for any msg type serde can handle
+#[cfg(feature = "serde")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SerdeCodec;
+
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for SerdeCodec {
+    fn id(&self) -> u8 { 2 }
+
+    fn encode(&self, msg: &T, buf: &mut Vec<u8>) {
+        serde_json::to_writer(buf, msg).expect("msg can be serialized");
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(bytes).map_err(|e| CodecError::Malformed(e.to_string()))
+    }
+}
+
+/*
+ * Versioned envelope: major u8, minor u8, codec id u8, header len u16, header, body
+ * A reader takes any minor version of a major it knows. Fields added in a later minor go in
+ * the header, which older readers skip, or on the end of the body. A new major is only for
+ * changes older readers can't skip, and they turn such msgs away.
+ */
+
+/// Envelope version written by this build
+pub const MAJOR: u8 = 1;
+pub const MINOR: u8 = 0;
+
+/// A msg along with the envelope version it was written with
+#[derive(Clone, Debug, PartialEq)]
+pub struct Versioned<T> {
+    pub major: u8,
+    pub minor: u8,
+    pub msg: T,
+}
+
+/// encode a msg in an envelope of the current version
+pub fn seal<T, C: Codec<T>>(codec: &C, msg: &T) -> Vec<u8> {
+    let mut buf = vec![MAJOR, MINOR, codec.id()];
+    buf.extend(0u16.to_le_bytes()); // no header fields yet
+    codec.encode(msg, &mut buf);
+    buf
+}
+
+/// decode a msg from an envelope written by this or any later minor version
+pub fn open<T, C: Codec<T>>(codec: &C, bytes: &[u8]) -> Result<Versioned<T>, CodecError> {
+    let mut r = Reader(bytes);
+    let truncated = || CodecError::Malformed("truncated envelope".to_string());
+    let (major, minor, id) = (r.u8().ok_or_else(truncated)?, r.u8().ok_or_else(truncated)?, r.u8().ok_or_else(truncated)?);
+    if major != MAJOR {
+        return Err(CodecError::UnsupportedVersion { major, minor });
+    }
+    if id != codec.id() {
+        return Err(CodecError::WrongCodec(id));
+    }
+    let header_len = r.u16().ok_or_else(truncated)?;
+    r.take(header_len as usize).ok_or_else(truncated)?;
+    Ok(Versioned { major, minor, msg: codec.decode(r.0)? })
+}
+
+#[test]
+fn test_binary_round_trip() {
+    let msg = ("this is the send message".to_string(), 42);
+    let bytes = seal(&BinaryCodec, &msg);
+    assert_eq!(open(&BinaryCodec, &bytes), Ok(Versioned { major: MAJOR, minor: MINOR, msg }));
+    assert!(matches!(open(&BinaryCodec, &bytes[..bytes.len() - 1]), Err(CodecError::Malformed(_))));
+    assert!(matches!(open(&BinaryCodec, &bytes[..2]), Err(CodecError::Malformed(_))));
+}
+
+#[test]
+fn test_newer_minor_accepted() {
+    // a later minor version with a header field and an extra body field
+    let mut bytes = vec![MAJOR, MINOR + 1, BinaryCodec.id()];
+    bytes.extend(3u16.to_le_bytes());
+    bytes.extend([9, 9, 9]);
+    BinaryCodec.encode(&("this is the send message".to_string(), 7), &mut bytes);
+    bytes.extend([1, 2, 3, 4]);
+    let v = open(&BinaryCodec, &bytes).unwrap();
+    assert_eq!(v.minor, MINOR + 1);
+    assert_eq!(v.msg, ("this is the send message".to_string(), 7));
+}
+
+#[test]
+fn test_newer_major_rejected() {
+    let mut bytes = seal(&BinaryCodec, &("this is the send message".to_string(), 7));
+    bytes[0] = MAJOR + 1;
+    assert_eq!(open(&BinaryCodec, &bytes), Err(CodecError::UnsupportedVersion { major: MAJOR + 1, minor: MINOR }));
+    bytes[0] = MAJOR;
+    bytes[2] = 99;
+    assert_eq!(open(&BinaryCodec, &bytes), Err(CodecError::WrongCodec(99)));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_round_trip() {
+    let msg = ("this is the send message".to_string(), 42);
+    let bytes = seal(&SerdeCodec, &msg);
+    let back: Versioned<(String, usize)> = open(&SerdeCodec, &bytes).unwrap();
+    assert_eq!(back.msg, msg);
+    assert_eq!(open::<(String, usize), _>(&BinaryCodec, &bytes), Err(CodecError::WrongCodec(2)));
+    let nums: Vec<u32> = vec![1, 2, 3];
+    let back: Versioned<Vec<u32>> = open(&SerdeCodec, &seal(&SerdeCodec, &nums)).unwrap();
+    assert_eq!(back.msg, nums);
+}
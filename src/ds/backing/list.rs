@@ -52,10 +52,12 @@ impl<TNode: NodeData> std::ops::IndexMut<usize> for ListData<TNode> {
 	}
 }
 
+#[cfg(test)]
 struct TestNode<T> {
 	val : T,
 }
 
+#[cfg(test)]
 impl<T:Copy> NodeData for TestNode<T> {
 	type TVal = T;
 
@@ -1,4 +1,4 @@
-use std::{fmt::Debug, usize};
+use std::fmt::Debug;
 use super::backing::{node::NodeData, list::ListData};
 
 struct Node<T> {
@@ -83,6 +83,12 @@ impl<T:Copy+PartialEq+PartialOrd> BinaryTree<T> {
     }
 }
 
+impl<T:Copy+PartialEq+PartialOrd> Default for BinaryTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct BinaryTreeIter<T:Copy> {
     curr_node_idx: Option<usize>,
 	walk_stack: Vec<usize>,
@@ -32,9 +32,11 @@ pub struct LinkedList<T:Copy> {
 /// # Examples
 /// 
 /// ```
-/// let l = SlList::<i32>::new();
+/// use rust_chan::ds::linked_list::LinkedList;
+///
+/// let l = LinkedList::<i32>::new();
 /// for item in l {
-///     println!(item);
+///     println!("{item}");
 /// }
 /// ```
 pub struct LinkedListIter<T:Copy> {
@@ -46,14 +48,7 @@ impl<T: Copy> Iterator for LinkedListIter<T> {
 	type Item = T;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		let idx = match self.curr_idx {
-			None => {
-				return None
-			},
-			Some(i) => {
-				i
-			}
-		};
+		let idx = self.curr_idx?;
 		self.curr_idx = self.list.data[idx].next;
 		Some(self.list.data[idx].val)
 	}
@@ -118,10 +113,7 @@ impl<T: Copy> LinkedList<T> {
 	}
 	/// fetch and remove a value from the back of the list
 	pub fn remove_tail(&mut self) -> Option<T> {
-		let tail_idx = match self.tail {
-			None => return None,
-			Some(i) => i
-		};
+		let tail_idx = self.tail?;
 		let result = self.data[tail_idx].val;
 		self.data.rem_item(tail_idx);
 		if self.head == self.tail {
@@ -150,7 +142,14 @@ impl<T: Copy> LinkedList<T> {
 
 }
 
+impl<T: Copy> Default for LinkedList<T> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
 #[test]
+#[allow(clippy::assertions_on_constants)]
 fn empty() {
 	let list = LinkedList::<i32>::new();
 	assert_eq!(list.len(), 0);
@@ -204,6 +203,7 @@ fn pop() {
 }
 
 #[test]
+#[allow(clippy::useless_vec, clippy::explicit_counter_loop)]
 fn push_n_pop() {
 	let mut list = LinkedList::<i32>::new();
 	list.push(1);
@@ -257,6 +257,7 @@ fn rem_tail() {
 }
 
 #[test]
+#[allow(clippy::explicit_counter_loop)]
 fn all_ops() {
 	let mut list = LinkedList::<i32>::new();
 	assert!(list.is_empty());
@@ -0,0 +1,206 @@
+use std::{fmt, sync::{Arc, Mutex, RwLock}, time::Duration};
+
+/*
+ * Buses, workers and connections report what they do as events to a single listener for the
+ * whole process instead of printing. Nothing is reported until a listener is set.
+ */
+
+/// Something that happened in a bus, worker or connection
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    WorkerStarted { worker: String },
+    WorkerStopped { worker: String },
+    MessageSent { msg: (String, usize) },
+    MessageReceived { worker: String, msg: (String, usize) },
+    SendFailed { msg: (String, usize), error: String },
+    /// taken off the bus after it was cancelled, so never run
+    MessageSkipped { worker: String, msg: (String, usize) },
+    /// queued again from a persistent bus' log
+    MessageRecovered { msg: (String, usize) },
+    BatchReceived { worker: String, size: usize },
+    /// handler failed and the msgs will be tried again
+    Retrying { worker: String, msgs: usize, error: String },
+    /// handler failed after its job was cancelled, the msgs aren't retried
+    JobCancelled { worker: String, msgs: usize, error: String },
+    DeadLettered { worker: String, msg: (String, usize), attempts: u32, error: String },
+    /// a remote handler failed, it isn't retried
+    MessageFailed { worker: String, msg: (String, usize), error: String },
+    Connected { worker: String },
+    Disconnected { worker: String, error: String },
+    WorkerStuck { worker: String, busy_for: Duration },
+    /// anything else that went wrong, `source` says where
+    Error { source: String, error: String },
+}
+
+impl Event {
+    /// name of the worker the event is about, if any
+    pub fn worker(&self) -> Option<&str> {
+        match self {
+            Event::WorkerStarted { worker }
+            | Event::WorkerStopped { worker }
+            | Event::MessageReceived { worker, .. }
+            | Event::MessageSkipped { worker, .. }
+            | Event::BatchReceived { worker, .. }
+            | Event::Retrying { worker, .. }
+            | Event::JobCancelled { worker, .. }
+            | Event::DeadLettered { worker, .. }
+            | Event::MessageFailed { worker, .. }
+            | Event::Connected { worker }
+            | Event::Disconnected { worker, .. }
+            | Event::WorkerStuck { worker, .. } => Some(worker),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::WorkerStarted { worker } => write!(f, "Creating worker: {:?}", worker),
+            Event::WorkerStopped { worker } => write!(f, "  worker '{}' | stopped", worker),
+            Event::MessageSent { msg } => write!(f, "  main        | sending msg # {} : {}", msg.1, msg.0),
+            Event::MessageReceived { worker, msg } => write!(f, "  worker '{}' | received msg # {} : {}", worker, msg.1, msg.0),
+            Event::SendFailed { msg, error } => write!(f, "Send error: msg # {} : {}", msg.1, error),
+            Event::MessageSkipped { worker, msg } => write!(f, "  worker '{}' | skipping cancelled msg # {} : {}", worker, msg.1, msg.0),
+            Event::MessageRecovered { msg } => write!(f, "  main        | recovered msg # {} : {}", msg.1, msg.0),
+            Event::BatchReceived { worker, size } => write!(f, "  worker '{}' | processing batch of {} msg(s)", worker, size),
+            Event::Retrying { worker, msgs, error } => write!(f, "  worker '{}' | retrying {} msg(s) : {}", worker, msgs, error),
+            Event::JobCancelled { worker, msgs, error } => write!(f, "  worker '{}' | cancelled {} msg(s) : {}", worker, msgs, error),
+            Event::DeadLettered { worker, msg, attempts, error } => write!(f, "  worker '{}' | dead-lettered msg # {} after {} attempts: {}", worker, msg.1, attempts, error),
+            Event::MessageFailed { worker, msg, error } => write!(f, "  worker '{}' | msg # {} failed: {}", worker, msg.1, error),
+            Event::Connected { worker } => write!(f, "  worker '{}' | connected", worker),
+            Event::Disconnected { worker, error } => write!(f, "  worker '{}' | lost connection: {}", worker, error),
+            Event::WorkerStuck { worker, busy_for } => write!(f, "  watchdog    | worker '{}' on one msg for {:?}", worker, busy_for),
+            Event::Error { source, error } => write!(f, "  {:<11} | {}", source, error),
+        }
+    }
+}
+
+/// Told about every event, called on whichever thread the event happened
+pub trait EventListener: Send + Sync {
+    fn on_event(&self, event: &Event);
+}
+
+/// Ignores every event, the same as having no listener set
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopListener;
+
+impl EventListener for NoopListener {
+    fn on_event(&self, _: &Event) {}
+}
+
+/// Prints every event on its own line
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdoutListener;
+
+impl EventListener for StdoutListener {
+    fn on_event(&self, event: &Event) {
+        println!("{}", event);
+    }
+}
+
+/// Keeps every event so tests can check what happened
+#[derive(Debug, Default)]
+pub struct RecordingListener {
+    events: Mutex<Vec<Event>>,
+}
+
+impl RecordingListener {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// every event so far, oldest first
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// events about the named worker, oldest first
+    pub fn events_for(&self, worker: &str) -> Vec<Event> {
+        self.events.lock().unwrap().iter().filter(|e| e.worker() == Some(worker)).cloned().collect()
+    }
+
+    pub fn clear(&self) {
+        self.events.lock().unwrap().clear();
+    }
+}
+
+impl EventListener for RecordingListener {
+    fn on_event(&self, event: &Event) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+}
+
+static LISTENER: RwLock<Option<Arc<dyn EventListener>>> = RwLock::new(None);
+
+/// send every event from now on to the listener, in place of any set before
+pub fn set_listener(listener: Arc<dyn EventListener>) {
+    *LISTENER.write().unwrap() = Some(listener);
+}
+
+/// the listener is called outside the lock, so it may set another and a slow one holds up no one
+pub(crate) fn emit(event: Event) {
+    let listener = LISTENER.read().unwrap().clone();
+    if let Some(listener) = listener {
+        listener.on_event(&event);
+    }
+}
+
+/// recording listener shared by every test, tests run at the same time so each
+/// should only look at events for its own workers
+#[cfg(test)]
+pub(crate) fn recorder() -> Arc<RecordingListener> {
+    static RECORDER: std::sync::OnceLock<Arc<RecordingListener>> = std::sync::OnceLock::new();
+    RECORDER.get_or_init(|| {
+        let recorder = Arc::new(RecordingListener::new());
+        set_listener(recorder.clone());
+        recorder
+    }).clone()
+}
+
+#[test]
+fn test_single_events() {
+    use crate::single::{MessageBus, Worker};
+
+    let recorder = recorder();
+    let mb = MessageBus::new(4);
+    let wrk = Worker::new("Events single".to_string(), &mb, false);
+    assert!(mb.send(("this is the send message".to_string(), 1)));
+    assert!(mb.wait_drained(Duration::from_secs(1)));
+    wrk.stop();
+    mb.close();
+    assert!(!mb.send(("events closed bus".to_string(), 2)));
+    assert_eq!(recorder.events_for("Events single"), vec![
+        Event::WorkerStarted { worker: "Events single".to_string() },
+        Event::MessageReceived { worker: "Events single".to_string(), msg: ("this is the send message".to_string(), 1) },
+        Event::WorkerStopped { worker: "Events single".to_string() },
+    ]);
+    assert!(recorder.events().contains(&Event::SendFailed { msg: ("events closed bus".to_string(), 2), error: "bus is closed".to_string() }));
+}
+
+#[test]
+fn test_broadcast_events() {
+    use crate::broadcast::{Worker, WorkerManager};
+
+    let recorder = recorder();
+    let mut workers = WorkerManager::new();
+    workers.add(Worker::new("Events broadcast".to_string(), false));
+    assert!(workers.send(("events broadcast msg".to_string(), 1)));
+    std::thread::sleep(Duration::from_millis(100));
+    drop(workers);
+    assert_eq!(recorder.events_for("Events broadcast"), vec![
+        Event::WorkerStarted { worker: "Events broadcast".to_string() },
+        Event::MessageReceived { worker: "Events broadcast".to_string(), msg: ("events broadcast msg".to_string(), 1) },
+        Event::WorkerStopped { worker: "Events broadcast".to_string() },
+    ]);
+    assert!(recorder.events().contains(&Event::MessageSent { msg: ("events broadcast msg".to_string(), 1) }));
+}
+
+#[test]
+fn test_event_display() {
+    let msg = ("this is the msg".to_string(), 3);
+    assert_eq!(Event::MessageSent { msg: msg.clone() }.to_string(), "  main        | sending msg # 3 : this is the msg");
+    assert_eq!(Event::MessageReceived { worker: "Worker 1".to_string(), msg }.to_string(), "  worker 'Worker 1' | received msg # 3 : this is the msg");
+    assert_eq!(Event::WorkerStarted { worker: "Worker 1".to_string() }.worker(), Some("Worker 1"));
+    assert_eq!(Event::Error { source: "log".to_string(), error: "disk full".to_string() }.worker(), None);
+}
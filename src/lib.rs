@@ -0,0 +1,18 @@
+// tests from before the lint gate compare bools with assert_eq
+#![cfg_attr(test, allow(clippy::bool_assert_comparison))]
+
+pub mod actor;
+pub mod clock;
+pub mod codec;
+pub mod event;
+pub mod single;
+pub mod broadcast;
+pub mod ds;
+pub mod net;
+pub mod pipeline;
+pub mod runtime;
+pub mod watchdog;
+
+#[cfg(all(test, loom))]
+mod loom_model;
+mod record;
@@ -0,0 +1,183 @@
+use std::time::Duration;
+
+use loom::{sync::{Arc, Mutex, RwLock, atomic::{AtomicBool, Ordering}}, thread};
+
+use crate::{runtime::{Inbox, Runtime, WorkerState, take_through}, watchdog::Health};
+
+/*
+ * Model checks of the worker lifecycle, only built with --cfg loom.
+ * Under loom the runtime's locks, flags and threads are loom's (see runtime/sync.rs), so each
+ * check runs a real worker, `Runtime::run` and its loop, stop signal and pause gate included.
+ * Crossbeam channels and real time aren't visible to loom, so the worker takes its msgs from
+ * an inbox built on loom's primitives:
+ *   channel       a one msg slot behind a mutex, like the bounded(1) queues in the tests
+ *   recv          take a msg through the worker's pause gate, on nothing yield and let the
+ *                 worker loop round to check again, which is all a real timeout does
+ *   closed        set under a write lock, sends hold it for reading like a single bus' flag
+ *   send_timeout  put the msg in the slot once there is room, unless a timer thread that loom
+ *                 may run at any point has expired first
+ * loom runs every interleaving of the threads and fails on a deadlock or a failed assert.
+ */
+
+/// A bounded channel with room for one msg
+#[derive(Default)]
+struct Slot(Mutex<Option<u32>>);
+
+impl Slot {
+    fn try_recv(&self) -> Option<u32> {
+        self.0.lock().unwrap().take()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_none()
+    }
+
+    /// wait for room until the timer expires
+    fn send_timeout(&self, msg: u32, expired: &AtomicBool) -> bool {
+        loop {
+            {
+                let mut slot = self.0.lock().unwrap();
+                if slot.is_none() {
+                    *slot = Some(msg);
+                    return true;
+                }
+            }
+            if expired.load(Ordering::SeqCst) {
+                return false;
+            }
+            thread::yield_now();
+        }
+    }
+}
+
+/// A worker's inbox on the slot, closed once the flag is set and the slot is empty
+/// a broadcast worker's inbox is never closed, a single bus worker's once the bus is closed and drained
+struct SlotInbox {
+    slot: Arc<Slot>,
+    closed: Arc<RwLock<bool>>,
+}
+
+impl Inbox for SlotInbox {
+    type Item = u32;
+
+    fn recv(&mut self, worker: &WorkerState, _: Duration) -> Option<u32> {
+        match take_through(&[&worker.pause], || self.slot.try_recv()).flatten() {
+            Some(msg) => {
+                worker.took_job();
+                Some(msg)
+            },
+            None => {
+                thread::yield_now();
+                None
+            },
+        }
+    }
+
+    fn closed(&self) -> bool {
+        *self.closed.read().unwrap() && self.slot.is_empty()
+    }
+}
+
+/// start a timer that can expire at any point in the run
+fn timer() -> (Arc<AtomicBool>, thread::JoinHandle<()>) {
+    let expired = Arc::new(AtomicBool::new(false));
+    let expired_ = expired.clone();
+    (expired, thread::spawn(move || expired_.store(true, Ordering::SeqCst)))
+}
+
+/// start a worker on the slot that does nothing with its msgs but count them
+fn worker(slot: &Arc<Slot>, closed: &Arc<RwLock<bool>>) -> (Runtime, WorkerState) {
+    let wrk = Runtime::run(WorkerState::new(Health::new("Worker 1", || {})), SlotInbox { slot: slot.clone(), closed: closed.clone() }, |_, _, _| {});
+    let state = wrk.state().clone();
+    (wrk, state)
+}
+
+/// a worker whose inbox is never closed
+fn broadcast_worker(slot: &Arc<Slot>) -> (Runtime, WorkerState) {
+    worker(slot, &Arc::new(RwLock::new(false)))
+}
+
+/// msgs the worker has taken
+fn cnt(state: &WorkerState) -> u32 { *state.rec_cnt.lock().unwrap() }
+
+#[test]
+fn loom_stop_idle_worker() {
+    loom::model(|| {
+        let (wrk, state) = broadcast_worker(&Arc::new(Slot::default()));
+        wrk.stop();
+        assert_eq!(cnt(&state), 0);
+    });
+}
+
+#[test]
+fn loom_broadcast_send_then_stop() {
+    loom::model(|| {
+        let slot = Arc::new(Slot::default());
+        let (wrk, state) = broadcast_worker(&slot);
+        // fill the slot so the second send races its timer against the worker taking the first
+        assert!(slot.send_timeout(1, &AtomicBool::new(true)));
+        let (expired, t) = timer();
+        let sent = slot.send_timeout(2, &expired);
+        wrk.stop();
+        t.join().unwrap();
+        // every msg that was sent was counted once or is still queued, none went missing
+        let queued = u32::from(!slot.is_empty());
+        assert_eq!(cnt(&state) + queued, 1 + u32::from(sent));
+    });
+}
+
+#[test]
+fn loom_single_close_drains() {
+    loom::model(|| {
+        let (slot, closed) = (Arc::new(Slot::default()), Arc::new(RwLock::new(false)));
+        let (wrk, state) = worker(&slot, &closed);
+        let (slot_, closed_) = (slot.clone(), closed.clone());
+        let producer = thread::spawn(move || {
+            // sends hold the closed flag for reading so close waits for them
+            let closed = closed_.read().unwrap();
+            !*closed && slot_.send_timeout(1, &AtomicBool::new(false))
+        });
+        *closed.write().unwrap() = true;
+        let sent = producer.join().unwrap();
+        // the worker exits on its own and has taken everything sent before the close
+        wrk.join();
+        assert_eq!(cnt(&state), u32::from(sent));
+        assert!(slot.is_empty());
+    });
+}
+
+#[test]
+fn loom_single_stop_during_send() {
+    loom::model(|| {
+        let (slot, closed) = (Arc::new(Slot::default()), Arc::new(RwLock::new(false)));
+        assert!(slot.send_timeout(1, &AtomicBool::new(true)));
+        let (wrk, state) = worker(&slot, &closed);
+        let slot_ = slot.clone();
+        let producer = thread::spawn(move || {
+            let (expired, t) = timer();
+            let sent = slot_.send_timeout(2, &expired);
+            t.join().unwrap();
+            sent
+        });
+        wrk.stop();
+        let sent = producer.join().unwrap();
+        // stopping never loses a msg, it was either taken or is left for another worker
+        let queued = u32::from(!slot.is_empty());
+        assert_eq!(cnt(&state) + queued, 1 + u32::from(sent));
+    });
+}
+
+#[test]
+fn loom_pause_stops_taking() {
+    loom::model(|| {
+        let slot = Arc::new(Slot::default());
+        assert!(slot.send_timeout(1, &AtomicBool::new(true)));
+        let (wrk, state) = broadcast_worker(&slot);
+        wrk.pause();
+        // once pause returns the msg is either already taken or stays queued
+        let queued = !slot.is_empty();
+        wrk.stop();
+        assert_eq!(!slot.is_empty(), queued);
+        assert_eq!(cnt(&state), u32::from(!queued));
+    });
+}
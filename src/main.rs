@@ -1,10 +1,9 @@
-mod single;
-mod broadcast;
-mod ds;
+use std::{sync::Arc, time::Duration};
 
-use std::time::Duration;
+use rust_chan::{broadcast, ds, event, single};
 
 fn main() {
+    event::set_listener(Arc::new(event::StdoutListener));
     println!("Running channels broadcast");
     let mut workers = broadcast::WorkerManager::new();
     for n in 1..=5 {
@@ -52,10 +51,8 @@ fn main() {
 	let x = list.remove_tail();
 	assert_eq!(x.unwrap(), 45);
 	assert_eq!(list.len(), 4);
-	let mut i = 1;
-	for x in list {
-		println!("item {i} = {x}");
-		i += 1;
+	for (i, x) in list.into_iter().enumerate() {
+		println!("item {} = {x}", i + 1);
 	}
 
     println!("Running Binary Tree");
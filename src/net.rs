@@ -0,0 +1,420 @@
+use std::{io::{self, Read, Write}, net::{Shutdown, SocketAddr, TcpStream}, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread::JoinHandle, time::{Duration, Instant}};
+
+use crate::{codec::{self, BinaryCodec}, record::{self, Reader, frame, put_bytes, put_str}, event::{self, Event}, runtime::{WorkerState, sync}, single::{Backoff, HandlerResult, Priority}};
+
+/*
+ * Frames sent between processes. Every frame is a record (len, checksum, payload)
+ * whose payload starts with its tag, msgs inside are sealed with the binary codec.
+ * Both ends of a connection send heartbeats so either side can tell when the other
+ * has gone quiet and drop it.
+ */
+
+const HELLO_PRODUCER: u8 = 1;
+const HELLO_WORKER: u8 = 2;
+const SEND: u8 = 3;
+const SENT: u8 = 4;
+const JOB: u8 = 5;
+const DONE: u8 = 6;
+const HEARTBEAT: u8 = 7;
+
+/// How often blocked reads wake up to check for a stop
+pub(crate) const POLL: Duration = Duration::from_millis(50);
+
+/// Heartbeat and reconnect settings for connections between processes
+#[derive(Clone, Debug)]
+pub struct NetOptions {
+    pub heartbeat: Duration, // how often each end says it is still there
+    pub dead_after: Duration, // the other end is dropped after hearing nothing for this long
+    pub reconnect: Backoff, // wait between attempts to get a lost connection back
+}
+
+impl Default for NetOptions {
+    fn default() -> Self {
+        Self {
+            heartbeat: Duration::from_millis(500),
+            dead_after: Duration::from_secs(3),
+            reconnect: Backoff::Exponential { initial: Duration::from_millis(100), max: Duration::from_secs(5) },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Frame {
+    HelloProducer,
+    HelloWorker(String),
+    Send(Priority, (String, usize)),
+    Sent(bool),
+    Job(u64, (String, usize)), // no. matching the job up with its result
+    Done(u64, HandlerResult),
+    Heartbeat,
+}
+
+/// msgs go in a versioned envelope so either end can be upgraded first
+fn put_msg(p: &mut Vec<u8>, msg: &(String, usize)) {
+    put_bytes(p, &codec::seal(&BinaryCodec, msg));
+}
+
+fn get_msg(r: &mut Reader) -> Option<(String, usize)> {
+    match codec::open(&BinaryCodec, r.bytes()?) {
+        Ok(v) => Some(v.msg),
+        Err(error) => {
+            event::emit(Event::Error { source: "receive".to_string(), error: error.to_string() });
+            None
+        },
+    }
+}
+
+impl Frame {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut p = Vec::new();
+        match self {
+            Frame::HelloProducer => p.push(HELLO_PRODUCER),
+            Frame::HelloWorker(nm) => {
+                p.push(HELLO_WORKER);
+                put_str(&mut p, nm);
+            },
+            Frame::Send(priority, msg) => {
+                p.extend([SEND, *priority as u8]);
+                put_msg(&mut p, msg);
+            },
+            Frame::Sent(ok) => p.extend([SENT, *ok as u8]),
+            Frame::Job(no, msg) => {
+                p.push(JOB);
+                p.extend(no.to_le_bytes());
+                put_msg(&mut p, msg);
+            },
+            Frame::Done(no, result) => {
+                p.push(DONE);
+                p.extend(no.to_le_bytes());
+                p.push(result.is_ok() as u8);
+                put_str(&mut p, result.as_ref().err().map_or("", |e| e.as_str()));
+            },
+            Frame::Heartbeat => p.push(HEARTBEAT),
+        }
+        frame(p)
+    }
+
+    fn decode(payload: &[u8]) -> Option<Frame> {
+        let mut r = Reader(payload);
+        Some(match r.u8()? {
+            HELLO_PRODUCER => Frame::HelloProducer,
+            HELLO_WORKER => Frame::HelloWorker(r.string()?),
+            SEND => Frame::Send(*Priority::ALL.get(r.u8()? as usize)?, get_msg(&mut r)?),
+            SENT => Frame::Sent(r.u8()? != 0),
+            JOB => Frame::Job(r.u64()?, get_msg(&mut r)?),
+            DONE => {
+                let no = r.u64()?;
+                let ok = r.u8()? != 0;
+                let error = r.string()?;
+                Frame::Done(no, if ok { Ok(()) } else { Err(error) })
+            },
+            HEARTBEAT => Frame::Heartbeat,
+            _ => return None,
+        })
+    }
+}
+
+/// A stream to another process
+pub(crate) trait Conn: Read + Write + Send + Sized + 'static {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn try_clone(&self) -> io::Result<Self>;
+    /// close both directions, unblocking anything reading or writing
+    fn shutdown(&self);
+}
+
+impl Conn for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self) {
+        let _ = TcpStream::shutdown(self, Shutdown::Both);
+    }
+}
+
+#[cfg(unix)]
+impl Conn for std::os::unix::net::UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        std::os::unix::net::UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self) {
+        let _ = std::os::unix::net::UnixStream::shutdown(self, Shutdown::Both);
+    }
+}
+
+/// open a TCP connection, frames are small so they're sent straight away
+pub(crate) fn tcp_connect(addr: SocketAddr, opts: &NetOptions) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&addr, opts.dead_after)?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// take the next waiting connection off a non-blocking listener, None if there isn't one
+pub(crate) fn tcp_accept(listener: &std::net::TcpListener) -> io::Result<Option<TcpStream>> {
+    match listener.accept() {
+        Ok((stream, _)) => {
+            stream.set_nonblocking(false)?;
+            stream.set_nodelay(true)?;
+            Ok(Some(stream))
+        },
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub(crate) fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+fn write_frame<C: Conn>(conn: &mut C, f: &Frame) -> io::Result<()> {
+    conn.write_all(&f.encode())?;
+    conn.flush()
+}
+
+/// wait up to the timeout for a frame to start arriving, None if nothing did
+/// once a frame has started the rest of it has to keep arriving, a wait of `dead_after` for it fails
+fn poll_frame<C: Conn>(conn: &mut C, timeout: Duration, dead_after: Duration) -> io::Result<Option<Frame>> {
+    // a zero timeout means block forever
+    conn.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+    let mut first = [0; 1];
+    match conn.read(&mut first) {
+        Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+        Ok(_) => {},
+        Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    conn.set_read_timeout(Some(dead_after.max(Duration::from_millis(1))))?;
+    let payload = record::read_frame(&mut (&first[..]).chain(&mut *conn)).map_err(|e| match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(io::ErrorKind::TimedOut, "other end stopped mid-frame"),
+        _ => e,
+    })?;
+    Frame::decode(&payload).map(Some).ok_or_else(|| invalid("unknown frame"))
+}
+
+/// A connection that sends heartbeats and notices when the other end stops sending them
+pub(crate) struct Link<C: Conn> {
+    reader: C,
+    writer: Arc<Mutex<C>>, // shared with the heartbeat thread
+    last_seen: Instant, // last time anything arrived
+    dead_after: Duration,
+    closed: Arc<AtomicBool>,
+    beat: Option<JoinHandle<()>>,
+}
+
+impl<C: Conn> Link<C> {
+    pub(crate) fn new(conn: C, opts: &NetOptions) -> io::Result<Self> {
+        let writer = Arc::new(Mutex::new(conn.try_clone()?));
+        let closed = Arc::new(AtomicBool::new(false));
+        let (writer_, closed_, every) = (writer.clone(), closed.clone(), opts.heartbeat);
+        let beat = std::thread::spawn(move || {
+            let mut next = Instant::now();
+            while !closed_.load(Ordering::SeqCst) {
+                if Instant::now() >= next {
+                    if write_frame(&mut *writer_.lock().unwrap(), &Frame::Heartbeat).is_err() {
+                        break;
+                    }
+                    next = Instant::now() + every;
+                }
+                std::thread::sleep(next.saturating_duration_since(Instant::now()).min(POLL));
+            }
+        });
+        Ok(Self { reader: conn, writer, last_seen: Instant::now(), dead_after: opts.dead_after, closed, beat: Some(beat) })
+    }
+
+    pub(crate) fn send(&self, f: &Frame) -> io::Result<()> {
+        write_frame(&mut *self.writer.lock().unwrap(), f)
+    }
+
+    /// next frame other than a heartbeat, None if nothing came within the timeout
+    /// fails once the other end has been quiet for longer than `dead_after`
+    pub(crate) fn recv(&mut self, timeout: Duration) -> io::Result<Option<Frame>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match poll_frame(&mut self.reader, deadline.saturating_duration_since(Instant::now()), self.dead_after)? {
+                Some(Frame::Heartbeat) => self.last_seen = Instant::now(),
+                Some(f) => {
+                    self.last_seen = Instant::now();
+                    return Ok(Some(f));
+                },
+                None => {},
+            }
+            if self.last_seen.elapsed() > self.dead_after {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no heartbeat from the other end"));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// wait for a frame, however long it takes while the other end is alive
+    pub(crate) fn recv_wait(&mut self) -> io::Result<Frame> {
+        loop {
+            if let Some(f) = self.recv(POLL)? {
+                return Ok(f);
+            }
+        }
+    }
+}
+
+impl<C: Conn> Drop for Link<C> {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.reader.shutdown();
+        if let Some(beat) = self.beat.take() {
+            let _ = beat.join();
+        }
+    }
+}
+
+/// Hands jobs over a link and waits for their results
+pub(crate) struct Caller<C: Conn> {
+    pub(crate) link: Link<C>,
+    next_no: u64,
+}
+
+impl<C: Conn> Caller<C> {
+    pub(crate) fn new(link: Link<C>) -> Self {
+        Self { link, next_no: 0 }
+    }
+
+    /// run a msg on the other end, returning its handler's result
+    /// stops waiting if `abandon` turns true, a late result is then ignored
+    pub(crate) fn call(&mut self, msg: &(String, usize), abandon: impl Fn() -> bool) -> io::Result<HandlerResult> {
+        let no = self.next_no;
+        self.next_no += 1;
+        self.link.send(&Frame::Job(no, msg.clone()))?;
+        loop {
+            if abandon() {
+                return Ok(Err("remote job abandoned".to_string()));
+            }
+            match self.link.recv(POLL)? {
+                Some(Frame::Done(n, result)) if n == no => return Ok(result),
+                Some(Frame::Done(..)) => {}, // from a job given up on earlier
+                Some(_) => return Err(invalid("expected a result")),
+                None => {},
+            }
+        }
+    }
+}
+
+/// run every job handed over the link until the worker is stopped, fails once the link is lost
+pub(crate) fn serve_jobs<C, F>(link: &mut Link<C>, worker: &WorkerState, handler: &F) -> io::Result<()>
+where C: Conn, F: Fn(&(String, usize)) -> HandlerResult {
+    while !worker.stopped() {
+        match link.recv(Duration::from_millis(500))? {
+            Some(Frame::Job(no, msg)) => {
+                // hold the job while paused, the caller waits on it
+                while worker.pause.is_paused() && !worker.stopped() {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                if worker.stopped() {
+                    break;
+                }
+                worker.took(&msg);
+                let result = handler(&msg);
+                worker.health.done();
+                link.send(&Frame::Done(no, result))?;
+            },
+            Some(_) => return Err(invalid("expected a job")),
+            None => {},
+        }
+    }
+    Ok(())
+}
+
+/// sleep for the duration unless interrupted first
+fn pause(d: Duration, interrupt: &sync::AtomicBool) {
+    let deadline = Instant::now() + d;
+    while !interrupt.load(Ordering::SeqCst) && Instant::now() < deadline {
+        std::thread::sleep(deadline.saturating_duration_since(Instant::now()).min(POLL));
+    }
+}
+
+/// run sessions over a link until one ends cleanly or the interrupt is set,
+/// reconnecting with backoff each time the link is lost
+/// `first` is an already open connection to start with
+pub(crate) fn reconnecting<C, K, S>(nm: &str, first: C, connect: K, opts: &NetOptions, interrupt: &sync::AtomicBool, mut session: S)
+where C: Conn, K: Fn() -> io::Result<C>, S: FnMut(Link<C>) -> io::Result<()> {
+    let mut first = Some(first);
+    let mut attempt = 0;
+    while !interrupt.load(Ordering::SeqCst) {
+        let conn = match first.take() {
+            Some(c) => Ok(c),
+            None => connect(),
+        };
+        match conn.and_then(|c| Link::new(c, opts)) {
+            Ok(link) => {
+                attempt = 0;
+                match session(link) {
+                    Ok(()) => return,
+                    Err(error) => event::emit(Event::Disconnected { worker: nm.to_string(), error: error.to_string() }),
+                }
+            },
+            Err(error) => event::emit(Event::Error { source: format!("worker '{}'", nm), error: format!("connect error: {}", error) }),
+        }
+        attempt += 1;
+        pause(opts.reconnect.delay(attempt), interrupt);
+    }
+}
+
+/// keep trying to connect with backoff until `give_up` has passed
+pub(crate) fn connect_for<C, K>(connect: &K, opts: &NetOptions, give_up: Duration) -> io::Result<Link<C>>
+where C: Conn, K: Fn() -> io::Result<C> {
+    let deadline = Instant::now() + give_up;
+    let mut attempt = 0;
+    loop {
+        match connect().and_then(|c| Link::new(c, opts)) {
+            Ok(link) => return Ok(link),
+            Err(error) if Instant::now() >= deadline => return Err(error),
+            Err(_) => {},
+        }
+        attempt += 1;
+        std::thread::sleep(opts.reconnect.delay(attempt).min(deadline.saturating_duration_since(Instant::now())));
+    }
+}
+
+#[test]
+fn test_frame_round_trip() {
+    let frames = [
+        Frame::HelloProducer,
+        Frame::HelloWorker("Worker 1".to_string()),
+        Frame::Send(Priority::Low, ("this is the send message".to_string(), 7)),
+        Frame::Sent(false),
+        Frame::Job(3, ("this is the send message".to_string(), 8)),
+        Frame::Done(3, Ok(())),
+        Frame::Done(4, Err("failed".to_string())),
+        Frame::Heartbeat,
+    ];
+    for f in frames {
+        let bytes = f.encode();
+        assert_eq!(Frame::decode(&record::read_frame(&mut &bytes[..]).unwrap()), Some(f));
+    }
+}
+
+#[test]
+fn test_stall_mid_frame() {
+    use std::{io::Write, net::{TcpListener, TcpStream}};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (conn, _) = listener.accept().unwrap();
+    let opts = NetOptions { heartbeat: Duration::from_millis(50), dead_after: Duration::from_millis(200), ..NetOptions::default() };
+    let mut link = Link::new(conn, &opts).unwrap();
+    // half a frame and then nothing, with the connection left open
+    let bytes = Frame::HelloProducer.encode();
+    peer.write_all(&bytes[..bytes.len() / 2]).unwrap();
+    let start = Instant::now();
+    let err = link.recv(Duration::from_secs(5)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
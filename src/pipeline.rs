@@ -0,0 +1,220 @@
+use std::{sync::Arc, time::Duration};
+
+use crossbeam_channel::Sender;
+
+use crate::{clock::{self, Clock}, event::{self, Event}, runtime::{ChannelInbox, Runtime, WorkerState}, watchdog::Health};
+
+/*
+ * Each stage of a pipeline is a bounded queue with a number of workers taking from it, and
+ * every msg a stage's handler returns is put on the next stage's queue. Putting a msg on a
+ * full queue blocks, so a slow stage fills its queue, then holds up the workers of the stage
+ * before it and so on back to whoever is sending into the pipeline.
+ * Shutting down drops the sender into the first stage. A stage's workers exit once its queue
+ * is empty and every sender into it is gone, which drops their senders into the next stage,
+ * so the stages drain one after the other.
+ */
+
+/// Msgs a stage hands on to the next one, an error drops the msg it was given
+pub type StageResult = Result<Vec<(String, usize)>, String>;
+
+type StageHandler = Arc<dyn Fn(&(String, usize)) -> StageResult + Send + Sync>;
+
+struct StageSpec {
+    nm: String,
+    parallelism: usize, // number of workers
+    buffer: usize, // msgs queued for the stage before sends into it block
+    handler: StageHandler,
+}
+
+/// Lists the stages of a pipeline, in the order msgs flow through them
+pub struct PipelineBuilder {
+    stages: Vec<StageSpec>,
+    clock: Arc<dyn Clock>,
+}
+
+impl PipelineBuilder {
+    pub fn new() -> Self {
+        Self { stages: Vec::new(), clock: clock::real() }
+    }
+
+    /// add a stage of `parallelism` workers running the handler, fed by a queue of `buffer` msgs
+    /// whatever the last stage returns is dropped
+    pub fn stage<F>(mut self, nm: &str, parallelism: usize, buffer: usize, handler: F) -> Self
+    where F: Fn(&(String, usize)) -> StageResult + Send + Sync + 'static {
+        self.stages.push(StageSpec { nm: nm.to_string(), parallelism: parallelism.max(1), buffer: buffer.max(1), handler: Arc::new(handler) });
+        self
+    }
+
+    /// time worker polling and send timeouts by the clock
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// start every stage's workers
+    pub fn build(self) -> Pipeline {
+        assert!(!self.stages.is_empty(), "a pipeline needs at least one stage");
+        let (mut txs, rxs): (Vec<Sender<(String, usize)>>, Vec<_>) = self.stages.iter().map(|s| crossbeam_channel::bounded(s.buffer)).unzip();
+        let mut stages = Vec::new();
+        for (k, (spec, rx)) in self.stages.into_iter().zip(rxs).enumerate() {
+            let next = txs.get(k + 1);
+            let workers = (1..=spec.parallelism).map(|n| {
+                // jobs can't be cancelled
+                let state = WorkerState::new(Health::new(&format!("{} {n}", spec.nm), || {}));
+                let (handler, next) = (spec.handler.clone(), next.cloned());
+                Runtime::run(state, ChannelInbox::new(rx.clone(), self.clock.clone()), move |state, _, msg| {
+                    match handler(&msg) {
+                        Ok(outs) => if let Some(next) = &next {
+                            for out in outs {
+                                // blocks while the next stage is full
+                                let _ = next.send(out);
+                            }
+                        },
+                        Err(error) => event::emit(Event::MessageFailed { worker: state.nm.clone(), msg, error }),
+                    }
+                })
+            }).collect();
+            stages.push(Stage { nm: spec.nm, workers });
+        }
+        // only the pipeline sends into the first stage, and only the stage before sends into the rest
+        txs.truncate(1);
+        Pipeline { input: txs.pop(), stages, clock: self.clock }
+    }
+}
+
+impl Default for PipelineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Stage {
+    nm: String,
+    workers: Vec<Runtime>,
+}
+
+/// Stages of workers with the output of each flowing into the next
+pub struct Pipeline {
+    input: Option<Sender<(String, usize)>>, // into the first stage, None once shut down
+    stages: Vec<Stage>,
+    clock: Arc<dyn Clock>, // times out sends
+}
+
+impl Pipeline {
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::new()
+    }
+
+    /// send a msg into the first stage, waiting for as long as it is backed up
+    pub fn send(&self, msg: (String, usize)) -> bool {
+        let Some(input) = &self.input else { return false };
+        event::emit(Event::MessageSent { msg: msg.clone() });
+        input.send(msg).is_ok()
+    }
+
+    /// send a msg into the first stage, giving up if it is still backed up after the timeout
+    pub fn send_timeout(&self, msg: (String, usize), timeout: Duration) -> bool {
+        let Some(input) = &self.input else { return false };
+        event::emit(Event::MessageSent { msg: msg.clone() });
+        match clock::send_timeout(&*self.clock, input, msg, timeout) {
+            Ok(_) => true,
+            Err(error) => {
+                let reason = error.to_string();
+                event::emit(Event::SendFailed { msg: error.into_inner(), error: reason });
+                false
+            },
+        }
+    }
+
+    /// msgs taken by each stage's workers, in stage order
+    pub fn counts(&self) -> Vec<(String, u32)> {
+        self.stages.iter().map(|s| (s.nm.clone(), s.workers.iter().map(|w| w.get_cnt()).sum())).collect()
+    }
+
+    /// stop taking msgs and wait for every stage to drain, first to last
+    pub fn shutdown(mut self) {
+        self.drain();
+    }
+
+    fn drain(&mut self) {
+        self.input = None;
+        for stage in self.stages.drain(..) {
+            for w in stage.workers {
+                w.join();
+            }
+        }
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        self.drain();
+    }
+}
+
+#[test]
+fn test_pipeline_flows() {
+    use std::sync::Mutex;
+
+    let out = Arc::new(Mutex::new(Vec::new()));
+    let out_ = out.clone();
+    let pipeline = Pipeline::builder()
+        .stage("double", 2, 4, |msg| Ok(vec![(msg.0.clone(), msg.1 * 2)]))
+        .stage("split", 3, 4, |msg| Ok(if msg.1 % 4 == 0 { vec![msg.clone(), msg.clone()] } else { Vec::new() }))
+        .stage("collect", 1, 4, move |msg| {
+            // slow last stage so the others back up behind it
+            std::thread::sleep(Duration::from_millis(5));
+            out_.lock().unwrap().push(msg.1);
+            Ok(Vec::new())
+        })
+        .build();
+    for i in 1..=10 {
+        assert!(pipeline.send(("this is the send message".to_string(), i)));
+    }
+    pipeline.shutdown();
+    // everything sent made it through before shutdown returned
+    let mut out = out.lock().unwrap().clone();
+    out.sort();
+    assert_eq!(out, vec![4, 4, 8, 8, 12, 12, 16, 16, 20, 20]);
+}
+
+#[test]
+fn test_backpressure() {
+    let (gate, wait) = crossbeam_channel::unbounded::<()>();
+    let pipeline = Pipeline::builder()
+        .stage("fast", 1, 1, |msg| Ok(vec![msg.clone()]))
+        .stage("stuck", 1, 1, move |_| {
+            let _ = wait.recv();
+            Ok(Vec::new())
+        })
+        .build();
+    // one msg held by each worker and one in each queue
+    let sent: Vec<bool> = (1..=4).map(|i| pipeline.send_timeout(("this is the send message".to_string(), i), Duration::from_secs(1))).collect();
+    let backed_up = !pipeline.send_timeout(("this is the send message".to_string(), 5), Duration::from_millis(100));
+    let counts = pipeline.counts();
+    drop(gate);
+    assert_eq!(sent, vec![true; 4]);
+    assert!(backed_up);
+    assert_eq!(counts, vec![("fast".to_string(), 3), ("stuck".to_string(), 1)]);
+    pipeline.shutdown();
+}
+
+#[test]
+fn test_failed_stage_drops_msg() {
+    use std::sync::Mutex;
+
+    let out = Arc::new(Mutex::new(Vec::new()));
+    let out_ = out.clone();
+    let pipeline = Pipeline::builder()
+        .stage("check", 1, 4, |msg| if msg.1 == 2 { Err("bad msg".to_string()) } else { Ok(vec![msg.clone()]) })
+        .stage("collect", 1, 4, move |msg| {
+            out_.lock().unwrap().push(msg.1);
+            Ok(Vec::new())
+        })
+        .build();
+    for i in 1..=3 {
+        assert!(pipeline.send(("this is the send message".to_string(), i)));
+    }
+    pipeline.shutdown();
+    assert_eq!(*out.lock().unwrap(), vec![1, 3]);
+}
@@ -0,0 +1,113 @@
+use std::{fs::File, io::{self, Read}, path::Path};
+
+/*
+ * Framing for records written to on-disk logs
+ * Record: payload len u32, checksum u32, payload
+ * Text fields: len u32 then utf8 bytes
+ */
+
+/// Largest record accepted off a stream, guards against allocating for a garbled length
+const MAX_FRAME: u32 = 16 * 1024 * 1024;
+
+fn checksum(bytes: &[u8]) -> u32 {
+    // FNV-1a
+    bytes.iter().fold(0x811c9dc5, |h, b| (h ^ *b as u32).wrapping_mul(0x01000193))
+}
+
+pub(crate) fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend((bytes.len() as u32).to_le_bytes());
+    buf.extend(bytes);
+}
+
+pub(crate) fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_bytes(buf, s.as_bytes());
+}
+
+/// wrap a payload with its length and checksum
+pub(crate) fn frame(payload: Vec<u8>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(payload.len() + 8);
+    buf.extend((payload.len() as u32).to_le_bytes());
+    buf.extend(checksum(&payload).to_le_bytes());
+    buf.extend(payload);
+    buf
+}
+
+/// payloads of every whole record in the bytes along with the length they take up
+/// stops at a record torn by a crash mid-write
+pub(crate) fn read_frames(bytes: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut r = Reader(bytes);
+    let mut frames = Vec::new();
+    let mut valid = 0;
+    while let (Some(len), Some(sum)) = (r.u32(), r.u32()) {
+        let Some(payload) = r.take(len as usize) else { break };
+        if checksum(payload) != sum {
+            break;
+        }
+        frames.push(payload);
+        valid = bytes.len() - r.0.len();
+    }
+    (frames, valid)
+}
+
+/// read one record off a stream, the payload is checked against its checksum
+pub(crate) fn read_frame(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut header = [0; 8];
+    r.read_exact(&mut header)?;
+    let mut h = Reader(&header);
+    let (len, sum) = (h.u32().unwrap(), h.u32().unwrap());
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "record too large"));
+    }
+    let mut payload = vec![0; len as usize];
+    r.read_exact(&mut payload)?;
+    if checksum(&payload) != sum {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "record checksum mismatch"));
+    }
+    Ok(payload)
+}
+
+/// make file creation and deletion in the directory durable, not supported everywhere
+pub(crate) fn sync_dir(dir: &Path) {
+    if let Ok(d) = File::open(dir) {
+        let _ = d.sync_all();
+    }
+}
+
+/// Reads fields off a record payload, None once it runs out
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> { Some(self.take(1)?[0]) }
+    pub(crate) fn u16(&mut self) -> Option<u16> { Some(u16::from_le_bytes(self.take(2)?.try_into().ok()?)) }
+    pub(crate) fn u32(&mut self) -> Option<u32> { Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?)) }
+    pub(crate) fn u64(&mut self) -> Option<u64> { Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?)) }
+
+    pub(crate) fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub(crate) fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?.to_vec()).ok()
+    }
+}
+
+#[test]
+fn test_torn_frame() {
+    let mut bytes = frame(vec![1, 2, 3]);
+    bytes.extend(frame(vec![4]));
+    let whole = bytes.len();
+    bytes.extend(&frame(vec![5, 6])[..7]);
+    let (frames, valid) = read_frames(&bytes);
+    assert_eq!(frames, vec![&[1, 2, 3][..], &[4][..]]);
+    assert_eq!(valid, whole);
+}
@@ -0,0 +1,645 @@
+use std::{collections::hash_map::DefaultHasher, hash::{Hash, Hasher}, sync::{Arc, atomic::{AtomicUsize, Ordering}}, time::Duration};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Select, SendTimeoutError, Sender, TryRecvError};
+
+use crate::{clock::{self, Clock, Timer}, event::{self, Event}, watchdog::Health};
+
+mod scoped;
+pub(crate) mod sync;
+mod thread;
+
+use sync::{AtomicBool, JoinHandle, Mutex, RwLock};
+
+pub use scoped::{ScopedPool, scoped};
+pub use thread::ThreadOptions;
+
+/*
+ * Every worker runs on the same runtime: a thread taking items from an Inbox and processing
+ * them until it is stopped or the inbox is closed, with the counter, stop signal and health
+ * kept in a WorkerState. Worker types only differ in their inbox and what they do with an item.
+ * Which workers a msg reaches is up to a Dispatch strategy, which hands each new worker its
+ * inbox and routes every msg sent:
+ *   FanOut       every worker gets every msg, each has its own queue
+ *   SharedQueue  one queue, whichever worker is free takes the msg
+ *   RoundRobin   each worker has its own queue and they get msgs in turn
+ *   Partitioned  each worker has its own queue and all msgs with the same text go to one worker
+ * A Pool runs workers with any strategy, so a new one only needs a Dispatch impl, and a
+ * ScopedPool does the same for workers whose handlers borrow from the caller. WorkerManager
+ * fans out with FanOut, and MessageBus has strategies of its own for its prioritised queues.
+ * Partitioned picks a msg's worker by rendezvous hashing: the one whose id hashed with the key
+ * scores highest. Only the keys of a worker that joins or leaves change hands, and there is
+ * no ring to keep in step with the members.
+ */
+
+/// How long a worker waits for a msg before checking whether it was stopped or its inbox closed
+const POLL: Duration = Duration::from_millis(500);
+
+/// How often a wait checks whether it was paused, resumed or given up on, in real time
+const CHECK: Duration = Duration::from_millis(10);
+
+/// Stops msgs being taken by a worker, or from a bus, while set
+/// every take holds it for reading so once `pause` returns nothing more is taken until `resume`
+#[derive(Clone, Default)]
+pub(crate) struct Pause(Arc<RwLock<bool>>);
+
+impl Pause {
+    pub(crate) fn pause(&self) {
+        *self.0.write().unwrap() = true;
+    }
+
+    pub(crate) fn resume(&self) {
+        *self.0.write().unwrap() = false;
+    }
+
+    pub(crate) fn is_paused(&self) -> bool { *self.0.read().unwrap() }
+}
+
+/// run `take` unless one of the gates is paused
+pub(crate) fn take_through<T>(gates: &[&Pause], take: impl FnOnce() -> T) -> Option<T> {
+    let held: Vec<_> = gates.iter().map(|g| g.0.read().unwrap()).collect();
+    if held.iter().any(|paused| **paused) {
+        return None;
+    }
+    Some(take())
+}
+
+/// wait a short while for one of the select's channels or the timer to be ready,
+/// or sleep if paused as nothing can be taken anyway, returns true once the timer has fired
+pub(crate) fn wait_ready<'a>(mut sel: Select<'a>, timer: &'a Timer, paused: bool) -> bool {
+    if paused {
+        std::thread::sleep(CHECK);
+        return timer.rx().try_recv().is_ok();
+    }
+    let t = sel.recv(timer.rx());
+    matches!(sel.ready_timeout(CHECK), Ok(i) if i == t)
+}
+
+/// take a msg off the channel once there is one and none of the gates are paused,
+/// waiting until the timeout passes on the clock, `give_up` ends the wait early when true
+pub(crate) fn recv_gated<T>(clock: &dyn Clock, rx: &Receiver<T>, timeout: Duration, gates: &[&Pause], give_up: impl Fn() -> bool) -> Result<T, RecvTimeoutError> {
+    let timer = clock.after(timeout);
+    loop {
+        match take_through(gates, || rx.try_recv()) {
+            Some(Ok(msg)) => return Ok(msg),
+            Some(Err(TryRecvError::Disconnected)) => return Err(RecvTimeoutError::Disconnected),
+            _ => {},
+        }
+        if give_up() {
+            return Err(RecvTimeoutError::Timeout);
+        }
+        let mut sel = Select::new();
+        sel.recv(rx);
+        if wait_ready(sel, &timer, gates.iter().any(|g| g.is_paused())) {
+            return Err(RecvTimeoutError::Timeout);
+        }
+    }
+}
+
+/// A worker's name, counter, stop signal and health, shared by the worker and its thread
+#[derive(Clone)]
+pub struct WorkerState {
+    pub(crate) nm: String,
+    pub(crate) rec_cnt: Arc<Mutex<u32>>, // counter of messages received
+    pub(crate) interrupt: Arc<AtomicBool>, // signal to thread to exit
+    pub(crate) health: Arc<Health>, // how long the worker has been on its current msg
+    pub(crate) pause: Pause, // stops the worker taking msgs
+    pub(crate) thread: ThreadOptions, // for the worker's thread, only read when it is started
+}
+
+impl WorkerState {
+    pub(crate) fn new(health: Arc<Health>) -> Self {
+        Self { nm: health.nm().to_string(), rec_cnt: Arc::default(), interrupt: Arc::default(), health, pause: Pause::default(), thread: ThreadOptions::default() }
+    }
+
+    pub fn nm(&self) -> &str { &self.nm }
+
+    /// the worker was told to stop
+    pub fn stopped(&self) -> bool {
+        self.interrupt.load(Ordering::SeqCst)
+    }
+
+    /// the worker took a msg, report and count it and start timing it
+    pub fn took(&self, msg: &(String, usize)) {
+        event::emit(Event::MessageReceived { worker: self.nm.clone(), msg: msg.clone() });
+        self.took_job();
+    }
+
+    /// the worker took something other than a msg to work on, count it and start timing it
+    pub fn took_job(&self) {
+        {
+            // increment rec counter
+            let mut n = self.rec_cnt.lock().unwrap();
+            *n += 1;
+        }
+        self.health.start();
+    }
+}
+
+/// Where a worker takes its msgs from
+pub trait Inbox: Send + 'static {
+    type Item: Send;
+    /// take the next item, None if nothing came within the timeout or the worker was stopped
+    /// calls `worker.took` for every msg taken
+    fn recv(&mut self, worker: &WorkerState, timeout: Duration) -> Option<Self::Item>;
+    /// nothing more will arrive, the worker exits instead of waiting for more
+    fn closed(&self) -> bool { false }
+}
+
+/// run a worker's body on its thread, between reporting it started and stopped
+fn lifetime<F: FnOnce(&WorkerState)>(state: &WorkerState, body: F) {
+    event::emit(Event::WorkerStarted { worker: state.nm.clone() });
+    body(state);
+    event::emit(Event::WorkerStopped { worker: state.nm.clone() });
+}
+
+/// the loop every worker runs, processing what it takes from the inbox
+fn work<I, F>(state: &WorkerState, mut inbox: I, mut process: F)
+where I: Inbox, F: FnMut(&WorkerState, &mut I, I::Item) {
+    loop {
+        if state.stopped() {
+            // stopped while working, leave the rest of the queue alone
+            break;
+        }
+        if inbox.closed() {
+            break;
+        }
+        // on a timeout loop round to check again
+        if let Some(item) = inbox.recv(state, POLL) {
+            process(state, &mut inbox, item);
+            state.health.done();
+        }
+    }
+}
+
+/// A worker thread along with its state
+pub(crate) struct Runtime {
+    state: WorkerState,
+    handle: JoinHandle<()>, // worker thread handle
+}
+
+impl Runtime {
+    /// start a worker thread running `body`, which should return soon after the worker is stopped
+    pub(crate) fn spawn<F>(state: WorkerState, body: F) -> Self
+    where F: FnOnce(&WorkerState) + Send + 'static {
+        let state_ = state.clone();
+        let handle = state.thread.spawn(&state.nm, move || lifetime(&state_, body));
+        Self { state, handle }
+    }
+
+    /// start a worker thread that processes every item it takes from the inbox
+    /// until it is stopped or the inbox is closed and empty
+    pub(crate) fn run<I, F>(state: WorkerState, inbox: I, process: F) -> Self
+    where I: Inbox, F: FnMut(&WorkerState, &mut I, I::Item) + Send + 'static {
+        Self::spawn(state, move |state| work(state, inbox, process))
+    }
+
+    pub(crate) fn state(&self) -> &WorkerState { &self.state }
+
+    /// get the number of msgs recvd by worker
+    pub(crate) fn get_cnt(&self) -> u32 { *self.state.rec_cnt.lock().unwrap() }
+
+    /// tell the thread to exit without waiting for it
+    pub(crate) fn interrupt(&self) {
+        self.state.interrupt.store(true, Ordering::SeqCst);
+    }
+
+    #[cfg(not(all(test, loom)))]
+    pub(crate) fn is_finished(&self) -> bool { self.handle.is_finished() }
+
+    /// loom's handles can't tell, nothing under loom asks
+    #[cfg(all(test, loom))]
+    pub(crate) fn is_finished(&self) -> bool { false }
+
+    /// true when called from the worker's own thread, which can't wait for itself to exit
+    #[cfg(not(all(test, loom)))]
+    pub(crate) fn is_current(&self) -> bool { self.handle.thread().id() == std::thread::current().id() }
+
+    #[cfg(all(test, loom))]
+    pub(crate) fn is_current(&self) -> bool { false }
+
+    /// stop taking msgs until resumed, the msg being worked on is finished
+    pub(crate) fn pause(&self) {
+        self.state.pause.pause();
+    }
+
+    pub(crate) fn resume(&self) {
+        self.state.pause.resume();
+    }
+
+    pub(crate) fn is_paused(&self) -> bool { self.state.pause.is_paused() }
+
+    /// wait for the thread to exit
+    pub(crate) fn join(self) {
+        self.handle.join().expect("Failed to join thread");
+    }
+
+    /// signal the thread to stop and wait until it does
+    pub(crate) fn stop(self) {
+        self.interrupt();
+        self.join();
+    }
+}
+
+/// Inbox fed by a channel, of the worker's own or shared with others
+/// closed once every sender is dropped and the channel is empty
+pub struct ChannelInbox {
+    rx: Receiver<(String, usize)>,
+    clock: Arc<dyn Clock>, // times out waits
+    disconnected: bool, // every sender is gone and nothing is left
+}
+
+impl ChannelInbox {
+    pub fn new(rx: Receiver<(String, usize)>, clock: Arc<dyn Clock>) -> Self {
+        Self { rx, clock, disconnected: false }
+    }
+}
+
+impl Inbox for ChannelInbox {
+    type Item = (String, usize);
+
+    fn recv(&mut self, worker: &WorkerState, timeout: Duration) -> Option<(String, usize)> {
+        match recv_gated(&*self.clock, &self.rx, timeout, &[&worker.pause], || worker.stopped()) {
+            Ok(msg) => {
+                worker.took(&msg);
+                Some(msg)
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                self.disconnected = true;
+                None
+            },
+            Err(RecvTimeoutError::Timeout) => None,
+        }
+    }
+
+    fn closed(&self) -> bool { self.disconnected }
+}
+
+/// How msgs sent to a group of workers are handed out to them
+pub trait Dispatch: Send + Sync + 'static {
+    /// what is sent to the workers
+    type Msg;
+    /// what a worker gets on joining, at least where it takes its msgs from
+    type Inbox;
+    /// make room for a new worker, the strategies here let it go once its inbox is dropped
+    fn join(&self) -> Self::Inbox;
+    /// hand a msg to the workers that should get it, false if it didn't reach them all
+    fn dispatch(&self, msg: Self::Msg) -> bool;
+}
+
+/// report a msg that couldn't be dispatched
+fn failed(msg: (String, usize), error: &str) -> bool {
+    event::emit(Event::SendFailed { msg, error: error.to_string() });
+    std::thread::yield_now(); // free up thread to give workers a chance to catchup
+    false
+}
+
+/// A worker's id and its queue
+type Member = (u64, Sender<(String, usize)>);
+
+/// Queues of the workers for strategies that pick which workers get a msg
+struct Members {
+    capacity: usize,
+    clock: Arc<dyn Clock>, // times out sends
+    queues: Mutex<(u64, Vec<Member>)>, // next id and the members
+}
+
+impl Members {
+    fn new(capacity: usize, clock: Arc<dyn Clock>) -> Self {
+        Self { capacity, clock, queues: Mutex::default() }
+    }
+
+    fn join(&self) -> ChannelInbox {
+        let (t, r) = crossbeam_channel::bounded(self.capacity);
+        self.adopt(t);
+        ChannelInbox::new(r, self.clock.clone())
+    }
+
+    fn adopt(&self, tx: Sender<(String, usize)>) {
+        let mut queues = self.queues.lock().unwrap();
+        let id = queues.0;
+        queues.0 += 1;
+        queues.1.push((id, tx));
+    }
+
+    fn current(&self) -> Vec<Member> {
+        self.queues.lock().unwrap().1.clone()
+    }
+
+    /// put the msg on the worker's queue, a worker that is gone is dropped from the members
+    fn send(&self, (id, tx): &Member, msg: (String, usize)) -> Result<(), SendTimeoutError<(String, usize)>> {
+        let result = clock::send_timeout(&*self.clock, tx, msg, Duration::from_millis(100));
+        if let Err(SendTimeoutError::Disconnected(_)) = result {
+            self.queues.lock().unwrap().1.retain(|(i, _)| i != id);
+        }
+        result
+    }
+
+    /// send to the member picked from the current ones, picking again if it turns out to be gone
+    fn send_to_one(&self, mut msg: (String, usize), pick: impl Fn(&[Member], &(String, usize)) -> usize) -> bool {
+        event::emit(Event::MessageSent { msg: msg.clone() });
+        loop {
+            let members = self.current();
+            if members.is_empty() {
+                return failed(msg, "no workers");
+            }
+            match self.send(&members[pick(&members, &msg)], msg) {
+                Ok(_) => return true,
+                Err(SendTimeoutError::Disconnected(m)) => msg = m,
+                Err(error) => {
+                    let reason = error.to_string();
+                    return failed(error.into_inner(), &reason);
+                },
+            }
+        }
+    }
+}
+
+/// Every worker gets every msg
+pub struct FanOut(Members);
+
+impl FanOut {
+    /// each worker gets its own queue of `capacity` msgs
+    pub fn new(capacity: usize) -> Self {
+        Self::with_clock(capacity, clock::real())
+    }
+
+    /// time out sends by the clock
+    pub fn with_clock(capacity: usize, clock: Arc<dyn Clock>) -> Self {
+        Self(Members::new(capacity, clock))
+    }
+
+    /// add a worker whose queue was made elsewhere, it leaves once the queue's receiver is dropped
+    pub(crate) fn adopt(&self, tx: Sender<(String, usize)>) {
+        self.0.adopt(tx);
+    }
+}
+
+impl Dispatch for FanOut {
+    type Msg = (String, usize);
+    type Inbox = ChannelInbox;
+
+    fn join(&self) -> ChannelInbox { self.0.join() }
+
+    fn dispatch(&self, msg: (String, usize)) -> bool {
+        event::emit(Event::MessageSent { msg: msg.clone() });
+        let mut result = true;
+        for member in self.0.current() {
+            match self.0.send(&member, msg.clone()) {
+                // a worker that is gone doesn't need the msg
+                Ok(_) | Err(SendTimeoutError::Disconnected(_)) => {},
+                Err(error) => {
+                    let reason = error.to_string();
+                    result = failed(error.into_inner(), &reason);
+                },
+            }
+        }
+        result
+    }
+}
+
+/// One queue for all the workers, each msg goes to whichever is free first
+pub struct SharedQueue {
+    tx: Sender<(String, usize)>,
+    rx: Receiver<(String, usize)>,
+    clock: Arc<dyn Clock>, // times out sends
+}
+
+impl SharedQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self::with_clock(capacity, clock::real())
+    }
+
+    /// time out sends by the clock
+    pub fn with_clock(capacity: usize, clock: Arc<dyn Clock>) -> Self {
+        let (tx, rx) = crossbeam_channel::bounded(capacity);
+        Self { tx, rx, clock }
+    }
+}
+
+impl Dispatch for SharedQueue {
+    type Msg = (String, usize);
+    type Inbox = ChannelInbox;
+
+    fn join(&self) -> ChannelInbox { ChannelInbox::new(self.rx.clone(), self.clock.clone()) }
+
+    fn dispatch(&self, msg: (String, usize)) -> bool {
+        event::emit(Event::MessageSent { msg: msg.clone() });
+        match clock::send_timeout(&*self.clock, &self.tx, msg, Duration::from_millis(100)) {
+            Ok(_) => true,
+            Err(error) => {
+                let reason = error.to_string();
+                failed(error.into_inner(), &reason)
+            },
+        }
+    }
+}
+
+/// Workers get msgs in turn, whether or not they are busy
+pub struct RoundRobin {
+    members: Members,
+    turn: AtomicUsize, // count of msgs dispatched, picks the next worker
+}
+
+impl RoundRobin {
+    /// each worker gets its own queue of `capacity` msgs
+    pub fn new(capacity: usize) -> Self {
+        Self::with_clock(capacity, clock::real())
+    }
+
+    /// time out sends by the clock
+    pub fn with_clock(capacity: usize, clock: Arc<dyn Clock>) -> Self {
+        Self { members: Members::new(capacity, clock), turn: AtomicUsize::new(0) }
+    }
+}
+
+impl Dispatch for RoundRobin {
+    type Msg = (String, usize);
+    type Inbox = ChannelInbox;
+
+    fn join(&self) -> ChannelInbox { self.members.join() }
+
+    fn dispatch(&self, msg: (String, usize)) -> bool {
+        self.members.send_to_one(msg, |members, _| self.turn.fetch_add(1, Ordering::SeqCst) % members.len())
+    }
+}
+
+/// All msgs with the same text go to the same worker while the workers stay the same
+pub struct Partitioned(Members);
+
+impl Partitioned {
+    /// each worker gets its own queue of `capacity` msgs
+    pub fn new(capacity: usize) -> Self {
+        Self::with_clock(capacity, clock::real())
+    }
+
+    /// time out sends by the clock
+    pub fn with_clock(capacity: usize, clock: Arc<dyn Clock>) -> Self {
+        Self(Members::new(capacity, clock))
+    }
+}
+
+impl Dispatch for Partitioned {
+    type Msg = (String, usize);
+    type Inbox = ChannelInbox;
+
+    fn join(&self) -> ChannelInbox { self.0.join() }
+
+    fn dispatch(&self, msg: (String, usize)) -> bool {
+        self.0.send_to_one(msg, |members, msg| {
+            let score = |id: u64| {
+                let mut h = DefaultHasher::new();
+                (&msg.0, id).hash(&mut h);
+                h.finish()
+            };
+            (0..members.len()).max_by_key(|i| score(members[*i].0)).unwrap()
+        })
+    }
+}
+
+/// Workers sharing msgs sent to them through a dispatch strategy
+pub struct Pool<D: Dispatch> {
+    dispatch: D,
+    workers: Vec<Runtime>,
+    thread: ThreadOptions, // for workers added from now on
+}
+
+impl<D: Dispatch> Pool<D> {
+    pub fn new(dispatch: D) -> Self {
+        Self { dispatch, workers: Vec::new(), thread: ThreadOptions::default() }
+    }
+
+    /// set the thread options of workers added from now on
+    pub fn set_thread_options(&mut self, thread: ThreadOptions) {
+        self.thread = thread;
+    }
+
+    /// start a worker that runs the handler on every msg the strategy hands it
+    pub fn add<F>(&mut self, nm: String, mut handler: F)
+    where D::Inbox: Inbox<Item = (String, usize)>, F: FnMut(&(String, usize)) + Send + 'static {
+        // the handler can't be cancelled
+        let mut state = WorkerState::new(Health::new(&nm, || {}));
+        state.thread = self.thread.clone();
+        self.workers.push(Runtime::run(state, self.dispatch.join(), move |_, _, msg| handler(&msg)));
+    }
+
+    pub fn send(&self, msg: D::Msg) -> bool {
+        self.dispatch.dispatch(msg)
+    }
+
+    /// msgs taken by each worker, in the order they were added
+    pub fn counts(&self) -> Vec<u32> {
+        self.workers.iter().map(|w| w.get_cnt()).collect()
+    }
+
+    /// health of each worker, in the order they were added
+    pub fn health(&self) -> Vec<Arc<Health>> {
+        self.workers.iter().map(|w| w.state().health.clone()).collect()
+    }
+
+    /// stop the worker added at `index`, msgs still on its own queue are dropped with it
+    pub fn remove(&mut self, index: usize) {
+        self.workers.remove(index).stop();
+    }
+}
+
+impl<D: Dispatch> Drop for Pool<D> {
+    fn drop(&mut self) {
+        for w in self.workers.drain(..) {
+            w.stop();
+        }
+    }
+}
+
+/// (worker no, seq) recorded by test handlers
+#[cfg(test)]
+type Seen = Arc<Mutex<Vec<(usize, usize)>>>;
+
+#[cfg(test)]
+fn recording_pool<D>(dispatch: D, workers: usize) -> (Pool<D>, Seen)
+where D: Dispatch<Msg = (String, usize), Inbox = ChannelInbox> {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let mut pool = Pool::new(dispatch);
+    for w in 0..workers {
+        let seen = seen.clone();
+        pool.add(format!("Worker {w}"), move |msg| seen.lock().unwrap().push((w, msg.1)));
+    }
+    (pool, seen)
+}
+
+#[cfg(test)]
+fn wait_for(seen: &Mutex<Vec<(usize, usize)>>, n: usize) -> Vec<(usize, usize)> {
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while seen.lock().unwrap().len() < n && std::time::Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let mut seen = seen.lock().unwrap().clone();
+    seen.sort();
+    seen
+}
+
+#[test]
+fn test_fan_out() {
+    let (pool, seen) = recording_pool(FanOut::new(4), 3);
+    for i in 1..=3 {
+        assert!(pool.send(("this is the msg".to_string(), i)));
+    }
+    let seen = wait_for(&seen, 9);
+    assert_eq!(seen, (0..3).flat_map(|w| (1..=3).map(move |i| (w, i))).collect::<Vec<_>>());
+    assert_eq!(pool.counts(), vec![3, 3, 3]);
+}
+
+#[test]
+fn test_shared_queue() {
+    let (pool, seen) = recording_pool(SharedQueue::new(10), 3);
+    for i in 1..=10 {
+        assert!(pool.send(("this is the msg".to_string(), i)));
+    }
+    let mut msgs: Vec<usize> = wait_for(&seen, 10).into_iter().map(|(_, i)| i).collect();
+    msgs.sort();
+    // each msg reaches a single worker
+    assert_eq!(msgs, (1..=10).collect::<Vec<_>>());
+    assert_eq!(pool.counts().iter().sum::<u32>(), 10);
+}
+
+#[test]
+fn test_round_robin() {
+    let (pool, seen) = recording_pool(RoundRobin::new(4), 3);
+    for i in 0..6 {
+        assert!(pool.send(("this is the msg".to_string(), i)));
+    }
+    let seen = wait_for(&seen, 6);
+    assert!(seen.iter().all(|(w, i)| i % 3 == *w));
+    assert_eq!(pool.counts(), vec![2, 2, 2]);
+}
+
+#[test]
+fn test_partitioned() {
+    let (mut pool, seen) = recording_pool(Partitioned::new(10), 3);
+    let owners = |seen: &[(usize, usize)]| {
+        let mut owners = std::collections::BTreeMap::new();
+        for (w, i) in seen {
+            owners.entry(i % 5).or_insert_with(std::collections::BTreeSet::new).insert(*w);
+        }
+        owners
+    };
+    for i in 0..20 {
+        assert!(pool.send((format!("key {}", i % 5), i)));
+    }
+    let before = owners(&wait_for(&seen, 20));
+    // each key stays with one worker
+    assert!(before.values().all(|ws| ws.len() == 1));
+
+    // only the keys of a worker that leaves move
+    pool.remove(0);
+    seen.lock().unwrap().clear();
+    for i in 0..20 {
+        assert!(pool.send((format!("key {}", i % 5), i)));
+    }
+    let after = owners(&wait_for(&seen, 20));
+    for (key, ws) in after {
+        assert_eq!(ws.len(), 1);
+        if !before[&key].contains(&0) {
+            assert_eq!(ws, before[&key]);
+        }
+    }
+}
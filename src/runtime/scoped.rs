@@ -0,0 +1,125 @@
+use std::{sync::{Arc, atomic::Ordering}, thread::{Scope, ScopedJoinHandle}};
+
+use crate::watchdog::Health;
+
+use super::{Dispatch, Inbox, ThreadOptions, WorkerState, lifetime, work};
+
+/*
+ * A Pool's handlers must be 'static since its threads can outlive the caller, so anything they
+ * share with it has to be put behind an Arc. A ScopedPool only lives for a `scoped` call, whose
+ * std::thread::scope joins every worker before returning, so its handlers can borrow from the
+ * caller's stack. Workers are stopped once the closure given to `scoped` returns or panics.
+ */
+
+/// run the closure with a pool whose handlers can borrow anything that outlives the call
+/// every worker is stopped and joined before this returns, msgs still queued are dropped
+pub fn scoped<'env, D, F, R>(dispatch: D, f: F) -> R
+where D: Dispatch, F: for<'scope> FnOnce(&mut ScopedPool<'scope, 'env, D>) -> R {
+    std::thread::scope(|scope| {
+        // dropped on the way out, even on a panic, which stops the workers for the scope to join
+        let mut pool = ScopedPool { scope, dispatch, workers: Vec::new(), thread: ThreadOptions::default() };
+        f(&mut pool)
+    })
+}
+
+/// Workers sharing msgs sent to them through a dispatch strategy, joined by the end of `scoped`
+pub struct ScopedPool<'scope, 'env: 'scope, D: Dispatch> {
+    scope: &'scope Scope<'scope, 'env>,
+    dispatch: D,
+    workers: Vec<(WorkerState, ScopedJoinHandle<'scope, ()>)>,
+    thread: ThreadOptions, // for workers added from now on
+}
+
+impl<'scope, D: Dispatch> ScopedPool<'scope, '_, D> {
+    /// set the thread options of workers added from now on
+    pub fn set_thread_options(&mut self, thread: ThreadOptions) {
+        self.thread = thread;
+    }
+
+    /// start a worker that runs the handler on every msg the strategy hands it
+    pub fn add<F>(&mut self, nm: String, mut handler: F)
+    where D::Inbox: Inbox<Item = (String, usize)>, F: FnMut(&(String, usize)) + Send + 'scope {
+        // the handler can't be cancelled
+        let mut state = WorkerState::new(Health::new(&nm, || {}));
+        state.thread = self.thread.clone();
+        let (state_, inbox) = (state.clone(), self.dispatch.join());
+        let handle = self.thread.spawn_scoped(self.scope, &nm, move || {
+            lifetime(&state_, |state| work(state, inbox, move |_, _, msg| handler(&msg)));
+        });
+        self.workers.push((state, handle));
+    }
+
+    pub fn send(&self, msg: D::Msg) -> bool {
+        self.dispatch.dispatch(msg)
+    }
+
+    /// msgs taken by each worker, in the order they were added
+    pub fn counts(&self) -> Vec<u32> {
+        self.workers.iter().map(|(state, _)| *state.rec_cnt.lock().unwrap()).collect()
+    }
+
+    /// health of each worker, in the order they were added
+    pub fn health(&self) -> Vec<Arc<Health>> {
+        self.workers.iter().map(|(state, _)| state.health.clone()).collect()
+    }
+
+    /// stop the worker added at `index` and wait until it does, msgs still on its own queue are dropped with it
+    pub fn remove(&mut self, index: usize) {
+        let (state, handle) = self.workers.remove(index);
+        state.interrupt.store(true, Ordering::SeqCst);
+        let _ = handle.join();
+    }
+}
+
+impl<D: Dispatch> Drop for ScopedPool<'_, '_, D> {
+    fn drop(&mut self) {
+        // the scope joins them
+        for (state, _) in self.workers.iter() {
+            state.interrupt.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+#[test]
+fn test_scoped_borrows() {
+    use std::sync::Mutex;
+
+    use super::SharedQueue;
+
+    // plain locals on the caller's stack, no Arc needed
+    let seen = Mutex::new(Vec::new());
+    let offset = 100;
+    let counts = scoped(SharedQueue::new(10), |pool| {
+        for n in 1..=3 {
+            pool.add(format!("Worker {n}"), |msg| seen.lock().unwrap().push(msg.1 + offset));
+        }
+        for i in 1..=10 {
+            assert!(pool.send(("this is the msg".to_string(), i)));
+        }
+        while seen.lock().unwrap().len() < 10 {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        pool.counts()
+    });
+    // every worker has been joined, so the borrow is over
+    let mut seen = seen.into_inner().unwrap();
+    seen.sort();
+    assert_eq!(seen, (101..=110).collect::<Vec<_>>());
+    assert_eq!(counts.iter().sum::<u32>(), 10);
+}
+
+#[test]
+fn test_scoped_panic_joins() {
+    use super::FanOut;
+
+    let taken = std::sync::atomic::AtomicU32::new(0);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        scoped(FanOut::new(4), |pool| {
+            pool.add("Worker 1".to_string(), |_| { taken.fetch_add(1, Ordering::SeqCst); });
+            panic!("caller failed");
+        })
+    }));
+    // the worker was still stopped and joined, or this would never return
+    assert!(result.is_err());
+    assert_eq!(taken.load(Ordering::SeqCst), 0);
+}
@@ -0,0 +1,12 @@
+/*
+ * The locks, flags and thread handles the worker runtime is built on. Under --cfg loom they are
+ * loom's, so the model checks in loom_model.rs run the real worker loop and loom sees every
+ * lock taken and flag set by it. Flags shared with a worker's state, like the cancel flags a
+ * CancelToken holds next to its interrupt, have to be these too.
+ */
+
+#[cfg(not(all(test, loom)))]
+pub(crate) use std::{sync::{Mutex, RwLock, atomic::AtomicBool}, thread::JoinHandle};
+
+#[cfg(all(test, loom))]
+pub(crate) use loom::{sync::{Mutex, RwLock, atomic::AtomicBool}, thread::JoinHandle};
@@ -0,0 +1,156 @@
+use std::{io, sync::{Arc, Mutex}, thread::{Builder, Scope, ScopedJoinHandle}};
+
+use crate::event::{self, Event};
+
+use super::sync::JoinHandle;
+
+/// OS settings for a worker's thread, which is always named after the worker
+/// affinity and niceness are only applied on Linux
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ThreadOptions {
+    pub stack_size: Option<usize>, // in bytes, None for the std default
+    pub affinity: Vec<usize>, // cpus the thread may run on, empty for any
+    pub nice: Option<i32>, // lower runs sooner, going below the process' niceness needs privileges
+}
+
+impl ThreadOptions {
+    /// start a thread named `nm` with the options
+    #[cfg(not(all(test, loom)))]
+    pub(crate) fn spawn<F>(&self, nm: &str, f: F) -> JoinHandle<()>
+    where F: FnOnce() + Send + 'static {
+        self.spawn_with(nm, f, |builder, f| builder.spawn(f))
+    }
+
+    /// start a thread for loom to run, the options only apply to real threads
+    #[cfg(all(test, loom))]
+    pub(crate) fn spawn<F>(&self, nm: &str, f: F) -> JoinHandle<()>
+    where F: FnOnce() + Send + 'static {
+        loom::thread::Builder::new().name(nm.to_string()).spawn(f).expect("failed to spawn thread")
+    }
+
+    /// start a thread like `spawn` that is joined by the end of the scope
+    pub(crate) fn spawn_scoped<'scope, 'env, F>(&self, scope: &'scope Scope<'scope, 'env>, nm: &str, f: F) -> ScopedJoinHandle<'scope, ()>
+    where F: FnOnce() + Send + 'scope {
+        self.spawn_with(nm, f, |builder, f| builder.spawn_scoped(scope, f))
+    }
+
+    /// thread names can't hold a NUL so any in `nm` are replaced
+    /// if the options stop the thread being created that is reported and it starts without them,
+    /// panicking like `std::thread::spawn` only if it can't be created even then
+    fn spawn_with<'a, F, H>(&self, nm: &str, f: F, spawn: impl Fn(Builder, Box<dyn FnOnce() + Send + 'a>) -> io::Result<H>) -> H
+    where F: FnOnce() + Send + 'a {
+        let nm = nm.replace('\0', "\u{fffd}");
+        // kept out here so it isn't lost with a thread that failed to start
+        let slot = Arc::new(Mutex::new(Some(f)));
+        let body = |opts: Option<ThreadOptions>| -> Box<dyn FnOnce() + Send + 'a> {
+            let (slot, nm) = (slot.clone(), nm.clone());
+            Box::new(move || {
+                if let Some(opts) = opts {
+                    opts.start(&nm);
+                }
+                let f = slot.lock().unwrap().take();
+                if let Some(f) = f {
+                    f();
+                }
+            })
+        };
+        match spawn(self.builder(&nm), body(Some(self.clone()))) {
+            Ok(handle) => handle,
+            Err(error) => {
+                event::emit(Event::Error { source: format!("worker '{nm}'"), error: format!("thread options: {error}") });
+                spawn(Builder::new().name(nm.clone()), body(None)).expect("failed to spawn thread")
+            },
+        }
+    }
+
+    fn builder(&self, nm: &str) -> Builder {
+        let builder = Builder::new().name(nm.to_string());
+        match self.stack_size {
+            Some(size) => builder.stack_size(size),
+            None => builder,
+        }
+    }
+
+    /// apply the options on the new thread, those that can't be are reported and it runs without them
+    fn start(&self, nm: &str) {
+        if let Err(error) = self.apply() {
+            event::emit(Event::Error { source: format!("worker '{nm}'"), error: format!("thread options: {error}") });
+        }
+    }
+
+    /// set the affinity and niceness of the calling thread
+    #[cfg(target_os = "linux")]
+    fn apply(&self) -> io::Result<()> {
+        if !self.affinity.is_empty() {
+            if let Some(cpu) = self.affinity.iter().find(|cpu| **cpu >= libc::CPU_SETSIZE as usize) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("no cpu {cpu}")));
+            }
+            // SAFETY: the set is plain data, every cpu added is within it and it outlives the call
+            let r = unsafe {
+                let mut set: libc::cpu_set_t = std::mem::zeroed();
+                for cpu in self.affinity.iter() {
+                    libc::CPU_SET(*cpu, &mut set);
+                }
+                libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+            };
+            if r != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(nice) = self.nice {
+            // on Linux niceness is per thread, so this leaves the rest of the process alone
+            // SAFETY: only takes plain values
+            let r = unsafe { libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t, nice) };
+            if r != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn apply(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_thread_options() {
+    // a cpu the tests are allowed on, which needn't be cpu 0 in a container
+    // SAFETY: the set is plain data that outlives the call
+    let cpu = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set);
+        (0..libc::CPU_SETSIZE as usize).find(|cpu| libc::CPU_ISSET(*cpu, &set)).unwrap()
+    };
+    let opts = ThreadOptions { stack_size: Some(256 * 1024), affinity: vec![cpu], nice: Some(5) };
+    let (t, r) = crossbeam_channel::bounded(1);
+    opts.spawn("Worker 1", move || {
+        // SAFETY: the set is plain data that outlives the call
+        let cpus = unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set);
+            libc::CPU_COUNT(&set)
+        };
+        // SAFETY: only takes plain values
+        let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t) };
+        t.send((std::thread::current().name().map(String::from), cpus, nice)).unwrap();
+    }).join().unwrap();
+    let (nm, cpus, nice) = r.recv().unwrap();
+    assert_eq!(nm.as_deref(), Some("Worker 1"));
+    assert_eq!(cpus, 1);
+    // raising niceness needs no privileges, unless the tests already run above it
+    assert!(nice >= 5);
+}
+
+#[test]
+fn test_thread_fallback() {
+    // neither a NUL in the name nor a stack that can't be had stop the thread starting
+    let opts = ThreadOptions { stack_size: Some(usize::MAX), ..ThreadOptions::default() };
+    let (t, r) = crossbeam_channel::bounded(1);
+    opts.spawn("Worker\0 1", move || {
+        t.send(std::thread::current().name().map(String::from)).unwrap();
+    }).join().unwrap();
+    assert_eq!(r.recv().unwrap().as_deref(), Some("Worker\u{fffd} 1"));
+}
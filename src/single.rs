@@ -1,104 +1,485 @@
-use std::{sync::{Arc, Mutex, atomic::AtomicBool}, thread::JoinHandle, time::Duration};
+use std::{collections::VecDeque, sync::{Arc, Mutex, OnceLock, RwLock}, time::Duration};
 
-use crossbeam_channel::{Receiver, Sender};
+use crate::{clock::{self, Clock, ClockCell}, event::{self, Event}, runtime::{Dispatch, Inbox, Pause, Runtime, ThreadOptions, WorkerState, sync::AtomicBool, take_through}, watchdog::{Health, Watched}};
+
+mod batch;
+mod cancel;
+mod close;
+mod dag;
+mod durable;
+mod inputs;
+mod partition;
+mod priority;
+mod ratelimit;
+mod retry;
+mod schedule;
+mod tcp;
+#[cfg(unix)]
+mod unix;
+mod wire;
+
+pub use cancel::{CancelToken, JobId, JobOutcome};
+pub use dag::{GraphError, GraphSummary, JobGraph, JobStatus};
+pub use durable::FsyncPolicy;
+pub use inputs::Selection;
+pub use priority::{Priority, PriorityStats};
+pub use ratelimit::{OnLimit, Producer, RateLimit, RateLimitError, RateLimiter};
+pub use retry::{Backoff, DeadLetter, RetryPolicy};
+pub use schedule::ScheduleHandle;
+pub use tcp::{TcpProducer, TcpServer};
+#[cfg(unix)]
+pub use unix::{UnixProducer, UnixServer};
 
 /*
  * Single channel all works subscribe only 1 worker receives the message
  */
 
+/// Outcome of a handler processing a single message
+pub type HandlerResult = Result<(), String>;
+
+/// A msg on its way into the bus along with how it was sent
+struct Envelope {
+    id: JobId,
+    msg: (String, usize),
+    priority: Priority,
+    key: Option<String>, // picks the worker on a partitioned bus
+}
+
+/// Sends a msg into a bus, returns false if it couldn't be sent
+type Downstream = Arc<dyn Fn(Envelope) -> bool + Send + Sync>;
+
 /// Holds channel objects for the main thread to communicate with workers
 pub struct MessageBus {
-    tx: Sender<(String, usize)>,
-    rx: Receiver<(String, usize)>
+    router: Router, // queues msgs for the workers
+    retry: RetryPolicy, // applied by workers when a handler fails
+    dead_letters: Arc<Mutex<VecDeque<DeadLetter>>>, // msgs that failed every retry
+    limit: Option<Arc<ratelimit::BusLimit>>, // throttles sends into the bus
+    scheduler: OnceLock<schedule::Scheduler>, // sends delayed and periodic msgs
+    thread: ThreadOptions, // for workers that join from now on
 }
 
 impl MessageBus {
+    /// panics if the capacity is 0, every msg waits in a queue for a worker so there must be room for one
     pub fn new(capacity: u8) -> Self {
         // channels are a fixed size and will not queue msgs beyond capacity
-        let (t, r) = crossbeam_channel::bounded(capacity as usize);
-        Self {tx: t, rx: r}
+        Self::with_strategy(|jobs, clock| Arc::new(SharedLanes::new(capacity as usize, jobs, clock)), Arc::default())
+    }
+
+    /// create a bus that hands msgs to its workers through the strategy made from its jobs and clock
+    fn with_strategy(strategy: impl FnOnce(Arc<cancel::Jobs>, Arc<dyn Clock>) -> Arc<dyn Strategy>, jobs: Arc<cancel::Jobs>) -> Self {
+        let clock = ClockCell::new(clock::real());
+        let router = Router {
+            dispatch: strategy(jobs.clone(), clock.clone()),
+            jobs,
+            closed: Arc::default(),
+            clock,
+            pause: Pause::default(),
+        };
+        Self {
+            router,
+            retry: RetryPolicy::none(),
+            dead_letters: Arc::new(Mutex::new(VecDeque::new())),
+            limit: None,
+            scheduler: OnceLock::new(),
+            thread: ThreadOptions::default(),
+        }
     }
  
     pub fn send(&self, msg: (String, usize)) -> bool {
-        println!("  main        | sending msg # {} : {}", msg.1, msg.0);
-        let result = self.tx.send_timeout((msg.0, msg.1), Duration::from_millis(100));
-        let mut retval = true;
-        match result {
-            Ok(_) => {},
-            Err(error) => {
-                println!("Send error: {}", error);
-                retval = false;
-                std::thread::yield_now(); // free up thread to give workers a chance to catchup
+        self.send_with_priority(msg, Priority::Normal)
+    }
+
+    /// send a msg at the given priority, workers take higher priority msgs first
+    pub fn send_with_priority(&self, msg: (String, usize), priority: Priority) -> bool {
+        self.send_env(self.router.jobs.envelope(msg, priority, None))
+    }
+
+    fn send_env(&self, env: Envelope) -> bool {
+        match &self.limit {
+            Some(limit) => limit.send(env),
+            None => self.router.send(env),
+        }
+    }
+
+    /// send function that goes through the bus rate limit, for sending from other threads
+    fn downstream(&self) -> Downstream {
+        match &self.limit {
+            Some(bus_limit) => {
+                let bus_limit = bus_limit.clone();
+                Arc::new(move |env| bus_limit.send(env))
+            },
+            None => {
+                let router = self.router.clone();
+                Arc::new(move |env| router.send(env))
             }
         }
-        retval
     }
- 
-    fn get_recvr(&self) -> Receiver<(String, usize)> {
-        self.rx.clone()
+
+    /// time send timeouts, worker polling, retry backoff, the rate limit, the scheduler, wait_drained
+    /// and how long workers have been busy by the clock instead of real time
+    /// workers and limits already on the bus switch to it too, waits already started keep their timer
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.router.clock.set(clock);
+    }
+
+    /// set the thread options of workers that join the bus from now on
+    pub fn set_thread_options(&mut self, thread: ThreadOptions) {
+        self.thread = thread;
+    }
+
+    /// stop every worker taking msgs off the bus until resumed, sends are still queued up to capacity
+    pub fn pause(&self) {
+        self.router.pause.pause();
+    }
+
+    pub fn resume(&self) {
+        self.router.pause.resume();
+    }
+
+    pub fn is_paused(&self) -> bool { self.router.pause.is_paused() }
+
+    #[cfg(test)]
+    fn get_recvr(&self) -> priority::Lanes {
+        self.router.dispatch.queues().remove(0)
+    }
+}
+
+/// A worker's place on a bus, what it gets on joining
+struct Member {
+    id: u64, // picks the worker out to the strategy, for leaving
+    lanes: priority::Lanes, // where it takes its msgs from
+    handover: Vec<JobId>, // jobs it has to wait for before taking any msg
+}
+
+/// How a bus hands msgs to its workers, a worker stays until it leaves
+trait Strategy: Dispatch<Msg = Envelope, Inbox = Member> {
+    /// the worker is gone, anything queued for it alone goes to the others
+    fn leave(&self, id: u64);
+    /// every queue msgs are sent to
+    fn queues(&self) -> Vec<priority::Lanes>;
+    /// msgs each queue holds
+    fn capacity(&self) -> usize;
+}
+
+/// One set of lanes shared by all the workers, each msg goes to whichever is free first
+struct SharedLanes {
+    lanes: priority::Lanes,
+    jobs: Arc<cancel::Jobs>,
+    clock: Arc<dyn Clock>, // times out sends
+}
+
+impl SharedLanes {
+    fn new(capacity: usize, jobs: Arc<cancel::Jobs>, clock: Arc<dyn Clock>) -> Self {
+        Self { lanes: priority::Lanes::new(capacity), jobs, clock }
+    }
+}
+
+impl Dispatch for SharedLanes {
+    type Msg = Envelope;
+    type Inbox = Member;
+
+    fn join(&self) -> Member { Member { id: 0, lanes: self.lanes.clone(), handover: Vec::new() } }
+
+    fn dispatch(&self, env: Envelope) -> bool {
+        self.jobs.queued(&env, None) && queue(&self.jobs, &self.lanes, env, &*self.clock)
+    }
+}
+
+impl Strategy for SharedLanes {
+    fn leave(&self, _: u64) {}
+
+    fn queues(&self) -> Vec<priority::Lanes> { vec![self.lanes.clone()] }
+
+    fn capacity(&self) -> usize { self.lanes.capacity() }
+}
+
+/// Where msgs sent into a bus end up
+/// clones share everything, so one kept by a limiter or scheduler follows later changes to the bus
+#[derive(Clone)]
+struct Router {
+    dispatch: Arc<dyn Strategy>, // picks the queue each msg goes on
+    jobs: Arc<cancel::Jobs>, // every msg from sending until a worker is done with it
+    closed: Arc<RwLock<bool>>, // held for reading while sending so close waits for sends in flight
+    clock: Arc<ClockCell>, // times out sends, shared with everything on the bus
+    pause: Pause, // stops every worker taking msgs off the bus
+}
+
+impl Router {
+    fn send(&self, env: Envelope) -> bool {
+        let closed = self.closed.read().unwrap();
+        if *closed {
+            event::emit(Event::SendFailed { msg: env.msg, error: "bus is closed".to_string() });
+            return false;
+        }
+        self.dispatch.dispatch(env)
+    }
+
+    /// stats summed over every queue the router sends to
+    fn stats(&self, priority: Priority) -> PriorityStats {
+        let mut stats = PriorityStats::default();
+        for lanes in self.dispatch.queues() {
+            stats += lanes.stats(priority);
+        }
+        stats
+    }
+}
+
+/// put a recorded job on the queue, forgetting it if there's no room
+fn queue(jobs: &cancel::Jobs, lanes: &priority::Lanes, env: Envelope, clock: &dyn Clock) -> bool {
+    let id = env.id;
+    let sent = send_msg(lanes, env, clock);
+    if !sent {
+        jobs.not_queued(id);
+    }
+    sent
+}
+
+/// Put a msg on the channel, giving up if there's no room within the send timeout
+fn send_msg(lanes: &priority::Lanes, env: Envelope, clock: &dyn Clock) -> bool {
+    event::emit(Event::MessageSent { msg: env.msg.clone() });
+    let result = lanes.send_timeout(env, clock, Duration::from_millis(100));
+    let mut retval = true;
+    match result {
+        Ok(_) => {},
+        Err(error) => {
+            let reason = error.to_string();
+            event::emit(Event::SendFailed { msg: error.into_inner().msg, error: reason });
+            retval = false;
+            std::thread::yield_now(); // free up thread to give workers a chance to catchup
+        }
+    }
+    retval
+}
+
+/// A worker's membership of a bus, which it gives up when it stops
+struct Membership {
+    id: u64,
+    dispatch: Arc<dyn Strategy>,
+}
+
+impl Membership {
+    /// stop new msgs being routed to the worker and hand what is still queued for it to the others
+    fn leave(&self) {
+        self.dispatch.leave(self.id);
+    }
+}
+
+/// Jobs a worker is working on with their cancel flags
+type Running = Arc<Mutex<Vec<(JobId, Arc<AtomicBool>)>>>;
+
+/// State owned by a worker thread, the worker's inbox
+struct WorkerCtx {
+    recvr: priority::Lanes,
+    retry: RetryPolicy,
+    dead_letters: Arc<Mutex<VecDeque<DeadLetter>>>,
+    jobs: Arc<cancel::Jobs>,
+    current: Running, // jobs being worked on and their cancel flags
+    closed: Arc<RwLock<bool>>, // bus closed, exit once the queue is empty
+    clock: Arc<dyn Clock>,
+    pause: Pause, // the bus' pause, the worker's own is in its state
+    handover: Mutex<Vec<JobId>>, // jobs on keys the worker took over that their old owners still have
+}
+
+/// A msg taken off the bus by a worker
+struct Job {
+    env: Envelope, // as it was sent, to put it back on the bus unchanged
+    cancel: Arc<AtomicBool>,
+}
+
+impl WorkerCtx {
+    /// take msgs from the bus, joining it through its strategy
+    fn join(mb: &MessageBus, current: &Running) -> (Self, Membership) {
+        let Member { id, lanes, handover } = mb.router.dispatch.join();
+        let ctx = WorkerCtx {
+            recvr: lanes,
+            retry: mb.retry.clone(),
+            dead_letters: mb.dead_letters.clone(),
+            jobs: mb.router.jobs.clone(),
+            current: current.clone(),
+            closed: mb.router.closed.clone(),
+            clock: mb.router.clock.clone(),
+            pause: mb.router.pause.clone(),
+            handover: Mutex::new(handover),
+        };
+        (ctx, Membership { id, dispatch: mb.router.dispatch.clone() })
+    }
+
+    /// take the next msg off the bus and count it, None if nothing arrived in time
+    /// msgs cancelled while queued are skipped and not counted
+    fn recv(&self, worker: &WorkerState, timeout: Duration) -> Option<Job> {
+        let deadline = self.clock.now() + timeout;
+        while !self.handed_over() {
+            if worker.stopped() || self.clock.now() >= deadline {
+                return None;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        loop {
+            let left = deadline.saturating_duration_since(self.clock.now());
+            let env = self.recvr.recv_timeout(&*self.clock, left, &[&worker.pause, &self.pause], || worker.stopped()).ok()?;
+            if let Some(job) = self.start(worker, env) {
+                return Some(job);
+            }
+        }
+    }
+
+    /// take a msg already waiting on the bus unless paused, skipping cancelled ones
+    fn try_recv(&self, worker: &WorkerState) -> Option<Job> {
+        if !self.handed_over() {
+            return None;
+        }
+        while let Some(env) = take_through(&[&worker.pause, &self.pause], || self.recvr.try_recv()).flatten() {
+            if let Some(job) = self.start(worker, env) {
+                return Some(job);
+            }
+        }
+        None
+    }
+
+    /// the old owners of the worker's keys are done with them, so msgs of the same key never run at once
+    fn handed_over(&self) -> bool {
+        let mut handover = self.handover.lock().unwrap();
+        handover.retain(|id| !self.jobs.is_done(*id));
+        handover.is_empty()
+    }
+
+    /// start work on a msg taken off the bus, None if it was cancelled while queued
+    fn start(&self, worker: &WorkerState, env: Envelope) -> Option<Job> {
+        let Some(cancel) = self.jobs.start(env.id) else {
+            event::emit(Event::MessageSkipped { worker: worker.nm.clone(), msg: env.msg });
+            return None;
+        };
+        self.current.lock().unwrap().push((env.id, cancel.clone()));
+        worker.took(&env.msg);
+        Some(Job { env, cancel })
+    }
+
+    /// put a job back on the worker's queue as it was sent, it is not finished and runs again
+    fn requeue(&self, job: Job) {
+        self.current.lock().unwrap().retain(|(id, _)| *id != job.env.id);
+        self.jobs.requeue(job.env.id);
+        self.recvr.force(job.env);
+    }
+
+    /// token cancelled by the job or by stopping the worker
+    fn token(&self, worker: &WorkerState, job: &Job) -> CancelToken {
+        CancelToken::new(vec![job.cancel.clone(), worker.interrupt.clone()], self.clock.clone())
+    }
+
+    /// done with everything taken off the bus so far
+    fn finish_all(&self, outcome: &JobOutcome) {
+        for (id, _) in self.current.lock().unwrap().drain(..) {
+            self.jobs.finish(id, outcome);
+        }
+    }
+}
+
+impl Inbox for WorkerCtx {
+    type Item = Job;
+
+    fn recv(&mut self, worker: &WorkerState, timeout: Duration) -> Option<Job> {
+        WorkerCtx::recv(self, worker, timeout)
+    }
+
+    /// bus is closed and nothing is left for this worker to take
+    fn closed(&self) -> bool {
+        *self.closed.read().unwrap() && self.recvr.is_empty()
     }
 }
 
 /// This is a unit that receives messages to do work
 pub struct Worker {
-    rec_cnt: Arc<Mutex<u32>>, // counter of messages received
-    handle: JoinHandle<()>, // worker thread handle
-    interrupt: Arc<AtomicBool>, // signal to thread to exit
+    runtime: Runtime, // worker thread, its counter and stop signal
+    memberships: Vec<Membership>, // of each bus the worker takes msgs from
+    current: Running, // jobs being worked on, for cancelling
 }
  
 impl Worker {
     pub fn new(nm : String, mb: &MessageBus, do_delay: bool) -> Self {
-        let recvr = mb.get_recvr();
-        let ctr = Arc::new(Mutex::new(0));
-        let intr = Arc::new(AtomicBool::new(false));
-        let intr_ = intr.clone();
-        let retval = Self {
-            rec_cnt: ctr.clone(),
-            handle: std::thread::spawn(move || {
-                println!("Creating worker: {:?}", nm);
-                let chk_stop = intr.clone();
-                loop {
-                    let message = recvr.recv_timeout(Duration::from_millis(500));
-                    match message {
-                        Ok(msg) => {
-                            println!("  worker '{}' | received msg # {} : {}", nm, msg.1, msg.0);
-                            {
-                                // increment rec counter
-                                let mut n = ctr.lock().unwrap();
-                                *n += 1;
-                            }
-                        },
-                        Err(_) => {
-                            // recv timeout
-                            if chk_stop.load(std::sync::atomic::Ordering::SeqCst) {
-                                // thread signalled to stop
-                                break;
-                            }
-                            continue;
-                        }
-                    }
-                    if do_delay {
-                        // pretend to do lengthy work
-                        std::thread::sleep(Duration::from_secs(2));
-                    }
-                }
-            }),
-            interrupt: intr_
-        };
+        Self::with_cancellable_handler(nm, mb, move |_, token| {
+            if do_delay {
+                // pretend to do lengthy work
+                token.sleep(Duration::from_secs(2));
+            }
+            Ok(())
+        })
+    }
+
+    /// create a worker that runs the handler on every message it receives
+    /// failed messages are retried per the bus retry policy and then dead-lettered
+    pub fn with_handler<F>(nm : String, mb: &MessageBus, handler: F) -> Self
+    where F: Fn(&(String, usize)) -> HandlerResult + Send + 'static {
+        Self::with_cancellable_handler(nm, mb, move |msg, _| handler(msg))
+    }
+
+    /// start the worker thread, `process` is called with each msg taken off the bus
+    /// and returns how the msg (and any others it took off the bus) turned out
+    fn spawn<F>(nm: String, mb: &MessageBus, mut process: F) -> Self
+    where F: FnMut(&WorkerCtx, &WorkerState, Job) -> JobOutcome + Send + 'static {
+        let current: Running = Arc::new(Mutex::new(Vec::new()));
+        let (ctx, membership) = WorkerCtx::join(mb, &current);
+        let runtime = Runtime::run(Self::state(&nm, &current, mb), ctx, move |state, ctx, job| {
+            let outcome = process(ctx, state, job);
+            ctx.finish_all(&outcome);
+        });
         std::thread::yield_now();
-        retval
+        Self { runtime, memberships: vec![membership], current }
+    }
+
+    /// state of a worker whose current jobs are cancelled through their flags,
+    /// started with the bus' thread options
+    fn state(nm: &str, current: &Running, mb: &MessageBus) -> WorkerState {
+        let current = current.clone();
+        let mut state = WorkerState::new(Health::with_clock(nm, mb.router.clock.clone(), move || cancel_running(&current)));
+        state.thread = mb.thread.clone();
+        state
     }
 
     /// get the number of msgs recvd by worker
-    pub fn get_cnt(&self) -> u32 { *self.rec_cnt.lock().unwrap() }
+    pub fn get_cnt(&self) -> u32 { self.runtime.get_cnt() }
+
+    /// stop taking msgs off the bus until resumed, the thread stays up and finishes the msg it is on
+    /// a paused worker keeps its keys on a partitioned bus, their msgs queue up for it
+    pub fn pause(&self) { self.runtime.pause() }
+
+    pub fn resume(&self) { self.runtime.resume() }
+
+    pub fn is_paused(&self) -> bool { self.runtime.is_paused() }
 
     /// signal the worker to stop and wait until it does
+    /// the job being worked on has its token cancelled
+    /// on a partitioned bus anything still queued for it goes to the keys' new owners
     pub fn stop(self) {
-        self.interrupt.store(true, std::sync::atomic::Ordering::SeqCst);
-        self.handle.join().expect("Failed to join thread");
+        self.runtime.stop();
+        for m in self.memberships.iter() {
+            m.leave();
+        }
     }
 }
 
+/// set the cancel flag of every job a worker is running
+fn cancel_running(current: &Running) {
+    for (_, flag) in current.lock().unwrap().iter() {
+        flag.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+impl Watched for Worker {
+    fn health(&self) -> Arc<Health> { self.runtime.state().health.clone() }
+
+    fn retire(&self) {
+        // the keys move on now rather than once the stuck msg is done
+        for m in self.memberships.iter() {
+            m.leave();
+        }
+        self.runtime.interrupt();
+    }
+
+    fn is_finished(&self) -> bool { self.runtime.is_finished() }
+
+    fn stop(self) { Worker::stop(self) }
+}
+
 //use std::time::Duration;
 
 #[test]
@@ -127,13 +508,29 @@ fn test_single() {
 
 #[test]
 fn test_delay() {
-    let mb = MessageBus::new(1);
+    let clock = Arc::new(clock::MockClock::new());
+    let mut mb = MessageBus::new(1);
+    mb.set_clock(clock.clone());
     let wrk = Worker::new("Worker 1".to_string(), &mb, true);
     assert_eq!(mb.send(("this is the send message".to_string(), 1)), true);
+    // waits for the worker to take msg 1 as sends only time out when the clock is moved on
     assert_eq!(mb.send(("this is the send message".to_string(), 2)), true);
-    assert_eq!(mb.send(("this is the send message".to_string(), 3)), false);
-    std::thread::sleep(Duration::from_secs(1));
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            clock.wait_for_timer(Duration::from_millis(100));
+            clock.advance(Duration::from_millis(100));
+        });
+        assert_eq!(mb.send(("this is the send message".to_string(), 3)), false);
+    });
+    // still on the 2s delay of msg 1
+    clock.wait_for_timer(Duration::from_secs(2));
     assert_eq!(wrk.get_cnt(), 1);
+    clock.advance(Duration::from_secs(2));
+    // and then on msg 2
+    clock.wait_for_timer(Duration::from_millis(4100));
+    assert_eq!(wrk.get_cnt(), 2);
+    clock.advance(Duration::from_secs(2));
+    assert!(mb.wait_drained(Duration::from_secs(1)));
     wrk.stop();
 }
 
@@ -143,3 +540,53 @@ fn test_no_recvr() {
     assert_eq!(mb.send(("this is the send message".to_string(), 1)), true);
     assert_eq!(mb.send(("this is the send message".to_string(), 2)), false);
 }
+
+#[test]
+fn test_pause_worker() {
+    let mb = MessageBus::new(2);
+    let wrk = Worker::new("Worker 1".to_string(), &mb, false);
+    wrk.pause();
+    assert!(wrk.is_paused());
+    // the bus still takes msgs up to its capacity
+    assert!(mb.send(("this is the send message".to_string(), 1)));
+    assert!(mb.send(("this is the send message".to_string(), 2)));
+    assert!(!mb.send(("this is the send message".to_string(), 3)));
+    assert_eq!(wrk.get_cnt(), 0);
+    wrk.resume();
+    assert!(mb.wait_drained(Duration::from_secs(1)));
+    assert_eq!(wrk.get_cnt(), 2);
+    wrk.stop();
+}
+
+#[test]
+fn test_pause_bus() {
+    let mb = MessageBus::new(4);
+    let workers: Vec<Worker> = (1..=2).map(|n| Worker::new(format!("Worker {n}"), &mb, false)).collect();
+    mb.pause();
+    for i in 1..=4 {
+        assert!(mb.send(("this is the send message".to_string(), i)));
+    }
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(workers.iter().map(|w| w.get_cnt()).sum::<u32>(), 0);
+    mb.resume();
+    assert!(mb.wait_drained(Duration::from_secs(1)));
+    assert_eq!(workers.iter().map(|w| w.get_cnt()).sum::<u32>(), 4);
+    for w in workers {
+        w.stop();
+    }
+}
+
+#[test]
+fn test_thread_options() {
+    let mut mb = MessageBus::new(1);
+    mb.set_thread_options(ThreadOptions { stack_size: Some(256 * 1024), ..ThreadOptions::default() });
+    let (t, r) = crossbeam_channel::unbounded();
+    let wrk = Worker::with_handler("Worker 1".to_string(), &mb, move |_| {
+        t.send(std::thread::current().name().map(String::from)).unwrap();
+        Ok(())
+    });
+    assert!(mb.send(("this is the send message".to_string(), 1)));
+    // the thread is named after the worker
+    assert_eq!(r.recv_timeout(Duration::from_secs(1)).unwrap().as_deref(), Some("Worker 1"));
+    wrk.stop();
+}
@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use crate::event::{self, Event};
+
+use super::{CancelToken, HandlerResult, MessageBus, Worker};
+
+impl Worker {
+    /// create a worker that hands msgs to the handler in batches
+    /// a batch is sent once it has `max_batch` msgs or `max_wait` has passed since its first msg
+    /// if the handler fails the whole batch is retried, and dead-lettered when retries run out
+    pub fn with_batch_handler<F>(nm: String, mb: &MessageBus, max_batch: usize, max_wait: Duration, handler: F) -> Self
+    where F: Fn(&[(String, usize)]) -> HandlerResult + Send + 'static {
+        let max_batch = max_batch.max(1);
+        Self::spawn(nm, mb, move |ctx, worker, first| {
+            let mut batch = vec![first.env.msg];
+            let deadline = ctx.clock.now() + max_wait;
+            while batch.len() < max_batch {
+                let left = deadline.saturating_duration_since(ctx.clock.now());
+                if left.is_zero() {
+                    break;
+                }
+                match ctx.recv(worker, left) {
+                    Some(job) => batch.push(job.env.msg),
+                    None => break,
+                }
+            }
+            event::emit(Event::BatchReceived { worker: worker.nm.clone(), size: batch.len() });
+            // only stopping the worker cancels a batch
+            let token = CancelToken::new(vec![worker.interrupt.clone()], ctx.clock.clone());
+            ctx.run_with_retry(worker, &batch, &token, || handler(&batch))
+        })
+    }
+}
+
+#[cfg(test)]
+fn recording_worker(mb: &MessageBus, max_batch: usize, max_wait: Duration) -> (Worker, std::sync::Arc<std::sync::Mutex<Vec<Vec<usize>>>>) {
+    let batches = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let batches_ = batches.clone();
+    let wrk = Worker::with_batch_handler("Worker 1".to_string(), mb, max_batch, max_wait, move |msgs| {
+        batches_.lock().unwrap().push(msgs.iter().map(|m| m.1).collect());
+        Ok(())
+    });
+    (wrk, batches)
+}
+
+#[test]
+fn test_batch_full() {
+    let mb = MessageBus::new(10);
+    for i in 1..=7 {
+        assert!(mb.send(("this is the send message".to_string(), i)));
+    }
+    let (wrk, batches) = recording_worker(&mb, 3, Duration::from_millis(500));
+    std::thread::sleep(Duration::from_millis(100));
+    // the last msg waits for more until max_wait
+    assert_eq!(*batches.lock().unwrap(), vec![vec![1, 2, 3], vec![4, 5, 6]]);
+    assert_eq!(wrk.get_cnt(), 7);
+    wrk.stop();
+}
+
+#[test]
+fn test_batch_max_wait() {
+    let mb = MessageBus::new(10);
+    let (wrk, batches) = recording_worker(&mb, 10, Duration::from_millis(100));
+    assert!(mb.send(("this is the send message".to_string(), 1)));
+    assert!(mb.send(("this is the send message".to_string(), 2)));
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(batches.lock().unwrap().is_empty());
+    std::thread::sleep(Duration::from_millis(150));
+    assert_eq!(*batches.lock().unwrap(), vec![vec![1, 2]]);
+    assert_eq!(wrk.get_cnt(), 2);
+    wrk.stop();
+}
+
+#[test]
+fn test_batch_dead_letter() {
+    let mb = MessageBus::new(10);
+    let wrk = Worker::with_batch_handler("Worker 1".to_string(), &mb, 2, Duration::from_millis(50), |msgs| {
+        if msgs.iter().any(|m| m.1 == 3) { Err("bad batch".to_string()) } else { Ok(()) }
+    });
+    for i in 1..=4 {
+        assert!(mb.send(("this is the send message".to_string(), i)));
+    }
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(wrk.get_cnt(), 4);
+    let dead: Vec<usize> = mb.dead_letters().iter().map(|d| d.msg.1).collect();
+    assert_eq!(dead, vec![3, 4]);
+    wrk.stop();
+}
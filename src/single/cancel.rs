@@ -0,0 +1,336 @@
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::Duration};
+
+use crossbeam_channel::Sender;
+
+use crate::{clock::Clock, event::{self, Event}, runtime::sync::AtomicBool};
+
+use super::{Envelope, HandlerResult, MessageBus, Priority, Worker, durable::SegmentLog};
+
+/// Id given to every msg sent into a bus
+pub type JobId = u64;
+
+/// How a job ended
+#[derive(Clone, Debug, PartialEq)]
+pub enum JobOutcome {
+    Succeeded,
+    /// handler failed on every attempt, holds the last error
+    Failed(String),
+    Cancelled,
+    /// never made it onto the bus, why was reported in a SendFailed event
+    SendFailed,
+}
+
+/// Tells a handler that the job it is running should be abandoned
+/// a token is cancelled when its job, its worker or the whole bus is cancelled
+#[derive(Clone)]
+pub struct CancelToken {
+    flags: Vec<Arc<AtomicBool>>,
+    clock: Arc<dyn Clock>, // times sleeps
+}
+
+impl CancelToken {
+    pub(crate) fn new(flags: Vec<Arc<AtomicBool>>, clock: Arc<dyn Clock>) -> Self {
+        Self { flags, clock }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flags.iter().any(|f| f.load(Ordering::SeqCst))
+    }
+
+    /// sleep for the duration unless cancelled first, returns false if it was cut short
+    pub fn sleep(&self, d: Duration) -> bool {
+        let timer = self.clock.after(d);
+        loop {
+            if self.is_cancelled() {
+                return false;
+            }
+            // check for cancelling every so often in real time
+            if timer.rx().recv_timeout(Duration::from_millis(10)).is_ok() {
+                return true;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct JobState {
+    queued: HashSet<JobId>, // waiting on the bus
+    cancelled: HashSet<JobId>, // cancelled while queued, dropped when a worker takes them
+    running: HashMap<JobId, Arc<AtomicBool>>, // taken by a worker, flag cancels it
+    watchers: HashMap<JobId, Sender<(JobId, JobOutcome)>>, // told how the job ended
+    keys: HashMap<JobId, String>, // routing key of jobs on a partitioned bus
+}
+
+impl JobState {
+    fn is_done(&self, id: JobId) -> bool {
+        !self.queued.contains(&id) && self.running.get(&id).is_none_or(|flag| flag.load(Ordering::SeqCst))
+    }
+}
+
+/// Tracks every job from being sent until a worker is done with it
+#[derive(Default)]
+pub(super) struct Jobs {
+    next_id: AtomicU64,
+    state: Mutex<JobState>,
+    log: Option<SegmentLog>, // keeps unfinished jobs on disk for a persistent bus
+}
+
+impl Jobs {
+    /// jobs of a persistent bus, ids carry on from the ones recovered from the log
+    pub(super) fn with_log(log: SegmentLog, next_id: JobId) -> Self {
+        Self { next_id: AtomicU64::new(next_id), state: Mutex::default(), log: Some(log) }
+    }
+
+    pub(super) fn log(&self) -> Option<&SegmentLog> { self.log.as_ref() }
+
+    pub(super) fn envelope(&self, msg: (String, usize), priority: Priority, key: Option<String>) -> Envelope {
+        Envelope { id: self.next_id.fetch_add(1, Ordering::Relaxed), msg, priority, key }
+    }
+
+    /// record a job before it goes on the channel so a worker can't take it first,
+    /// along with the key it is routed by on a partitioned bus
+    /// returns false if it couldn't be written to the log
+    pub(super) fn queued(&self, env: &Envelope, key: Option<&str>) -> bool {
+        if let Some(log) = &self.log {
+            if let Err(error) = log.add(env) {
+                event::emit(Event::SendFailed { msg: env.msg.clone(), error: error.to_string() });
+                return false;
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        state.queued.insert(env.id);
+        if let Some(key) = key {
+            state.keys.insert(env.id, key.to_string());
+        }
+        true
+    }
+
+    /// the job never made it onto the channel, a watcher is told it failed to send
+    pub(super) fn not_queued(&self, id: JobId) {
+        let mut state = self.state.lock().unwrap();
+        state.queued.remove(&id);
+        state.keys.remove(&id);
+        if let Some(tx) = state.watchers.remove(&id) {
+            let _ = tx.send((id, JobOutcome::SendFailed));
+        }
+        drop(state);
+        self.ack(id);
+    }
+
+    /// done with the job for good, it isn't recovered after a restart
+    fn ack(&self, id: JobId) {
+        if let Some(log) = &self.log {
+            if let Err(error) = log.ack(id) {
+                event::emit(Event::Error { source: "log".to_string(), error: error.to_string() });
+            }
+        }
+    }
+
+    /// a worker took the job, returns its cancel flag or None if it was cancelled while queued
+    pub(super) fn start(&self, id: JobId) -> Option<Arc<AtomicBool>> {
+        let mut state = self.state.lock().unwrap();
+        state.queued.remove(&id);
+        if state.cancelled.remove(&id) {
+            state.keys.remove(&id);
+            if let Some(tx) = state.watchers.remove(&id) {
+                let _ = tx.send((id, JobOutcome::Cancelled));
+            }
+            drop(state);
+            self.ack(id);
+            return None;
+        }
+        let flag = Arc::new(AtomicBool::new(false));
+        state.running.insert(id, flag.clone());
+        Some(flag)
+    }
+
+    /// a worker gave the job back without finishing it, it is waiting on the bus again
+    pub(super) fn requeue(&self, id: JobId) {
+        let mut state = self.state.lock().unwrap();
+        state.running.remove(&id);
+        state.queued.insert(id);
+    }
+
+    pub(super) fn finish(&self, id: JobId, outcome: &JobOutcome) {
+        let mut state = self.state.lock().unwrap();
+        state.running.remove(&id);
+        state.keys.remove(&id);
+        if let Some(tx) = state.watchers.remove(&id) {
+            let _ = tx.send((id, outcome.clone()));
+        }
+        drop(state);
+        self.ack(id);
+    }
+
+    /// send the job's outcome on the channel once a worker is done with it
+    pub(super) fn watch(&self, id: JobId, tx: Sender<(JobId, JobOutcome)>) {
+        self.state.lock().unwrap().watchers.insert(id, tx);
+    }
+
+    pub(super) fn unwatch(&self, id: JobId) {
+        self.state.lock().unwrap().watchers.remove(&id);
+    }
+
+    /// keyed jobs queued or being worked on whose key matches
+    /// jobs cancelled while running are left out, a stuck one would hold up its key for good
+    pub(super) fn pending(&self, matches: impl Fn(&str) -> bool) -> Vec<JobId> {
+        let state = self.state.lock().unwrap();
+        state.keys.iter().filter(|(id, key)| !state.is_done(**id) && matches(key)).map(|(id, _)| *id).collect()
+    }
+
+    /// no longer queued nor running uncancelled
+    pub(super) fn is_done(&self, id: JobId) -> bool {
+        self.state.lock().unwrap().is_done(id)
+    }
+
+    /// nothing queued or being worked on
+    pub(super) fn is_idle(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.queued.is_empty() && state.running.is_empty()
+    }
+
+    fn cancel(&self, id: JobId) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.queued.remove(&id) {
+            state.cancelled.insert(id);
+            true
+        } else if let Some(flag) = state.running.get(&id) {
+            flag.store(true, Ordering::SeqCst);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn cancel_all(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let queued: Vec<JobId> = state.queued.drain().collect();
+        state.cancelled.extend(queued.iter());
+        for flag in state.running.values() {
+            flag.store(true, Ordering::SeqCst);
+        }
+        queued.len() + state.running.len()
+    }
+}
+
+impl MessageBus {
+    /// send a msg and get back its id so it can be cancelled, None if it couldn't be sent
+    pub fn submit(&self, msg: (String, usize)) -> Option<JobId> {
+        let env = self.router.jobs.envelope(msg, Priority::Normal, None);
+        let id = env.id;
+        if self.send_env(env) { Some(id) } else { None }
+    }
+
+    /// cancel a job, a queued job is dropped before any worker runs it
+    /// and a running job has its token cancelled, returns false if the job is already done
+    pub fn cancel(&self, id: JobId) -> bool {
+        self.router.jobs.cancel(id)
+    }
+
+    /// cancel every queued and running job, returns how many were cancelled
+    pub fn cancel_all(&self) -> usize {
+        self.router.jobs.cancel_all()
+    }
+}
+
+impl Worker {
+    /// create a worker whose handler gets a token telling it when to give up on a job
+    pub fn with_cancellable_handler<F>(nm: String, mb: &MessageBus, handler: F) -> Self
+    where F: Fn(&(String, usize), &CancelToken) -> HandlerResult + Send + 'static {
+        Self::spawn(nm, mb, move |ctx, worker, job| {
+            let token = ctx.token(worker, &job);
+            ctx.run_with_retry(worker, std::slice::from_ref(&job.env.msg), &token, || handler(&job.env.msg, &token))
+        })
+    }
+
+    /// cancel whatever the worker is running right now, it carries on with the next msg
+    pub fn cancel_current(&self) {
+        super::cancel_running(&self.current);
+    }
+}
+
+#[test]
+fn test_cancel_queued() {
+    let mb = MessageBus::new(4);
+    let id1 = mb.submit(("this is the send message".to_string(), 1)).unwrap();
+    let id2 = mb.submit(("this is the send message".to_string(), 2)).unwrap();
+    assert_ne!(id1, id2);
+    assert!(mb.cancel(id1));
+    assert!(!mb.cancel(id1));
+    let wrk = Worker::new("Worker 1".to_string(), &mb, false);
+    std::thread::sleep(Duration::from_millis(100));
+    // the cancelled job was never run
+    assert_eq!(wrk.get_cnt(), 1);
+    assert!(!mb.cancel(id2));
+    wrk.stop();
+}
+
+#[test]
+fn test_cancel_running() {
+    let mb = MessageBus::new(4);
+    let finished = Arc::new(AtomicBool::new(false));
+    let finished_ = finished.clone();
+    let wrk = Worker::with_cancellable_handler("Worker 1".to_string(), &mb, move |_, token| {
+        if token.sleep(Duration::from_secs(5)) {
+            finished_.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    });
+    let id = mb.submit(("this is the send message".to_string(), 1)).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    let start = std::time::Instant::now();
+    assert!(mb.cancel(id));
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(!mb.cancel(id));
+    assert!(!finished.load(Ordering::SeqCst));
+    wrk.stop();
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn test_cancel_current_keeps_worker() {
+    let mb = MessageBus::new(4);
+    let done = Arc::new(Mutex::new(Vec::new()));
+    let done_ = done.clone();
+    let wrk = Worker::with_cancellable_handler("Worker 1".to_string(), &mb, move |msg, token| {
+        let secs = if msg.1 == 1 { 5 } else { 0 };
+        if token.sleep(Duration::from_secs(secs)) {
+            done_.lock().unwrap().push(msg.1);
+        }
+        Ok(())
+    });
+    assert!(mb.send(("this is the send message".to_string(), 1)));
+    assert!(mb.send(("this is the send message".to_string(), 2)));
+    std::thread::sleep(Duration::from_millis(100));
+    wrk.cancel_current();
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(*done.lock().unwrap(), vec![2]);
+    assert_eq!(wrk.get_cnt(), 2);
+    wrk.stop();
+}
+
+#[test]
+fn test_cancel_all() {
+    let mb = MessageBus::new(4);
+    let wrk = Worker::new("Worker 1".to_string(), &mb, true);
+    for i in 1..=3 {
+        assert!(mb.send(("this is the send message".to_string(), i)));
+    }
+    std::thread::sleep(Duration::from_millis(100));
+    // one running and two queued
+    assert_eq!(mb.cancel_all(), 3);
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(wrk.get_cnt(), 1);
+    wrk.stop();
+}
+
+#[test]
+fn test_stop_interrupts_delay() {
+    let mb = MessageBus::new(1);
+    let wrk = Worker::new("Worker 1".to_string(), &mb, true);
+    assert!(mb.send(("this is the send message".to_string(), 1)));
+    std::thread::sleep(Duration::from_millis(100));
+    let start = std::time::Instant::now();
+    wrk.stop();
+    assert!(start.elapsed() < Duration::from_millis(200));
+}
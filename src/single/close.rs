@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use crate::clock::Clock;
+
+use super::{MessageBus, Priority, Worker};
+
+impl MessageBus {
+    /// stop accepting msgs, workers carry on until everything already queued is done and then exit
+    /// waits for sends already in progress, msgs still held back by a rate limit or scheduler are rejected
+    pub fn close(&self) {
+        *self.router.closed.write().unwrap() = true;
+    }
+
+    /// true once the bus has been closed
+    pub fn closed(&self) -> bool {
+        *self.router.closed.read().unwrap()
+    }
+
+    /// wait until every queued msg has been taken and finished by a worker
+    /// returns false if there was still work left when the timeout ran out
+    /// the timeout is on the bus clock, the bus is checked again every 10ms of real time
+    pub fn wait_drained(&self, timeout: Duration) -> bool {
+        let timer = self.router.clock.after(timeout);
+        loop {
+            let depth: usize = Priority::ALL.iter().map(|p| self.router.stats(*p).depth).sum();
+            if depth == 0 && self.router.jobs.is_idle() {
+                return true;
+            }
+            if timer.rx().recv_timeout(Duration::from_millis(10)).is_ok() {
+                return false;
+            }
+        }
+    }
+}
+
+impl Worker {
+    /// wait for the worker to exit on its own, which it does once its bus is closed and drained
+    pub fn join(self) {
+        self.runtime.join();
+        for m in self.memberships.iter() {
+            m.leave();
+        }
+    }
+}
+
+#[test]
+fn test_close_rejects_sends() {
+    let mb = MessageBus::new(4);
+    assert!(!mb.closed());
+    assert!(mb.send(("this is the send message".to_string(), 1)));
+    mb.close();
+    assert!(mb.closed());
+    assert!(!mb.send(("this is the send message".to_string(), 2)));
+    assert!(mb.submit(("this is the send message".to_string(), 3)).is_none());
+}
+
+#[test]
+fn test_close_drains_then_exits() {
+    let mb = MessageBus::new(10);
+    let wrk = Worker::with_handler("Worker 1".to_string(), &mb, |_| {
+        std::thread::sleep(Duration::from_millis(20));
+        Ok(())
+    });
+    for i in 1..=5 {
+        assert!(mb.send(("this is the send message".to_string(), i)));
+    }
+    mb.close();
+    assert!(mb.wait_drained(Duration::from_secs(2)));
+    // the worker exits by itself without being stopped
+    let start = std::time::Instant::now();
+    let cnt = wrk.runtime.state().rec_cnt.clone();
+    wrk.join();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(*cnt.lock().unwrap(), 5);
+}
+
+#[test]
+fn test_wait_drained_timeout() {
+    let mb = MessageBus::new(4);
+    assert!(mb.wait_drained(Duration::from_millis(10)));
+    assert!(mb.send(("this is the send message".to_string(), 1)));
+    // no worker to take it
+    assert!(!mb.wait_drained(Duration::from_millis(50)));
+}
+
+#[test]
+fn test_close_partitioned() {
+    let mb = MessageBus::partitioned(10);
+    let workers: Vec<Worker> = (1..=2).map(|n| Worker::new(format!("Worker {n}"), &mb, false)).collect();
+    for key in ["alpha", "beta", "gamma", "delta"] {
+        assert!(mb.send_keyed(key, (key.to_string(), 1)));
+    }
+    mb.close();
+    assert!(mb.wait_drained(Duration::from_secs(1)));
+    for w in workers {
+        w.join();
+    }
+}
@@ -0,0 +1,344 @@
+use std::{collections::{BTreeMap, HashMap, VecDeque}, fmt, time::Duration};
+
+use crate::clock::{self, Clock};
+
+use super::{JobId, JobOutcome, MessageBus, Priority};
+
+/// How long on the bus clock before jobs that couldn't be sent are tried again
+const RESEND: Duration = Duration::from_millis(50);
+
+/// Why a graph can't be run
+#[derive(Clone, Debug, PartialEq)]
+pub enum GraphError {
+    DuplicateJob(String),
+    UnknownDependency { job: String, dep: String },
+    /// jobs that depend on each other in a loop
+    Cycle(Vec<String>),
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphError::DuplicateJob(job) => write!(f, "job '{job}' added more than once"),
+            GraphError::UnknownDependency { job, dep } => write!(f, "job '{job}' depends on unknown job '{dep}'"),
+            GraphError::Cycle(jobs) => write!(f, "dependency cycle between {}", jobs.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
+/// How a job in a graph ended
+#[derive(Clone, Debug, PartialEq)]
+pub enum JobStatus {
+    Succeeded,
+    Failed(String),
+    Cancelled,
+    /// not run because the named upstream job didn't succeed
+    Skipped(String),
+}
+
+/// Result of running a whole graph
+#[derive(Clone, Debug, PartialEq)]
+pub struct GraphSummary {
+    pub statuses: BTreeMap<String, JobStatus>,
+}
+
+impl GraphSummary {
+    /// true if every job in the graph succeeded
+    pub fn succeeded(&self) -> bool {
+        self.statuses.values().all(|s| *s == JobStatus::Succeeded)
+    }
+
+    /// number of jobs that ended with the given status kind
+    pub fn count(&self, f: impl Fn(&JobStatus) -> bool) -> usize {
+        self.statuses.values().filter(|s| f(s)).count()
+    }
+}
+
+struct GraphJob {
+    name: String,
+    msg: (String, usize),
+    deps: Vec<String>,
+}
+
+/// Set of jobs with dependencies between them, run on a bus' workers
+#[derive(Default)]
+pub struct JobGraph {
+    jobs: Vec<GraphJob>,
+}
+
+impl JobGraph {
+    pub fn new() -> Self {
+        Self { jobs: Vec::new() }
+    }
+
+    /// add a job that is only sent to the workers once every job in `deps` has succeeded
+    pub fn add_job(&mut self, name: &str, msg: (String, usize), deps: &[&str]) {
+        self.jobs.push(GraphJob { name: name.to_string(), msg, deps: deps.iter().map(|d| d.to_string()).collect() });
+    }
+
+    pub fn len(&self) -> usize { self.jobs.len() }
+    pub fn is_empty(&self) -> bool { self.jobs.is_empty() }
+
+    /// check names are unique, dependencies exist and there are no cycles
+    /// returns the dependents of each job
+    fn validate(&self) -> Result<Vec<Vec<usize>>, GraphError> {
+        let mut idx = HashMap::new();
+        for (i, job) in self.jobs.iter().enumerate() {
+            if idx.insert(job.name.as_str(), i).is_some() {
+                return Err(GraphError::DuplicateJob(job.name.clone()));
+            }
+        }
+        let mut dependents = vec![Vec::new(); self.jobs.len()];
+        let mut indegree = vec![0; self.jobs.len()];
+        for (i, job) in self.jobs.iter().enumerate() {
+            for dep in job.deps.iter() {
+                let d = *idx.get(dep.as_str()).ok_or_else(|| GraphError::UnknownDependency { job: job.name.clone(), dep: dep.clone() })?;
+                dependents[d].push(i);
+                indegree[i] += 1;
+            }
+        }
+        // Kahn's algorithm, whatever can't be ordered is part of a cycle
+        let mut ready: VecDeque<usize> = (0..self.jobs.len()).filter(|i| indegree[*i] == 0).collect();
+        let mut ordered = 0;
+        while let Some(i) = ready.pop_front() {
+            ordered += 1;
+            for &d in dependents[i].iter() {
+                indegree[d] -= 1;
+                if indegree[d] == 0 {
+                    ready.push_back(d);
+                }
+            }
+        }
+        if ordered < self.jobs.len() {
+            let stuck = (0..self.jobs.len()).filter(|i| indegree[*i] > 0).map(|i| self.jobs[i].name.clone()).collect();
+            return Err(GraphError::Cycle(stuck));
+        }
+        Ok(dependents)
+    }
+}
+
+impl MessageBus {
+    /// run every job in the graph on the bus' workers and wait for them all to finish
+    /// a job is sent once all its prerequisites succeeded, jobs depending on one that failed are skipped
+    /// gives up once the timeout passes on the bus clock, cancelling whatever hasn't finished by then,
+    /// jobs that can't be sent are tried again until then, unless the bus was closed which cancels them
+    pub fn run_graph(&self, graph: &JobGraph, timeout: Duration) -> Result<GraphSummary, GraphError> {
+        let dependents = graph.validate()?;
+        let n = graph.jobs.len();
+        let mut waiting_on: Vec<usize> = graph.jobs.iter().map(|j| j.deps.len()).collect();
+        let mut statuses: Vec<Option<JobStatus>> = vec![None; n];
+        let mut to_send: VecDeque<usize> = (0..n).filter(|i| waiting_on[*i] == 0).collect();
+        let mut running: HashMap<JobId, usize> = HashMap::new();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let deadline = self.router.clock.now() + timeout;
+
+        while !to_send.is_empty() || !running.is_empty() {
+            // send whatever is ready until the bus is full
+            while let Some(&i) = to_send.front() {
+                let env = self.router.jobs.envelope(graph.jobs[i].msg.clone(), Priority::Normal, None);
+                let id = env.id;
+                self.router.jobs.watch(id, tx.clone());
+                if !self.send_env(env) {
+                    self.router.jobs.unwatch(id);
+                    break;
+                }
+                to_send.pop_front();
+                running.insert(id, i);
+            }
+            if self.closed() {
+                // jobs already on the bus still run
+                for i in to_send.drain(..) {
+                    end(i, JobStatus::Cancelled, graph, &dependents, &mut statuses);
+                }
+                if running.is_empty() {
+                    break;
+                }
+            }
+            let left = deadline.saturating_duration_since(self.router.clock.now());
+            if left.is_zero() {
+                break;
+            }
+            // while jobs are waiting to be sent check back regularly for room on the bus
+            let wait = if to_send.is_empty() { left } else { left.min(RESEND) };
+            let finished = clock::recv_timeout(&*self.router.clock, &rx, wait, || false).ok();
+            let Some((id, outcome)) = finished else { continue };
+            let Some(i) = running.remove(&id) else { continue };
+            match outcome {
+                JobOutcome::Succeeded => {
+                    statuses[i] = Some(JobStatus::Succeeded);
+                    for &d in dependents[i].iter() {
+                        waiting_on[d] -= 1;
+                        if waiting_on[d] == 0 && statuses[d].is_none() {
+                            to_send.push_back(d);
+                        }
+                    }
+                },
+                JobOutcome::Failed(e) => end(i, JobStatus::Failed(e), graph, &dependents, &mut statuses),
+                JobOutcome::Cancelled => end(i, JobStatus::Cancelled, graph, &dependents, &mut statuses),
+                // held back by a rate limit and then couldn't be sent, tried again like a send that failed straight away
+                JobOutcome::SendFailed => to_send.push_front(i),
+            }
+        }
+
+        // out of time, nothing left will be waited for
+        for (id, i) in running {
+            self.router.jobs.unwatch(id);
+            self.cancel(id);
+            end(i, JobStatus::Cancelled, graph, &dependents, &mut statuses);
+        }
+        for i in to_send {
+            end(i, JobStatus::Cancelled, graph, &dependents, &mut statuses);
+        }
+        let statuses = graph.jobs.iter().zip(statuses)
+            .map(|(j, s)| (j.name.clone(), s.expect("every job has a status once the graph is done")))
+            .collect();
+        Ok(GraphSummary { statuses })
+    }
+}
+
+/// give a job that didn't succeed its status and skip everything downstream of it
+fn end(i: usize, status: JobStatus, graph: &JobGraph, dependents: &[Vec<usize>], statuses: &mut [Option<JobStatus>]) {
+    statuses[i] = Some(status);
+    skip_dependents(i, &graph.jobs[i].name, dependents, statuses);
+}
+
+/// mark everything downstream of a failed job as skipped
+fn skip_dependents(failed: usize, failed_nm: &str, dependents: &[Vec<usize>], statuses: &mut [Option<JobStatus>]) {
+    let mut stack = dependents[failed].clone();
+    while let Some(d) = stack.pop() {
+        if statuses[d].is_none() {
+            statuses[d] = Some(JobStatus::Skipped(failed_nm.to_string()));
+            stack.extend(dependents[d].iter());
+        }
+    }
+}
+
+#[test]
+fn test_validate() {
+    let mut g = JobGraph::new();
+    g.add_job("a", ("a".to_string(), 1), &[]);
+    g.add_job("a", ("a".to_string(), 2), &[]);
+    assert_eq!(g.validate().unwrap_err(), GraphError::DuplicateJob("a".to_string()));
+
+    let mut g = JobGraph::new();
+    g.add_job("a", ("a".to_string(), 1), &["b"]);
+    assert_eq!(g.validate().unwrap_err(), GraphError::UnknownDependency { job: "a".to_string(), dep: "b".to_string() });
+
+    let mut g = JobGraph::new();
+    g.add_job("a", ("a".to_string(), 1), &[]);
+    g.add_job("b", ("b".to_string(), 2), &["a", "c"]);
+    g.add_job("c", ("c".to_string(), 3), &["b"]);
+    assert_eq!(g.validate().unwrap_err(), GraphError::Cycle(vec!["b".to_string(), "c".to_string()]));
+}
+
+#[test]
+fn test_run_in_dependency_order() {
+    use std::sync::{Arc, Mutex};
+
+    let mb = MessageBus::new(2);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let workers: Vec<super::Worker> = (1..=3).map(|n| {
+        let seen = seen.clone();
+        super::Worker::with_handler(format!("Worker {n}"), &mb, move |msg| {
+            std::thread::sleep(Duration::from_millis(20));
+            seen.lock().unwrap().push(msg.0.clone());
+            Ok(())
+        })
+    }).collect();
+
+    // diamond: fetch -> (parse, index) -> report
+    let mut g = JobGraph::new();
+    g.add_job("report", ("report".to_string(), 4), &["parse", "index"]);
+    g.add_job("parse", ("parse".to_string(), 2), &["fetch"]);
+    g.add_job("index", ("index".to_string(), 3), &["fetch"]);
+    g.add_job("fetch", ("fetch".to_string(), 1), &[]);
+    let summary = mb.run_graph(&g, Duration::from_secs(5)).unwrap();
+    assert!(summary.succeeded());
+    assert_eq!(summary.statuses.len(), 4);
+
+    let seen = seen.lock().unwrap();
+    let pos = |nm: &str| seen.iter().position(|s| s == nm).unwrap();
+    assert_eq!(pos("fetch"), 0);
+    assert_eq!(pos("report"), 3);
+    for w in workers {
+        w.stop();
+    }
+}
+
+#[test]
+fn test_failure_skips_dependents() {
+    let mb = MessageBus::new(4);
+    let wrk = super::Worker::with_handler("Worker 1".to_string(), &mb, |msg| {
+        if msg.0 == "compile" { Err("syntax error".to_string()) } else { Ok(()) }
+    });
+    let mut g = JobGraph::new();
+    g.add_job("checkout", ("checkout".to_string(), 1), &[]);
+    g.add_job("compile", ("compile".to_string(), 2), &["checkout"]);
+    g.add_job("test", ("test".to_string(), 3), &["compile"]);
+    g.add_job("package", ("package".to_string(), 4), &["test"]);
+    g.add_job("lint", ("lint".to_string(), 5), &["checkout"]);
+    let summary = mb.run_graph(&g, Duration::from_secs(5)).unwrap();
+    assert!(!summary.succeeded());
+    assert_eq!(summary.statuses["checkout"], JobStatus::Succeeded);
+    assert_eq!(summary.statuses["lint"], JobStatus::Succeeded);
+    assert_eq!(summary.statuses["compile"], JobStatus::Failed("syntax error".to_string()));
+    assert_eq!(summary.statuses["test"], JobStatus::Skipped("compile".to_string()));
+    assert_eq!(summary.statuses["package"], JobStatus::Skipped("compile".to_string()));
+    assert_eq!(summary.count(|s| matches!(s, JobStatus::Skipped(_))), 2);
+    // skipped jobs never reach a worker
+    assert_eq!(wrk.get_cnt(), 3);
+    wrk.stop();
+}
+
+#[test]
+fn test_graph_times_out() {
+    // no workers so nothing ever finishes
+    let mb = MessageBus::new(4);
+    let mut g = JobGraph::new();
+    g.add_job("fetch", ("fetch".to_string(), 1), &[]);
+    g.add_job("parse", ("parse".to_string(), 2), &["fetch"]);
+    let summary = mb.run_graph(&g, Duration::from_millis(100)).unwrap();
+    assert_eq!(summary.statuses["fetch"], JobStatus::Cancelled);
+    assert_eq!(summary.statuses["parse"], JobStatus::Skipped("fetch".to_string()));
+    // the job left on the bus was cancelled with it
+    let wrk = super::Worker::new("Worker 1".to_string(), &mb, false);
+    assert!(mb.wait_drained(Duration::from_secs(1)));
+    assert_eq!(wrk.get_cnt(), 0);
+    wrk.stop();
+}
+
+#[test]
+fn test_graph_on_closed_bus() {
+    let mb = MessageBus::new(4);
+    mb.close();
+    let mut g = JobGraph::new();
+    g.add_job("fetch", ("fetch".to_string(), 1), &[]);
+    g.add_job("parse", ("parse".to_string(), 2), &["fetch"]);
+    // returns without waiting for the timeout
+    let start = std::time::Instant::now();
+    let summary = mb.run_graph(&g, Duration::from_secs(10)).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(summary.statuses["fetch"], JobStatus::Cancelled);
+    assert_eq!(summary.statuses["parse"], JobStatus::Skipped("fetch".to_string()));
+}
+
+#[test]
+fn test_failed_paced_send_retried() {
+    let mut mb = MessageBus::new(1);
+    mb.set_rate_limit(super::RateLimit::LeakyBucket { rate: 20.0 }, super::OnLimit::Queue).unwrap();
+    // slow enough that paced sends time out on the full bus
+    let wrk = super::Worker::with_handler("Worker 1".to_string(), &mb, |_| {
+        std::thread::sleep(Duration::from_millis(300));
+        Ok(())
+    });
+    let mut g = JobGraph::new();
+    for (i, nm) in ["a", "b", "c", "d"].into_iter().enumerate() {
+        g.add_job(nm, (nm.to_string(), i), &[]);
+    }
+    let summary = mb.run_graph(&g, Duration::from_secs(5)).unwrap();
+    assert!(summary.succeeded(), "{:?}", summary.statuses);
+    assert_eq!(wrk.get_cnt(), 4);
+    wrk.stop();
+}
@@ -0,0 +1,394 @@
+use std::{collections::{HashMap, VecDeque}, fs::{self, File, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use crate::{event::{self, Event}, record::{Reader, frame, put_str, read_frames, sync_dir}};
+
+use super::{Envelope, JobId, MessageBus, Priority, SharedLanes, cancel::Jobs};
+
+/// Size a segment file grows to before a new one is started
+const SEGMENT_BYTES: u64 = 1024 * 1024;
+
+const ADD: u8 = 1;
+const ACK: u8 = 2;
+
+/// When writes to the log are flushed to disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    /// after every write, nothing acknowledged by a send is lost
+    Always,
+    /// after every n writes
+    EveryN(u32),
+    /// on the first write once the interval has passed since the last flush
+    Interval(Duration),
+    /// left to the OS, msgs can be lost if the machine goes down
+    Never,
+}
+
+/*
+ * Append-only log of segment files named by their sequence no. Every msg sent into the bus
+ * is written as an add record and as an ack record once a worker is done with it.
+ * Segments are deleted oldest first once all their msgs are acked, so an ack is never
+ * deleted while the msg it refers to is still on disk.
+ *
+ * Payload: tag u8, job id u64, and for adds priority u8, seq u64, text, key (0 or 1 then text)
+ */
+
+struct Segment {
+    no: u64,
+    pending: usize, // msgs added in this segment not acked yet
+}
+
+struct LogState {
+    segments: VecDeque<Segment>, // oldest first, the last one is written to
+    active: File,
+    active_len: u64,
+    pending: HashMap<JobId, u64>, // msgs not acked yet -> segment holding them
+    unsynced: u32, // writes since the last flush
+    last_sync: Instant,
+}
+
+/// On-disk record of every msg on a persistent bus that a worker hasn't finished with
+pub(super) struct SegmentLog {
+    dir: PathBuf,
+    fsync: FsyncPolicy,
+    segment_bytes: u64,
+    state: Mutex<LogState>,
+}
+
+impl SegmentLog {
+    /// open the log in the directory, returns it along with the msgs that were never acked
+    /// the msgs are written to a fresh segment and the old segments deleted
+    pub(super) fn open(dir: &Path, fsync: FsyncPolicy, segment_bytes: u64) -> io::Result<(Self, Vec<Envelope>)> {
+        fs::create_dir_all(dir)?;
+        let mut old = segment_files(dir)?;
+        old.sort();
+        let mut adds: HashMap<JobId, Envelope> = HashMap::new();
+        let mut next_no = 0;
+        for (i, (no, path)) in old.iter().enumerate() {
+            next_no = no + 1;
+            for (tag, env) in read_records(path, i + 1 == old.len())? {
+                match tag {
+                    ADD => { adds.insert(env.id, env); },
+                    ACK => { adds.remove(&env.id); },
+                    _ => unreachable!("read_records only returns adds and acks"),
+                }
+            }
+        }
+        let mut recovered: Vec<Envelope> = adds.into_values().collect();
+        recovered.sort_by_key(|env| env.id);
+
+        let active = create_segment(dir, next_no)?;
+        let log = Self {
+            dir: dir.to_path_buf(),
+            fsync,
+            segment_bytes,
+            state: Mutex::new(LogState {
+                segments: VecDeque::from([Segment { no: next_no, pending: 0 }]),
+                active,
+                active_len: 0,
+                pending: HashMap::new(),
+                unsynced: 0,
+                last_sync: Instant::now(),
+            }),
+        };
+        for env in recovered.iter() {
+            log.add(env)?;
+        }
+        log.state.lock().unwrap().active.sync_data()?;
+        // only drop the old segments once the recovered msgs are safely in the new one
+        for (_, path) in old {
+            fs::remove_file(path)?;
+        }
+        sync_dir(dir);
+        Ok((log, recovered))
+    }
+
+    /// record a msg going onto the bus, a msg already in the log (rerouted) isn't written again
+    pub(super) fn add(&self, env: &Envelope) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.pending.contains_key(&env.id) {
+            return Ok(());
+        }
+        self.append(&mut state, &encode(ADD, env))?;
+        let no = state.segments.back().unwrap().no;
+        state.pending.insert(env.id, no);
+        state.segments.back_mut().unwrap().pending += 1;
+        Ok(())
+    }
+
+    /// record that a msg is done with, and delete segments that have nothing left in them
+    pub(super) fn ack(&self, id: JobId) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(no) = state.pending.remove(&id) else { return Ok(()) };
+        self.append(&mut state, &encode_ack(id))?;
+        if let Some(seg) = state.segments.iter_mut().find(|s| s.no == no) {
+            seg.pending -= 1;
+        }
+        let mut removed = false;
+        while state.segments.len() > 1 && state.segments[0].pending == 0 {
+            let seg = state.segments.pop_front().unwrap();
+            fs::remove_file(segment_path(&self.dir, seg.no))?;
+            removed = true;
+        }
+        if removed {
+            sync_dir(&self.dir);
+        }
+        Ok(())
+    }
+
+    /// msgs in the log that haven't been acked
+    pub(super) fn pending(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    fn append(&self, state: &mut LogState, record: &[u8]) -> io::Result<()> {
+        if state.active_len > 0 && state.active_len + record.len() as u64 > self.segment_bytes {
+            // roll over to a new segment
+            state.active.sync_data()?;
+            let no = state.segments.back().unwrap().no + 1;
+            let file = create_segment(&self.dir, no)?;
+            state.active = file;
+            state.active_len = 0;
+            state.segments.push_back(Segment { no, pending: 0 });
+            sync_dir(&self.dir);
+        }
+        state.active.write_all(record)?;
+        state.active_len += record.len() as u64;
+        state.unsynced += 1;
+        let sync = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryN(n) => state.unsynced >= n,
+            FsyncPolicy::Interval(d) => state.last_sync.elapsed() >= d,
+            FsyncPolicy::Never => false,
+        };
+        if sync {
+            state.active.sync_data()?;
+            state.unsynced = 0;
+            state.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, no: u64) -> PathBuf {
+    dir.join(format!("{no:020}.log"))
+}
+
+fn create_segment(dir: &Path, no: u64) -> io::Result<File> {
+    OpenOptions::new().create(true).write(true).truncate(true).open(segment_path(dir, no))
+}
+
+/// segment files in the directory with their sequence no
+fn segment_files(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "log") {
+            if let Some(no) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                files.push((no, path));
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn encode(tag: u8, env: &Envelope) -> Vec<u8> {
+    let mut p = vec![tag];
+    p.extend(env.id.to_le_bytes());
+    p.push(env.priority as u8);
+    p.extend((env.msg.1 as u64).to_le_bytes());
+    put_str(&mut p, &env.msg.0);
+    match &env.key {
+        Some(key) => {
+            p.push(1);
+            put_str(&mut p, key);
+        },
+        None => p.push(0),
+    }
+    frame(p)
+}
+
+fn encode_ack(id: JobId) -> Vec<u8> {
+    let mut p = vec![ACK];
+    p.extend(id.to_le_bytes());
+    frame(p)
+}
+
+/// decode a payload, acks come back as an envelope with only the id set
+fn decode(payload: &[u8]) -> Option<(u8, Envelope)> {
+    let mut r = Reader(payload);
+    let tag = r.u8()?;
+    if tag != ADD && tag != ACK {
+        return None;
+    }
+    let id = r.u64()?;
+    if tag == ACK {
+        return Some((tag, Envelope { id, msg: (String::new(), 0), priority: Priority::Normal, key: None }));
+    }
+    let priority = *Priority::ALL.get(r.u8()? as usize)?;
+    let seq = r.u64()? as usize;
+    let text = r.string()?;
+    let key = match r.u8()? {
+        0 => None,
+        _ => Some(r.string()?),
+    };
+    Some((tag, Envelope { id, msg: (text, seq), priority, key }))
+}
+
+/// every record in a segment, a whole record that can't be decoded is an error
+/// only the last segment may end in a record torn by a crash mid-write, the older ones were closed whole
+fn read_records(path: &Path, last: bool) -> io::Result<Vec<(u8, Envelope)>> {
+    let bytes = fs::read(path)?;
+    let (frames, valid) = read_frames(&bytes);
+    let records = frames.into_iter().map(|p| {
+        decode(p).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("corrupt record in {}", path.display())))
+    }).collect::<io::Result<Vec<_>>>()?;
+    if valid < bytes.len() {
+        if !last {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("torn record in closed segment {}", path.display())));
+        }
+        event::emit(Event::Error { source: "durable".to_string(), error: format!("dropped {} bytes of a torn record in {}", bytes.len() - valid, path.display()) });
+    }
+    Ok(records)
+}
+
+impl MessageBus {
+    /// create a bus that keeps its queued msgs in a log in `dir` so they survive a restart
+    /// msgs left unfinished by a previous run are queued again straight away, oldest first,
+    /// and the queues are made big enough to hold them all
+    /// msgs are only logged once they reach the bus, not while held back by a rate limit or scheduler
+    pub fn persistent(capacity: u8, dir: impl AsRef<Path>, fsync: FsyncPolicy) -> io::Result<Self> {
+        let (log, recovered) = SegmentLog::open(dir.as_ref(), fsync, SEGMENT_BYTES)?;
+        let next_id = recovered.last().map_or(0, |env| env.id + 1);
+        let capacity = (capacity as usize).max(recovered.len());
+        let mb = Self::with_strategy(|jobs, clock| Arc::new(SharedLanes::new(capacity, jobs, clock)), Arc::new(Jobs::with_log(log, next_id)));
+        for env in recovered {
+            event::emit(Event::MessageRecovered { msg: env.msg.clone() });
+            mb.router.send(env);
+        }
+        Ok(mb)
+    }
+
+    /// msgs written to the log that a worker hasn't finished with, 0 if the bus isn't persistent
+    pub fn persisted_cnt(&self) -> usize {
+        self.router.jobs.log().map_or(0, |log| log.pending())
+    }
+}
+
+#[cfg(test)]
+fn test_dir(nm: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rust_chan_{nm}_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn test_recover_after_restart() {
+    let dir = test_dir("recover");
+    {
+        let mb = MessageBus::persistent(2, &dir, FsyncPolicy::Always).unwrap();
+        assert!(mb.send(("this is the send message".to_string(), 1)));
+        assert!(mb.send_with_priority(("this is the send message".to_string(), 2), Priority::High));
+        assert_eq!(mb.persisted_cnt(), 2);
+        // dropped without any worker taking the msgs
+    }
+    let mb = MessageBus::persistent(1, &dir, FsyncPolicy::Always).unwrap();
+    assert_eq!(mb.persisted_cnt(), 2);
+    assert_eq!(mb.queue_depth(Priority::High), 1);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_ = seen.clone();
+    let wrk = super::Worker::with_handler("Worker 1".to_string(), &mb, move |msg| {
+        seen_.lock().unwrap().push(msg.1);
+        Ok(())
+    });
+    assert!(mb.wait_drained(Duration::from_secs(1)));
+    assert_eq!(*seen.lock().unwrap(), vec![2, 1]);
+    assert_eq!(mb.persisted_cnt(), 0);
+    // new msgs don't reuse the recovered ids
+    assert!(mb.send(("this is the send message".to_string(), 3)));
+    assert!(mb.wait_drained(Duration::from_secs(1)));
+    assert_eq!(*seen.lock().unwrap(), vec![2, 1, 3]);
+    wrk.stop();
+    drop(mb);
+
+    // everything was acked so nothing comes back
+    let mb = MessageBus::persistent(1, &dir, FsyncPolicy::Always).unwrap();
+    assert_eq!(mb.persisted_cnt(), 0);
+    drop(mb);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_truncate_acked_segments() {
+    let dir = test_dir("truncate");
+    let (log, recovered) = SegmentLog::open(&dir, FsyncPolicy::Never, 256).unwrap();
+    assert!(recovered.is_empty());
+    let jobs = Jobs::default();
+    let envs: Vec<Envelope> = (0..20).map(|i| jobs.envelope(("this is the send message".to_string(), i), Priority::Normal, None)).collect();
+    for env in envs.iter() {
+        log.add(env).unwrap();
+    }
+    assert!(segment_files(&dir).unwrap().len() > 3);
+    // acking out of order keeps the oldest segment until its msgs are done
+    for env in envs.iter().skip(1) {
+        log.ack(env.id).unwrap();
+    }
+    assert!(segment_files(&dir).unwrap().len() > 1);
+    log.ack(envs[0].id).unwrap();
+    assert_eq!(segment_files(&dir).unwrap().len(), 1);
+    assert_eq!(log.pending(), 0);
+    drop(log);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_torn_record_ignored() {
+    let dir = test_dir("torn");
+    let (log, _) = SegmentLog::open(&dir, FsyncPolicy::Always, SEGMENT_BYTES).unwrap();
+    let jobs = Jobs::default();
+    for i in 1..=2 {
+        log.add(&jobs.envelope(("this is the send message".to_string(), i), Priority::Low, Some("key".to_string()))).unwrap();
+    }
+    drop(log);
+    // a crash part way through writing a third record
+    let (_, path) = segment_files(&dir).unwrap().pop().unwrap();
+    let mut file = OpenOptions::new().append(true).open(path).unwrap();
+    file.write_all(&encode(ADD, &jobs.envelope(("torn".to_string(), 3), Priority::Low, None))[..10]).unwrap();
+    drop(file);
+
+    let (log, recovered) = SegmentLog::open(&dir, FsyncPolicy::Always, SEGMENT_BYTES).unwrap();
+    assert_eq!(recovered.iter().map(|env| env.msg.1).collect::<Vec<_>>(), vec![1, 2]);
+    assert_eq!(recovered[0].key.as_deref(), Some("key"));
+    assert_eq!(recovered[0].priority, Priority::Low);
+    assert_eq!(log.pending(), 2);
+    drop(log);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_corrupt_record_fails_recovery() {
+    let dir = test_dir("corrupt");
+    let (log, _) = SegmentLog::open(&dir, FsyncPolicy::Always, SEGMENT_BYTES).unwrap();
+    let jobs = Jobs::default();
+    log.add(&jobs.envelope(("before".to_string(), 1), Priority::Normal, None)).unwrap();
+    drop(log);
+    // a whole record with an unknown tag, followed by an add that must not be lost quietly
+    let (_, path) = segment_files(&dir).unwrap().pop().unwrap();
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&frame(vec![9, 0, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+    file.write_all(&encode(ADD, &jobs.envelope(("after".to_string(), 2), Priority::Normal, None))).unwrap();
+    drop(file);
+    assert_eq!(SegmentLog::open(&dir, FsyncPolicy::Always, SEGMENT_BYTES).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    fs::remove_dir_all(&dir).unwrap();
+
+    // a torn record is only a crash mid-write in the last segment
+    let (log, _) = SegmentLog::open(&dir, FsyncPolicy::Always, SEGMENT_BYTES).unwrap();
+    log.add(&jobs.envelope(("first".to_string(), 1), Priority::Normal, None)).unwrap();
+    drop(log);
+    let (_, path) = segment_files(&dir).unwrap().pop().unwrap();
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&encode(ADD, &jobs.envelope(("torn".to_string(), 2), Priority::Normal, None))[..10]).unwrap();
+    drop(file);
+    create_segment(&dir, 99).unwrap();
+    assert_eq!(SegmentLog::open(&dir, FsyncPolicy::Always, SEGMENT_BYTES).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    fs::remove_dir_all(&dir).unwrap();
+}
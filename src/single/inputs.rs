@@ -0,0 +1,151 @@
+use std::{sync::{Arc, Mutex}, time::Duration};
+
+use crossbeam_channel::Select;
+
+use crate::runtime::{Inbox, Runtime, WorkerState, wait_ready};
+
+use super::{HandlerResult, Job, MessageBus, Running, Worker, WorkerCtx};
+
+/// How a worker with several input buses picks which one to take its next msg from
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Selection {
+    /// always the first input with a msg waiting, in the order the buses were given
+    #[default]
+    Priority,
+    /// inputs take turns, those with nothing waiting are passed over
+    Fair,
+}
+
+/// The buses a worker takes msgs from
+struct Inputs {
+    ctxs: Vec<WorkerCtx>,
+    selection: Selection,
+    turn: usize, // input looked at first under Fair selection
+}
+
+impl Inbox for Inputs {
+    /// the msg and the index of the input it came from
+    type Item = (usize, Job);
+
+    fn recv(&mut self, worker: &WorkerState, timeout: Duration) -> Option<(usize, Job)> {
+        let timer = self.ctxs[0].clock.after(timeout);
+        loop {
+            let n = self.ctxs.len();
+            let first = match self.selection {
+                Selection::Priority => 0,
+                Selection::Fair => self.turn,
+            };
+            for i in (first..first + n).map(|i| i % n) {
+                if let Some(job) = self.ctxs[i].try_recv(worker) {
+                    self.turn = (i + 1) % n;
+                    return Some((i, job));
+                }
+            }
+            if worker.stopped() {
+                return None;
+            }
+            // every input is empty, paused or waiting on its keys' old owners
+            // so wait for any that isn't to get a msg
+            let mut sel = Select::new();
+            let open: Vec<_> = self.ctxs.iter().filter(|ctx| !ctx.pause.is_paused() && ctx.handed_over()).collect();
+            for ctx in open.iter() {
+                ctx.recvr.select(&mut sel);
+            }
+            if wait_ready(sel, &timer, open.is_empty() || worker.pause.is_paused()) {
+                return None;
+            }
+        }
+    }
+
+    /// every bus is closed and drained, a closed input alone is just passed over
+    fn closed(&self) -> bool {
+        self.ctxs.iter().all(|ctx| ctx.closed())
+    }
+}
+
+impl Worker {
+    /// create a worker that takes msgs from every bus given, picking between them per `selection`
+    /// the handler is told the index of the bus each msg came from, failures are retried
+    /// per that bus' retry policy, and the worker exits once all the buses are closed and drained
+    /// times its polling by the first bus' clock and is started with its thread options
+    pub fn with_inputs<F>(nm: String, buses: &[&MessageBus], selection: Selection, handler: F) -> Self
+    where F: Fn(usize, &(String, usize)) -> HandlerResult + Send + 'static {
+        assert!(!buses.is_empty(), "a worker needs at least one input");
+        let current: Running = Arc::new(Mutex::new(Vec::new()));
+        let (ctxs, memberships): (Vec<_>, Vec<_>) = buses.iter().map(|mb| WorkerCtx::join(mb, &current)).unzip();
+        let inputs = Inputs { ctxs, selection, turn: 0 };
+        let runtime = Runtime::run(Self::state(&nm, &current, buses[0]), inputs, move |state, inputs, (i, job)| {
+            let ctx = &inputs.ctxs[i];
+            let token = ctx.token(state, &job);
+            let outcome = ctx.run_with_retry(state, std::slice::from_ref(&job.env.msg), &token, || handler(i, &job.env.msg));
+            ctx.finish_all(&outcome);
+        });
+        std::thread::yield_now();
+        Self { runtime, memberships, current }
+    }
+}
+
+/// (input, seq) recorded by test handlers
+#[cfg(test)]
+type Seen = Arc<Mutex<Vec<(usize, usize)>>>;
+
+#[cfg(test)]
+fn recording_worker(buses: &[&MessageBus], selection: Selection) -> (Worker, Seen) {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_ = seen.clone();
+    let wrk = Worker::with_inputs("Worker 1".to_string(), buses, selection, move |i, msg| {
+        seen_.lock().unwrap().push((i, msg.1));
+        Ok(())
+    });
+    (wrk, seen)
+}
+
+#[test]
+fn test_inputs_priority() {
+    let (control, data) = (MessageBus::new(4), MessageBus::new(4));
+    for i in 1..=3 {
+        assert!(data.send(("this is the send message".to_string(), i)));
+    }
+    for i in 1..=2 {
+        assert!(control.send(("this is the control message".to_string(), i)));
+    }
+    let (wrk, seen) = recording_worker(&[&control, &data], Selection::Priority);
+    assert!(data.wait_drained(Duration::from_secs(1)));
+    // control msgs first even though the data msgs were sent earlier
+    assert_eq!(*seen.lock().unwrap(), vec![(0, 1), (0, 2), (1, 1), (1, 2), (1, 3)]);
+    assert_eq!(wrk.get_cnt(), 5);
+    wrk.stop();
+}
+
+#[test]
+fn test_inputs_fair() {
+    let (a, b) = (MessageBus::new(4), MessageBus::new(4));
+    for i in 1..=3 {
+        assert!(a.send(("this is the send message".to_string(), i)));
+        assert!(b.send(("this is the send message".to_string(), i)));
+    }
+    assert!(a.send(("this is the send message".to_string(), 4)));
+    let (wrk, seen) = recording_worker(&[&a, &b], Selection::Fair);
+    assert!(a.wait_drained(Duration::from_secs(1)));
+    assert!(b.wait_drained(Duration::from_secs(1)));
+    // turn about while both have msgs, then whatever is left
+    assert_eq!(*seen.lock().unwrap(), vec![(0, 1), (1, 1), (0, 2), (1, 2), (0, 3), (1, 3), (0, 4)]);
+    wrk.stop();
+}
+
+#[test]
+fn test_close_one_input() {
+    let (control, data) = (MessageBus::new(4), MessageBus::new(4));
+    let (wrk, seen) = recording_worker(&[&control, &data], Selection::Priority);
+    control.close();
+    std::thread::sleep(Duration::from_millis(50));
+    // still serving the open input
+    assert!(data.send(("this is the send message".to_string(), 1)));
+    assert!(data.wait_drained(Duration::from_secs(1)));
+    assert_eq!(*seen.lock().unwrap(), vec![(1, 1)]);
+    // and exits by itself once both are closed
+    data.close();
+    let start = std::time::Instant::now();
+    wrk.join();
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
@@ -0,0 +1,269 @@
+use std::{collections::{BTreeMap, HashMap, HashSet, hash_map::DefaultHasher}, hash::{Hash, Hasher}, sync::{Arc, RwLock}};
+
+use crate::{clock::Clock, runtime::Dispatch};
+
+use super::{Envelope, JobId, Member, MessageBus, Priority, Strategy, cancel::Jobs, priority::Lanes, queue};
+
+/// Points each worker gets on the hash ring, more points spread keys more evenly
+const VNODES: u64 = 64;
+
+fn hash_of<T: Hash>(t: &T) -> u64 {
+    let mut h = DefaultHasher::new();
+    t.hash(&mut h);
+    h.finish()
+}
+
+struct Ring {
+    points: BTreeMap<u64, u64>, // ring position -> worker id
+    members: HashMap<u64, Lanes>, // worker id -> its own queue
+    next_id: u64,
+}
+
+impl Ring {
+    fn owner(&self, key: &str) -> Option<u64> {
+        let h = hash_of(&key);
+        self.points.range(h..).next().or_else(|| self.points.iter().next()).map(|(_, id)| *id)
+    }
+
+    /// move everything queued on `from` to the current owners of the keys, or to `unowned` with no workers
+    /// returns the ids of the msgs that moved, in the order they were queued
+    fn reroute(&self, from: &Lanes, unowned: &Lanes) -> Vec<JobId> {
+        // always goes over capacity rather than drop a msg or wait on a worker
+        from.drain().into_iter().map(|env| {
+            let id = env.id;
+            let to = self.owner(route_key(&env)).and_then(|id| self.members.get(&id));
+            to.unwrap_or(unowned).force(env);
+            id
+        }).collect()
+    }
+}
+
+/*
+ * Sends hold the ring for reading from picking the owner until the msg is queued, while workers
+ * join and leave with it held for writing. Whatever is queued is moved to the keys' new owners
+ * before any msg sent after the change, so each key's msgs stay in the order they were sent.
+ * A joining worker also waits for the jobs on its new keys that older owners have already taken.
+ */
+
+/// Consistent hash ring giving every key a single owning worker
+pub(super) struct Partitions {
+    capacity: usize,
+    ring: RwLock<Ring>,
+    unowned: Lanes, // msgs sent while there are no workers
+    jobs: Arc<Jobs>,
+    clock: Arc<dyn Clock>, // times out sends
+}
+
+impl Partitions {
+    fn new(capacity: usize, jobs: Arc<Jobs>, clock: Arc<dyn Clock>) -> Self {
+        let ring = RwLock::new(Ring { points: BTreeMap::new(), members: HashMap::new(), next_id: 0 });
+        Self { capacity, ring, unowned: Lanes::new(capacity), jobs, clock }
+    }
+}
+
+impl Dispatch for Partitions {
+    type Msg = Envelope;
+    type Inbox = Member;
+
+    /// add a worker to the ring, the backlog of the keys it takes over moves to its queue
+    /// it has to wait for the jobs on those keys still with their old owners
+    fn join(&self) -> Member {
+        let mut ring = self.ring.write().unwrap();
+        let id = ring.next_id;
+        ring.next_id += 1;
+        let lanes = Lanes::new(self.capacity);
+        for v in 0..VNODES {
+            ring.points.insert(hash_of(&(id, v)), id);
+        }
+        ring.members.insert(id, lanes.clone());
+        let mut moved: HashSet<JobId> = ring.reroute(&self.unowned, &self.unowned).into_iter().collect();
+        for (_, from) in ring.members.iter().filter(|(m, _)| **m != id) {
+            moved.extend(ring.reroute(from, &self.unowned));
+        }
+        let handover = self.jobs.pending(|key| ring.owner(key) == Some(id)).into_iter().filter(|job| !moved.contains(job)).collect();
+        Member { id, lanes, handover }
+    }
+
+    /// queue the msg for the worker that owns its key,
+    /// no worker joins or leaves until it is queued
+    fn dispatch(&self, env: Envelope) -> bool {
+        let key = route_key(&env).to_string();
+        let ring = self.ring.read().unwrap();
+        let owner = ring.owner(&key).and_then(|id| ring.members.get(&id));
+        self.jobs.queued(&env, Some(&key)) && queue(&self.jobs, owner.unwrap_or(&self.unowned), env, &*self.clock)
+    }
+}
+
+impl Strategy for Partitions {
+    /// take a worker off the ring, its keys and anything queued for it move to the remaining workers
+    /// does nothing if it already left
+    fn leave(&self, id: u64) {
+        let mut ring = self.ring.write().unwrap();
+        ring.points.retain(|_, owner| *owner != id);
+        if let Some(lanes) = ring.members.remove(&id) {
+            ring.reroute(&lanes, &self.unowned);
+        }
+    }
+
+    fn queues(&self) -> Vec<Lanes> {
+        let ring = self.ring.read().unwrap();
+        std::iter::once(self.unowned.clone()).chain(ring.members.values().cloned()).collect()
+    }
+
+    fn capacity(&self) -> usize { self.capacity }
+}
+
+/// key a msg is routed by, plain sends use the msg text
+fn route_key(env: &Envelope) -> &str {
+    env.key.as_deref().unwrap_or(&env.msg.0)
+}
+
+impl MessageBus {
+    /// create a bus where every msg has a key and all msgs with the same key go to the same worker
+    /// each worker gets its own queue of `capacity` msgs, which must be at least 1
+    pub fn partitioned(capacity: u8) -> Self {
+        Self::with_strategy(|jobs, clock| Arc::new(Partitions::new(capacity as usize, jobs, clock)), Arc::default())
+    }
+
+    /// send a msg routed by key, on a partitioned bus the same key always reaches the same worker
+    /// plain `send` on a partitioned bus uses the msg text as the key
+    pub fn send_keyed(&self, key: &str, msg: (String, usize)) -> bool {
+        self.send_env(self.router.jobs.envelope(msg, Priority::Normal, Some(key.to_string())))
+    }
+}
+
+/// (key text, seq, worker no) recorded by test handlers
+#[cfg(test)]
+type Seen = Arc<std::sync::Mutex<Vec<(String, usize, usize)>>>;
+
+#[cfg(test)]
+fn keyed_workers(mb: &MessageBus, n: usize, seen: &Seen) -> Vec<super::Worker> {
+    (1..=n).map(|w| {
+        let seen = seen.clone();
+        super::Worker::with_handler(format!("Worker {w}"), mb, move |msg| {
+            seen.lock().unwrap().push((msg.0.clone(), msg.1, w));
+            Ok(())
+        })
+    }).collect()
+}
+
+#[test]
+fn test_same_key_same_worker() {
+    let mb = MessageBus::partitioned(10);
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let workers = keyed_workers(&mb, 4, &seen);
+    for i in 1..=10 {
+        for key in ["alpha", "beta", "gamma", "delta", "epsilon"] {
+            assert!(mb.send_keyed(key, (key.to_string(), i)));
+        }
+    }
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 50);
+    for key in ["alpha", "beta", "gamma", "delta", "epsilon"] {
+        let runs: Vec<&(String, usize, usize)> = seen.iter().filter(|s| s.0 == key).collect();
+        // one worker per key, in the order sent
+        assert!(runs.iter().all(|s| s.2 == runs[0].2));
+        assert_eq!(runs.iter().map(|s| s.1).collect::<Vec<_>>(), (1..=10).collect::<Vec<_>>());
+    }
+    for w in workers {
+        w.stop();
+    }
+}
+
+#[test]
+fn test_rebalance_on_leave() {
+    let mb = MessageBus::partitioned(20);
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut workers = keyed_workers(&mb, 2, &seen);
+    let keys: Vec<String> = (0..20).map(|k| format!("key {k}")).collect();
+    for key in keys.iter() {
+        assert!(mb.send_keyed(key, (key.clone(), 1)));
+    }
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    // after a worker leaves the other one owns every key
+    workers.remove(0).stop();
+    for key in keys.iter() {
+        assert!(mb.send_keyed(key, (key.clone(), 2)));
+    }
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 40);
+    assert!(seen.iter().filter(|s| s.1 == 2).all(|s| s.2 == 2));
+    workers.remove(0).stop();
+}
+
+#[test]
+fn test_unassigned_until_worker_joins() {
+    let mb = MessageBus::partitioned(10);
+    assert!(mb.send_keyed("alpha", ("alpha".to_string(), 1)));
+    assert!(mb.send_keyed("beta", ("beta".to_string(), 1)));
+    assert_eq!(mb.queue_depth(Priority::Normal), 2);
+    let wrk = super::Worker::new("Worker 1".to_string(), &mb, false);
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    assert_eq!(wrk.get_cnt(), 2);
+    assert_eq!(mb.queue_depth(Priority::Normal), 0);
+    wrk.stop();
+}
+
+#[test]
+fn test_join_keeps_key_order() {
+    let mb = MessageBus::partitioned(100);
+    let seen: Seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let slow = |w: usize, seen: &Seen| {
+        let seen = seen.clone();
+        super::Worker::with_handler(format!("Worker {w}"), &mb, move |msg| {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            seen.lock().unwrap().push((msg.0.clone(), msg.1, w));
+            Ok(())
+        })
+    };
+    let mut workers = vec![slow(1, &seen)];
+    let keys: Vec<String> = (0..8).map(|k| format!("key {k}")).collect();
+    for i in 1..=10 {
+        for key in keys.iter() {
+            assert!(mb.send_keyed(key, (key.clone(), i)));
+        }
+    }
+    // joins with a backlog queued on the first worker for keys it takes over
+    workers.push(slow(2, &seen));
+    assert!(mb.wait_drained(std::time::Duration::from_secs(5)));
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 80);
+    for key in keys.iter() {
+        let seqs: Vec<usize> = seen.iter().filter(|s| s.0 == *key).map(|s| s.1).collect();
+        assert_eq!(seqs, (1..=10).collect::<Vec<_>>());
+    }
+    assert!(seen.iter().any(|s| s.2 == 2));
+    for w in workers {
+        w.stop();
+    }
+}
+
+#[test]
+fn test_leave_keeps_every_msg() {
+    let mb = MessageBus::partitioned(2);
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut workers = keyed_workers(&mb, 2, &seen);
+    for w in workers.iter() {
+        w.pause();
+    }
+    let mut sent = 0;
+    for k in 0..20 {
+        let key = format!("key {k}");
+        if mb.send_keyed(&key, (key.clone(), 1)) {
+            sent += 1;
+        }
+    }
+    // both queues are full, the one left takes the other's msgs on top of its own
+    assert_eq!(sent, 4);
+    workers.remove(0).stop();
+    assert_eq!(mb.queue_depth(Priority::Normal), 4);
+    workers[0].resume();
+    assert!(mb.wait_drained(std::time::Duration::from_secs(2)));
+    assert_eq!(seen.lock().unwrap().len(), 4);
+    workers.remove(0).stop();
+    // with no workers left msgs wait for the next one
+    assert!(mb.send_keyed("alpha", ("alpha".to_string(), 2)));
+    assert_eq!(mb.queue_depth(Priority::Normal), 1);
+}
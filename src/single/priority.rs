@@ -0,0 +1,242 @@
+use std::{sync::{Arc, atomic::{AtomicU64, AtomicUsize, Ordering}}, time::Duration};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Select, SendTimeoutError, Sender};
+
+use crate::{clock::{self, Clock}, runtime::{Pause, take_through, wait_ready}};
+
+use super::{Envelope, MessageBus};
+
+/// Urgency of a msg, workers take higher priority msgs first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    fn idx(self) -> usize { self as usize }
+}
+
+/*
+ * Weighted fair selection: out of every 7 msgs taken while all levels are backed up
+ * 4 are high, 2 are normal and 1 is low so low priority msgs are never starved
+ */
+const FAIR_ORDER: [Priority; 7] = [
+    Priority::High, Priority::Normal, Priority::High, Priority::Low,
+    Priority::High, Priority::Normal, Priority::High,
+];
+
+/// Queue depth and throughput for one priority level
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PriorityStats {
+    pub depth: usize, // msgs currently waiting
+    pub sent: u64, // msgs put on the queue
+    pub received: u64, // msgs taken off the queue by workers
+}
+
+impl std::ops::AddAssign for PriorityStats {
+    fn add_assign(&mut self, other: Self) {
+        self.depth += other.depth;
+        self.sent += other.sent;
+        self.received += other.received;
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+/// One channel per priority level, shared by the bus and its workers
+/// the levels share a single capacity, a msg of any priority needs a free slot to be queued
+#[derive(Clone)]
+pub(super) struct Lanes {
+    txs: Vec<Sender<Envelope>>,
+    rxs: Vec<Receiver<Envelope>>,
+    slots: (Sender<()>, Receiver<()>), // holds a token for every msg queued, bounded by the capacity
+    over: Arc<AtomicUsize>, // msgs forced on past the capacity
+    counters: Arc<[Counters; 3]>,
+    turn: Arc<AtomicUsize>, // position in FAIR_ORDER
+}
+
+impl Lanes {
+    /// panics on a capacity of 0, a msg is queued by taking a slot so there'd be no room for any
+    pub(super) fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "bus capacity must be at least 1");
+        let (txs, rxs) = Priority::ALL.iter().map(|_| crossbeam_channel::unbounded()).unzip();
+        let slots = crossbeam_channel::bounded(capacity);
+        Self { txs, rxs, slots, over: Arc::default(), counters: Arc::new(Default::default()), turn: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    /// queue the msg at its priority once a slot is free, giving up if none is before the timeout on the clock
+    pub(super) fn send_timeout(&self, env: Envelope, clock: &dyn Clock, timeout: Duration) -> Result<(), SendTimeoutError<Envelope>> {
+        if clock::send_timeout(clock, &self.slots.0, (), timeout).is_err() {
+            return Err(SendTimeoutError::Timeout(env));
+        }
+        let p = env.priority.idx();
+        // never full and the lanes hold their own receivers
+        let _ = self.txs[p].send(env);
+        self.counters[p].sent.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// queue a msg moved from another queue, going over capacity if there's no free slot
+    /// it was already counted as sent where it came from
+    pub(super) fn force(&self, env: Envelope) {
+        if self.slots.0.try_send(()).is_err() {
+            self.over.fetch_add(1, Ordering::SeqCst);
+        }
+        let _ = self.txs[env.priority.idx()].send(env);
+    }
+
+    /// free the slot of a msg taken off a lane, msgs over capacity give theirs up first
+    fn taken(&self) {
+        if self.over.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_err() {
+            let _ = self.slots.1.try_recv();
+        }
+    }
+
+    /// take the next msg by weighted priority if there is one waiting
+    pub(super) fn try_recv(&self) -> Option<Envelope> {
+        // favoured level for this turn first, then the rest from high to low
+        let preferred = FAIR_ORDER[self.turn.load(Ordering::Relaxed) % FAIR_ORDER.len()];
+        let order = std::iter::once(preferred).chain(Priority::ALL.into_iter().filter(|p| *p != preferred));
+        for p in order {
+            if let Ok(msg) = self.rxs[p.idx()].try_recv() {
+                self.taken();
+                self.turn.fetch_add(1, Ordering::Relaxed);
+                self.counters[p.idx()].received.fetch_add(1, Ordering::Relaxed);
+                return Some(msg);
+            }
+        }
+        None
+    }
+
+    /// add every lane to the select, to wait for a msg on any of them
+    pub(super) fn select<'a>(&'a self, sel: &mut Select<'a>) {
+        for rx in self.rxs.iter() {
+            sel.recv(rx);
+        }
+    }
+
+    /// take the next msg by weighted priority once none of the gates are paused,
+    /// waiting until the timeout passes on the clock for one to arrive
+    /// `give_up` is checked every so often in real time and ends the wait early when true
+    pub(super) fn recv_timeout(&self, clock: &dyn Clock, timeout: Duration, gates: &[&Pause], give_up: impl Fn() -> bool) -> Result<Envelope, RecvTimeoutError> {
+        let timer = clock.after(timeout);
+        loop {
+            if let Some(Some(msg)) = take_through(gates, || self.try_recv()) {
+                return Ok(msg);
+            }
+            if give_up() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            // wait for any lane to get a msg
+            let mut sel = Select::new();
+            self.select(&mut sel);
+            if wait_ready(sel, &timer, gates.iter().any(|g| g.is_paused())) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+        }
+    }
+
+    /// take everything that is queued to move it elsewhere, highest priority first
+    pub(super) fn drain(&self) -> Vec<Envelope> {
+        let msgs: Vec<Envelope> = self.rxs.iter().flat_map(|rx| rx.try_iter()).collect();
+        for _ in msgs.iter() {
+            self.taken();
+        }
+        msgs
+    }
+
+    pub(super) fn capacity(&self) -> usize { self.slots.0.capacity().unwrap_or(usize::MAX) }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.rxs.iter().all(|rx| rx.is_empty())
+    }
+
+    pub(super) fn stats(&self, priority: Priority) -> PriorityStats {
+        let c = &self.counters[priority.idx()];
+        PriorityStats {
+            depth: self.rxs[priority.idx()].len(),
+            sent: c.sent.load(Ordering::Relaxed),
+            received: c.received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl MessageBus {
+    /// number of msgs of the given priority waiting for a worker
+    pub fn queue_depth(&self, priority: Priority) -> usize { self.router.stats(priority).depth }
+
+    /// queue depth and throughput for a priority level
+    pub fn priority_stats(&self, priority: Priority) -> PriorityStats { self.router.stats(priority) }
+}
+
+#[test]
+fn test_priority_order() {
+    let mb = MessageBus::new(4);
+    assert!(mb.send_with_priority(("this is the send message".to_string(), 1), Priority::Low));
+    assert!(mb.send_with_priority(("this is the send message".to_string(), 2), Priority::Normal));
+    assert!(mb.send_with_priority(("this is the send message".to_string(), 3), Priority::High));
+    assert_eq!(mb.queue_depth(Priority::High), 1);
+    assert_eq!(mb.queue_depth(Priority::Low), 1);
+
+    let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen_ = seen.clone();
+    let wrk = super::Worker::with_handler("Worker 1".to_string(), &mb, move |msg| {
+        seen_.lock().unwrap().push(msg.1);
+        Ok(())
+    });
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(*seen.lock().unwrap(), vec![3, 2, 1]);
+    for p in Priority::ALL {
+        assert_eq!(mb.priority_stats(p), PriorityStats { depth: 0, sent: 1, received: 1 });
+    }
+    wrk.stop();
+}
+
+#[test]
+#[should_panic(expected = "bus capacity must be at least 1")]
+fn test_zero_capacity_rejected() {
+    // the lanes have no rendezvous, a bus of 0 would time out every send
+    MessageBus::new(0);
+}
+
+#[test]
+fn test_no_starvation() {
+    // with every level backed up low priority msgs still get a share
+    let lanes = Lanes::new(60);
+    for i in 0..20 {
+        for p in Priority::ALL {
+            let env = Envelope { id: 0, msg: (format!("{p:?}"), i), priority: p, key: None };
+            assert!(lanes.send_timeout(env, &crate::clock::RealClock, Duration::from_secs(1)).is_ok());
+        }
+    }
+    let mut taken = Vec::new();
+    for _ in 0..14 {
+        taken.push(lanes.recv_timeout(&crate::clock::RealClock, Duration::from_millis(10), &[], || false).unwrap().msg.0);
+    }
+    let cnt = |nm: &str| taken.iter().filter(|t| *t == nm).count();
+    assert_eq!(cnt("High"), 8);
+    assert_eq!(cnt("Normal"), 4);
+    assert_eq!(cnt("Low"), 2);
+}
+
+#[test]
+fn test_capacity_shared() {
+    let mb = MessageBus::new(2);
+    assert!(mb.send_with_priority(("this is the send message".to_string(), 1), Priority::Low));
+    assert!(mb.send_with_priority(("this is the send message".to_string(), 2), Priority::Normal));
+    // the bus holds no more than its capacity whatever the priority
+    assert!(!mb.send_with_priority(("this is the send message".to_string(), 3), Priority::High));
+    assert_eq!(Priority::ALL.iter().map(|p| mb.queue_depth(*p)).sum::<usize>(), 2);
+    // taking a msg of any priority frees a slot
+    let lanes = mb.get_recvr();
+    assert_eq!(lanes.try_recv().map(|env| env.msg.1), Some(2));
+    assert!(mb.send_with_priority(("this is the send message".to_string(), 3), Priority::High));
+}
@@ -0,0 +1,433 @@
+use std::{fmt, sync::{Arc, Mutex}, thread::JoinHandle, time::{Duration, Instant}};
+
+use crossbeam_channel::Sender;
+
+use crate::{clock::{self, Clock}, event::{self, Event}};
+
+use super::{Downstream, Envelope, MessageBus, Priority, Router, cancel::Jobs};
+
+/// Slowest rate a limit can be set to, one msg a year, so waits stay well within a Duration
+const MIN_RATE: f64 = 1.0 / (365.0 * 24.0 * 60.0 * 60.0);
+
+/// Algorithm used to throttle sends
+#[derive(Clone, Debug, PartialEq)]
+pub enum RateLimit {
+    /// allows bursts of up to `burst` msgs, refilled at `rate` msgs per second
+    TokenBucket { rate: f64, burst: u32 },
+    /// lets msgs out at a steady `rate` msgs per second with no bursts
+    LeakyBucket { rate: f64 },
+}
+
+impl RateLimit {
+    fn validate(&self) -> Result<(), RateLimitError> {
+        let (RateLimit::TokenBucket { rate, .. } | RateLimit::LeakyBucket { rate }) = *self;
+        // also rules out NaN
+        if !(rate.is_finite() && rate >= MIN_RATE) {
+            return Err(RateLimitError::Rate(rate));
+        }
+        if let RateLimit::TokenBucket { burst: 0, .. } = self {
+            return Err(RateLimitError::Burst);
+        }
+        Ok(())
+    }
+}
+
+/// Why a rate limit can't be used
+#[derive(Clone, Debug, PartialEq)]
+pub enum RateLimitError {
+    /// the rate isn't a finite number of at least one msg a year
+    Rate(f64),
+    /// a token bucket that can never hold a whole token
+    Burst,
+}
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitError::Rate(rate) => write!(f, "rate of {rate} msgs per second is out of range"),
+            RateLimitError::Burst => write!(f, "token bucket burst must be at least 1"),
+        }
+    }
+}
+
+impl std::error::Error for RateLimitError {}
+
+/// What a send does when the rate limit has been hit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnLimit {
+    /// wait until the limiter lets the msg through
+    Block,
+    /// reject the msg straight away
+    FailFast,
+    /// hold the msg and send it in the background once allowed,
+    /// up to as many msgs as the bus holds
+    Queue,
+}
+
+enum BucketState {
+    Tokens { tokens: f64, last: Instant },
+    Leaky { next: Instant }, // earliest time the next msg may leave
+}
+
+/// Thread safe limiter shared by everything sending through it
+pub struct RateLimiter {
+    limit: RateLimit,
+    state: Mutex<BucketState>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Result<Self, RateLimitError> {
+        Self::with_clock(limit, clock::real())
+    }
+
+    /// create a limiter that refills and waits by the clock
+    pub fn with_clock(limit: RateLimit, clock: Arc<dyn Clock>) -> Result<Self, RateLimitError> {
+        limit.validate()?;
+        let now = clock.now();
+        let state = match limit {
+            RateLimit::TokenBucket { burst, .. } => BucketState::Tokens { tokens: burst as f64, last: now },
+            RateLimit::LeakyBucket { .. } => BucketState::Leaky { next: now },
+        };
+        Ok(Self { limit, state: Mutex::new(state), clock })
+    }
+
+    /// take a permit if one is free, otherwise return how long until one will be
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        match (&self.limit, &mut *state) {
+            (RateLimit::TokenBucket { rate, burst }, BucketState::Tokens { tokens, last }) => {
+                // refill for the time since the last call
+                *tokens = (*tokens + now.saturating_duration_since(*last).as_secs_f64() * rate).min(*burst as f64);
+                *last = now;
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    Ok(())
+                } else {
+                    Err(Duration::from_secs_f64((1.0 - *tokens) / rate))
+                }
+            },
+            (RateLimit::LeakyBucket { rate }, BucketState::Leaky { next }) => {
+                if now >= *next {
+                    *next = now + Duration::from_secs_f64(1.0 / rate);
+                    Ok(())
+                } else {
+                    Err(*next - now)
+                }
+            },
+            _ => unreachable!("limiter state doesn't match its algorithm"),
+        }
+    }
+
+    /// wait until a permit is free and take it
+    pub fn acquire(&self) {
+        while let Err(wait) = self.try_acquire() {
+            self.clock.after(wait).wait();
+        }
+    }
+}
+
+/// Background thread that sends queued msgs as the limiter allows
+/// holds no more msgs than the bus does
+struct Pacer {
+    tx: Option<Sender<Envelope>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Pacer {
+    fn new(limiter: Arc<RateLimiter>, downstream: Downstream, jobs: Arc<Jobs>, capacity: usize) -> Self {
+        let (t, r) = crossbeam_channel::bounded::<Envelope>(capacity);
+        let handle = std::thread::spawn(move || {
+            // runs until the owner is dropped and the queue has been sent
+            for env in r.iter() {
+                limiter.acquire();
+                let id = env.id;
+                // the failure was already reported, the job still needs an outcome for whoever watches it
+                if !downstream(env) {
+                    jobs.not_queued(id);
+                }
+            }
+        });
+        Self { tx: Some(t), handle: Some(handle) }
+    }
+
+    /// hold the msg to be sent later, gives up if the queue is full
+    fn push(&self, env: Envelope) -> bool {
+        match self.tx.as_ref().unwrap().try_send(env) {
+            Ok(_) => true,
+            Err(error) => {
+                event::emit(Event::SendFailed { msg: error.into_inner().msg, error: "rate limit queue is full".to_string() });
+                false
+            },
+        }
+    }
+
+    fn pending(&self) -> usize { self.tx.as_ref().unwrap().len() }
+}
+
+impl Drop for Pacer {
+    fn drop(&mut self) {
+        // disconnect so the thread exits once everything queued is sent
+        self.tx.take();
+        if let Some(h) = self.handle.take() {
+            h.join().expect("Failed to join pacer thread");
+        }
+    }
+}
+
+/// Limiter plus the behaviour to apply when it is hit
+struct Gate {
+    limiter: Arc<RateLimiter>,
+    on_limit: OnLimit,
+    downstream: Downstream,
+    pacer: Option<Pacer>,
+}
+
+impl Gate {
+    fn new(limit: RateLimit, on_limit: OnLimit, downstream: Downstream, bus: &Router) -> Result<Self, RateLimitError> {
+        let limiter = Arc::new(RateLimiter::with_clock(limit, bus.clock.clone())?);
+        let pacer = match on_limit {
+            OnLimit::Queue => Some(Pacer::new(limiter.clone(), downstream.clone(), bus.jobs.clone(), bus.dispatch.capacity())),
+            _ => None,
+        };
+        Ok(Self { limiter, on_limit, downstream, pacer })
+    }
+
+    fn send(&self, env: Envelope) -> bool {
+        match self.on_limit {
+            OnLimit::Block => {
+                self.limiter.acquire();
+                (self.downstream)(env)
+            },
+            OnLimit::FailFast => {
+                if self.limiter.try_acquire().is_ok() {
+                    (self.downstream)(env)
+                } else {
+                    event::emit(Event::SendFailed { msg: env.msg, error: "rate limited".to_string() });
+                    false
+                }
+            },
+            // always go through the pacer so queued msgs keep their order
+            OnLimit::Queue => self.pacer.as_ref().unwrap().push(env),
+        }
+    }
+
+    fn pending(&self) -> usize {
+        self.pacer.as_ref().map_or(0, |p| p.pending())
+    }
+}
+
+/// Rate limit state of a bus, shared with the producers created from it
+pub(super) struct BusLimit(Gate);
+
+impl BusLimit {
+    pub(super) fn send(&self, env: Envelope) -> bool { self.0.send(env) }
+}
+
+/// Handle for feeding a bus with its own optional rate limit on top of the bus limit
+pub struct Producer {
+    gate: Option<Gate>,
+    downstream: Downstream,
+    jobs: Arc<Jobs>,
+}
+
+impl Producer {
+    pub fn send(&self, msg: (String, usize)) -> bool {
+        self.send_with_priority(msg, Priority::Normal)
+    }
+
+    pub fn send_with_priority(&self, msg: (String, usize), priority: Priority) -> bool {
+        let env = self.jobs.envelope(msg, priority, None);
+        match &self.gate {
+            Some(gate) => gate.send(env),
+            None => (self.downstream)(env),
+        }
+    }
+
+    /// number of msgs waiting to be sent when the producer queues on its limit
+    pub fn pending(&self) -> usize {
+        self.gate.as_ref().map_or(0, |g| g.pending())
+    }
+}
+
+impl MessageBus {
+    /// throttle every send going into the bus, including sends from producers
+    /// the limit refills and waits by the bus clock
+    pub fn set_rate_limit(&mut self, limit: RateLimit, on_limit: OnLimit) -> Result<(), RateLimitError> {
+        let router = self.router.clone();
+        let downstream: Downstream = Arc::new(move |env| router.send(env));
+        self.limit = Some(Arc::new(BusLimit(Gate::new(limit, on_limit, downstream, &self.router)?)));
+        Ok(())
+    }
+
+    /// remove the bus wide rate limit
+    pub fn clear_rate_limit(&mut self) {
+        self.limit = None;
+    }
+
+    /// number of msgs waiting to be sent when the bus queues on its limit
+    pub fn rate_limit_pending(&self) -> usize {
+        self.limit.as_ref().map_or(0, |l| l.0.pending())
+    }
+
+    /// create a producer handle, optionally with its own rate limit
+    /// the bus rate limit (if set) still applies to the producer's msgs
+    pub fn producer(&self, limit: Option<RateLimit>, on_limit: OnLimit) -> Result<Producer, RateLimitError> {
+        let downstream = self.downstream();
+        let gate = limit.map(|l| Gate::new(l, on_limit, downstream.clone(), &self.router)).transpose()?;
+        Ok(Producer { gate, downstream, jobs: self.router.jobs.clone() })
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    let limiter = RateLimiter::new(RateLimit::TokenBucket { rate: 10.0, burst: 3 }).unwrap();
+    for _ in 0..3 {
+        assert!(limiter.try_acquire().is_ok());
+    }
+    let wait = limiter.try_acquire().unwrap_err();
+    assert!(wait <= Duration::from_millis(100));
+    std::thread::sleep(wait);
+    assert!(limiter.try_acquire().is_ok());
+}
+
+#[test]
+fn test_leaky_bucket() {
+    let limiter = RateLimiter::new(RateLimit::LeakyBucket { rate: 20.0 }).unwrap();
+    assert!(limiter.try_acquire().is_ok());
+    // no bursts, the next msg must wait its turn
+    let wait = limiter.try_acquire().unwrap_err();
+    assert!(wait > Duration::from_millis(40) && wait <= Duration::from_millis(50));
+    let start = Instant::now();
+    limiter.acquire();
+    limiter.acquire();
+    assert!(start.elapsed() >= Duration::from_millis(90));
+}
+
+#[test]
+fn test_bus_fail_fast() {
+    let mut mb = MessageBus::new(10);
+    mb.set_rate_limit(RateLimit::TokenBucket { rate: 1.0, burst: 2 }, OnLimit::FailFast).unwrap();
+    let wrk = super::Worker::new("Worker 1".to_string(), &mb, false);
+    assert!(mb.send(("this is the send message".to_string(), 1)));
+    assert!(mb.send(("this is the send message".to_string(), 2)));
+    assert!(!mb.send(("this is the send message".to_string(), 3)));
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(wrk.get_cnt(), 2);
+    wrk.stop();
+}
+
+#[test]
+fn test_bus_block() {
+    let mut mb = MessageBus::new(10);
+    mb.set_rate_limit(RateLimit::LeakyBucket { rate: 20.0 }, OnLimit::Block).unwrap();
+    let wrk = super::Worker::new("Worker 1".to_string(), &mb, false);
+    let start = Instant::now();
+    for i in 1..=5 {
+        assert!(mb.send(("this is the send message".to_string(), i)));
+    }
+    // first msg goes straight away, the next 4 are spaced 50ms apart
+    assert!(start.elapsed() >= Duration::from_millis(190));
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(wrk.get_cnt(), 5);
+    wrk.stop();
+}
+
+#[test]
+fn test_producer_queue() {
+    let mb = MessageBus::new(10);
+    let wrk = super::Worker::new("Worker 1".to_string(), &mb, false);
+    let producer = mb.producer(Some(RateLimit::TokenBucket { rate: 50.0, burst: 1 }), OnLimit::Queue).unwrap();
+    let start = Instant::now();
+    for i in 1..=5 {
+        assert!(producer.send(("this is the send message".to_string(), i)));
+    }
+    // queueing doesn't hold up the caller
+    assert!(start.elapsed() < Duration::from_millis(20));
+    assert!(producer.pending() > 0);
+    // dropping waits for the queue to be sent
+    drop(producer);
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(wrk.get_cnt(), 5);
+    wrk.stop();
+}
+
+#[test]
+fn test_producer_limits_are_independent() {
+    let mut mb = MessageBus::new(10);
+    mb.set_rate_limit(RateLimit::TokenBucket { rate: 1.0, burst: 3 }, OnLimit::FailFast).unwrap();
+    let wrk = super::Worker::new("Worker 1".to_string(), &mb, false);
+    let p1 = mb.producer(Some(RateLimit::TokenBucket { rate: 1.0, burst: 1 }), OnLimit::FailFast).unwrap();
+    let p2 = mb.producer(None, OnLimit::FailFast).unwrap();
+    assert!(p1.send(("from p1".to_string(), 1)));
+    assert!(!p1.send(("from p1".to_string(), 2)));
+    assert!(p2.send(("from p2".to_string(), 1)));
+    assert!(p2.send(("from p2".to_string(), 2)));
+    // bus limit is shared by all producers
+    assert!(!p2.send(("from p2".to_string(), 3)));
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(wrk.get_cnt(), 3);
+    wrk.stop();
+}
+
+#[test]
+fn test_invalid_limits() {
+    for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+        assert_eq!(RateLimiter::new(RateLimit::LeakyBucket { rate }).err().map(|e| e.to_string()), Some(RateLimitError::Rate(rate).to_string()));
+    }
+    assert_eq!(RateLimiter::new(RateLimit::TokenBucket { rate: 1.0, burst: 0 }).err(), Some(RateLimitError::Burst));
+    let mut mb = MessageBus::new(10);
+    assert_eq!(mb.set_rate_limit(RateLimit::TokenBucket { rate: 0.0, burst: 1 }, OnLimit::Block), Err(RateLimitError::Rate(0.0)));
+    assert!(mb.producer(Some(RateLimit::TokenBucket { rate: 1.0, burst: 0 }), OnLimit::Block).is_err());
+}
+
+#[test]
+fn test_limit_on_bus_clock() {
+    let mut mb = MessageBus::new(10);
+    mb.set_rate_limit(RateLimit::LeakyBucket { rate: 1.0 }, OnLimit::FailFast).unwrap();
+    // set after the limit and still used by it
+    let clock = Arc::new(clock::MockClock::new());
+    mb.set_clock(clock.clone());
+    assert!(mb.send(("this is the send message".to_string(), 1)));
+    assert!(!mb.send(("this is the send message".to_string(), 2)));
+    clock.advance(Duration::from_secs(1));
+    assert!(mb.send(("this is the send message".to_string(), 3)));
+}
+
+#[test]
+fn test_queue_bounded_by_bus() {
+    let mut mb = MessageBus::new(2);
+    let clock = Arc::new(clock::MockClock::new());
+    mb.set_clock(clock.clone());
+    // until the clock moves only the first msg gets a permit
+    mb.set_rate_limit(RateLimit::TokenBucket { rate: 1.0, burst: 1 }, OnLimit::Queue).unwrap();
+    let sent: Vec<bool> = (1..=10).map(|i| mb.send(("this is the send message".to_string(), i))).collect();
+    // one on the bus, one waiting on the limiter and two held
+    let cnt = sent.iter().filter(|s| **s).count() as u32;
+    assert!(cnt <= 4);
+    assert!(!sent[9]);
+    assert_eq!(mb.rate_limit_pending(), 2);
+    // let the held msgs through
+    let wrk = super::Worker::new("Worker 1".to_string(), &mb, false);
+    while wrk.get_cnt() < cnt {
+        clock.advance(Duration::from_secs(1));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    wrk.stop();
+}
+
+#[test]
+fn test_paced_send_failure_ends_job() {
+    let mut mb = MessageBus::new(2);
+    mb.set_rate_limit(RateLimit::LeakyBucket { rate: 20.0 }, OnLimit::Queue).unwrap();
+    // no workers, the first two msgs fill the bus and the third times out once paced
+    for i in 1..=2 {
+        assert!(mb.submit(("this is the send message".to_string(), i)).is_some());
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let id = mb.submit(("this is the send message".to_string(), 3)).unwrap();
+    let (tx, rx) = crossbeam_channel::unbounded();
+    mb.router.jobs.watch(id, tx);
+    assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok((id, super::JobOutcome::SendFailed)));
+}
@@ -0,0 +1,229 @@
+use std::{collections::hash_map::RandomState, hash::{BuildHasher, Hasher}, time::Duration};
+
+use crate::{event::{self, Event}, runtime::WorkerState};
+
+use super::{CancelToken, HandlerResult, JobOutcome, MessageBus, WorkerCtx};
+
+/// How long to wait between attempts of a failed message
+#[derive(Clone, Debug)]
+pub enum Backoff {
+    /// same delay before every retry
+    Fixed(Duration),
+    /// delay doubles on every retry starting at `initial`, capped at `max`
+    Exponential { initial: Duration, max: Duration },
+    /// random delay between zero and the exponential delay (full jitter)
+    Jitter { initial: Duration, max: Duration },
+}
+
+impl Backoff {
+    /// delay to wait before retry number `retry` (first retry is 1)
+    pub fn delay(&self, retry: u32) -> Duration {
+        match self {
+            Backoff::Fixed(d) => *d,
+            Backoff::Exponential { initial, max } => exp_delay(*initial, *max, retry),
+            Backoff::Jitter { initial, max } => {
+                let ceiling = exp_delay(*initial, *max, retry).as_nanos() as u64;
+                if ceiling == 0 {
+                    return Duration::ZERO;
+                }
+                let mut h = RandomState::new().build_hasher();
+                h.write_u32(retry);
+                Duration::from_nanos(h.finish() % (ceiling + 1))
+            }
+        }
+    }
+}
+
+fn exp_delay(initial: Duration, max: Duration, retry: u32) -> Duration {
+    let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+    initial.saturating_mul(factor).min(max)
+}
+
+/// How many times a worker retries a message whose handler failed
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub backoff: Backoff,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, backoff: Backoff) -> Self {
+        Self { max_retries, backoff }
+    }
+
+    /// failed messages go straight to the dead-letter queue
+    pub fn none() -> Self {
+        Self::new(0, Backoff::Fixed(Duration::ZERO))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+/// A message that failed on every attempt
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeadLetter {
+    pub msg: (String, usize),
+    pub attempts: u32, // total number of times the handler was called
+    pub error: String, // error returned by the last attempt
+}
+
+impl WorkerCtx {
+    /// run the handler for msgs, retrying per the policy and dead-lettering them when retries run out
+    /// a cancelled job is not retried or dead-lettered
+    pub(super) fn run_with_retry<F>(&self, worker: &WorkerState, msgs: &[(String, usize)], token: &CancelToken, handler: F) -> JobOutcome
+    where F: Fn() -> HandlerResult {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let error = match handler() {
+                Ok(_) => return JobOutcome::Succeeded,
+                Err(error) => error,
+            };
+            if token.is_cancelled() {
+                event::emit(Event::JobCancelled { worker: worker.nm.clone(), msgs: msgs.len(), error });
+                return JobOutcome::Cancelled;
+            }
+            if attempts > self.retry.max_retries {
+                self.dead_letters.lock().unwrap().extend(msgs.iter().map(|msg| DeadLetter { msg: msg.clone(), attempts, error: error.clone() }));
+                // emitted once unlocked so a listener can look at the dead letters
+                for msg in msgs {
+                    event::emit(Event::DeadLettered { worker: worker.nm.clone(), msg: msg.clone(), attempts, error: error.clone() });
+                }
+                return JobOutcome::Failed(error);
+            }
+            event::emit(Event::Retrying { worker: worker.nm.clone(), msgs: msgs.len(), error });
+            token.sleep(self.retry.backoff.delay(attempts));
+        }
+    }
+}
+
+impl MessageBus {
+    /// create a bus whose workers retry failed messages per the given policy
+    pub fn with_retry(capacity: u8, retry: RetryPolicy) -> Self {
+        let mut mb = Self::new(capacity);
+        mb.retry = retry;
+        mb
+    }
+
+    /// copy of the messages currently in the dead-letter queue
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.dead_letters.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// number of messages in the dead-letter queue
+    pub fn dead_letter_cnt(&self) -> usize { self.dead_letters.lock().unwrap().len() }
+
+    /// remove and return everything in the dead-letter queue
+    pub fn drain_dead_letters(&self) -> Vec<DeadLetter> {
+        self.dead_letters.lock().unwrap().drain(..).collect()
+    }
+
+    /// resend dead-lettered messages to the workers, returns how many were sent
+    /// messages that can't be sent stay in the dead-letter queue
+    pub fn redrive_dead_letters(&self) -> usize {
+        let pending = self.drain_dead_letters();
+        let mut sent = 0;
+        for dl in pending {
+            if self.send(dl.msg.clone()) {
+                sent += 1;
+            } else {
+                self.dead_letters.lock().unwrap().push_back(dl);
+            }
+        }
+        sent
+    }
+}
+
+#[test]
+fn test_backoff_delays() {
+    let fixed = Backoff::Fixed(Duration::from_millis(10));
+    assert_eq!(fixed.delay(1), Duration::from_millis(10));
+    assert_eq!(fixed.delay(5), Duration::from_millis(10));
+
+    let exp = Backoff::Exponential { initial: Duration::from_millis(10), max: Duration::from_millis(50) };
+    assert_eq!(exp.delay(1), Duration::from_millis(10));
+    assert_eq!(exp.delay(2), Duration::from_millis(20));
+    assert_eq!(exp.delay(3), Duration::from_millis(40));
+    assert_eq!(exp.delay(4), Duration::from_millis(50));
+    assert_eq!(exp.delay(100), Duration::from_millis(50));
+
+    let jitter = Backoff::Jitter { initial: Duration::from_millis(10), max: Duration::from_millis(50) };
+    for retry in 1..=10 {
+        assert!(jitter.delay(retry) <= exp.delay(retry));
+    }
+}
+
+#[cfg(test)]
+fn failing_bus(max_retries: u32) -> MessageBus {
+    MessageBus::with_retry(4, RetryPolicy::new(max_retries, Backoff::Fixed(Duration::from_millis(10))))
+}
+
+#[test]
+fn test_retry_then_succeed() {
+    use std::sync::{Arc, atomic::{AtomicU32, Ordering}};
+
+    let mb = failing_bus(3);
+    let calls = Arc::new(AtomicU32::new(0));
+    let calls_ = calls.clone();
+    let wrk = super::Worker::with_handler("Worker 1".to_string(), &mb, move |_| {
+        // fail the first 2 attempts
+        if calls_.fetch_add(1, Ordering::SeqCst) < 2 { Err("not yet".to_string()) } else { Ok(()) }
+    });
+    assert!(mb.send(("flaky".to_string(), 1)));
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+    assert_eq!(wrk.get_cnt(), 1);
+    assert_eq!(mb.dead_letter_cnt(), 0);
+    wrk.stop();
+}
+
+#[test]
+fn test_dead_letter() {
+    let mb = failing_bus(2);
+    let wrk = super::Worker::with_handler("Worker 1".to_string(), &mb, |msg| {
+        if msg.1 % 2 == 0 { Err(format!("bad msg {}", msg.1)) } else { Ok(()) }
+    });
+    for i in 1..=4 {
+        assert!(mb.send(("this is the send message".to_string(), i)));
+    }
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(wrk.get_cnt(), 4);
+    let dead = mb.dead_letters();
+    assert_eq!(dead.len(), 2);
+    assert_eq!(dead[0], DeadLetter { msg: ("this is the send message".to_string(), 2), attempts: 3, error: "bad msg 2".to_string() });
+    assert_eq!(dead[1].msg.1, 4);
+    // inspecting doesn't remove
+    assert_eq!(mb.dead_letter_cnt(), 2);
+    assert_eq!(mb.drain_dead_letters().len(), 2);
+    assert_eq!(mb.dead_letter_cnt(), 0);
+    wrk.stop();
+}
+
+#[test]
+fn test_redrive() {
+    use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+
+    let mb = failing_bus(0);
+    let healthy = Arc::new(AtomicBool::new(false));
+    let healthy_ = healthy.clone();
+    let wrk = super::Worker::with_handler("Worker 1".to_string(), &mb, move |_| {
+        if healthy_.load(Ordering::SeqCst) { Ok(()) } else { Err("downstream unavailable".to_string()) }
+    });
+    for i in 1..=3 {
+        assert!(mb.send(("this is the send message".to_string(), i)));
+    }
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(mb.dead_letter_cnt(), 3);
+
+    // downstream recovered, push the failures through again
+    healthy.store(true, Ordering::SeqCst);
+    assert_eq!(mb.redrive_dead_letters(), 3);
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(mb.dead_letter_cnt(), 0);
+    assert_eq!(wrk.get_cnt(), 6);
+    wrk.stop();
+}
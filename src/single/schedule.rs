@@ -0,0 +1,269 @@
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap}, sync::{Arc, Mutex}, thread::JoinHandle, time::{Duration, Instant}};
+
+use crossbeam_channel::Sender;
+
+use crate::{clock::Clock, event::{self, Event}};
+
+use super::{Downstream, MessageBus, Priority, cancel::Jobs};
+
+// shared so it can be called without the timers locked, a factory may cancel or schedule jobs
+type MsgFactory = Arc<Mutex<dyn FnMut() -> (String, usize) + Send>>;
+
+enum Entry {
+    Once((String, usize)),
+    Every(Duration, MsgFactory),
+}
+
+/// Pending jobs, ordered by when they are due on the bus clock
+struct Timers {
+    heap: BinaryHeap<Reverse<(Instant, u64)>>, // (due, id) cancelled ids are skipped when popped
+    jobs: HashMap<u64, Entry>,
+    next_id: u64,
+    stop: bool,
+}
+
+type Shared = Arc<Mutex<Timers>>;
+
+/// Dedicated thread that puts scheduled msgs on the bus when they are due
+pub(super) struct Scheduler {
+    shared: Shared,
+    wake: Sender<()>, // a sooner job was added or the scheduler is stopping
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Scheduler {
+    pub(super) fn new(downstream: Downstream, jobs: Arc<Jobs>, clock: Arc<dyn Clock>) -> Self {
+        let shared: Shared = Arc::new(Mutex::new(Timers { heap: BinaryHeap::new(), jobs: HashMap::new(), next_id: 0, stop: false }));
+        // one wake left pending is enough for any number of adds
+        let (wake, woken) = crossbeam_channel::bounded(1);
+        let shared_ = shared.clone();
+        let handle = std::thread::spawn(move || {
+            loop {
+                let mut timers = shared_.lock().unwrap();
+                if timers.stop {
+                    break;
+                }
+                let now = clock.now();
+                let (due, id) = match timers.heap.peek() {
+                    None => {
+                        drop(timers);
+                        let _ = woken.recv();
+                        continue;
+                    },
+                    Some(Reverse(next)) => *next,
+                };
+                if due > now {
+                    // sleep until due or until a sooner job is added
+                    drop(timers);
+                    let timer = clock.after(due - now);
+                    crossbeam_channel::select! {
+                        recv(woken) -> _ => {},
+                        recv(timer.rx()) -> _ => {},
+                    }
+                    continue;
+                }
+                timers.heap.pop();
+                let entry = match timers.jobs.get(&id) {
+                    None => continue, // cancelled
+                    Some(Entry::Every(interval, factory)) => {
+                        // don't try to catch up if sending fell behind
+                        let (interval, factory) = (*interval, factory.clone());
+                        timers.heap.push(Reverse(((due + interval).max(now), id)));
+                        Entry::Every(interval, factory)
+                    },
+                    Some(Entry::Once(_)) => timers.jobs.remove(&id).unwrap(),
+                };
+                // don't hold the lock while building the msg or while the bus is busy
+                drop(timers);
+                let msg = match entry {
+                    Entry::Every(_, factory) => (factory.lock().unwrap())(),
+                    Entry::Once(msg) => msg,
+                };
+                if !downstream(jobs.envelope(msg.clone(), Priority::Normal, None)) {
+                    // not tried again, a periodic msg carries on with the next one
+                    event::emit(Event::SendFailed { msg, error: "scheduled msg dropped".to_string() });
+                }
+            }
+        });
+        Self { shared, wake, handle: Some(handle) }
+    }
+
+    fn add(&self, due: Instant, entry: Entry) -> ScheduleHandle {
+        let mut timers = self.shared.lock().unwrap();
+        let id = timers.next_id;
+        timers.next_id += 1;
+        timers.jobs.insert(id, entry);
+        timers.heap.push(Reverse((due, id)));
+        let _ = self.wake.try_send(());
+        ScheduleHandle { id, shared: self.shared.clone() }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().stop = true;
+        let _ = self.wake.try_send(());
+        if let Some(h) = self.handle.take() {
+            h.join().expect("Failed to join scheduler thread");
+        }
+    }
+}
+
+/// Returned for every scheduled msg so it can be cancelled
+pub struct ScheduleHandle {
+    id: u64,
+    shared: Shared,
+}
+
+impl ScheduleHandle {
+    /// stop the msg from being sent, returns false if it already went or was cancelled
+    /// for periodic msgs this stops all future sends
+    pub fn cancel(&self) -> bool {
+        self.shared.lock().unwrap().jobs.remove(&self.id).is_some()
+    }
+
+    /// true while the msg is still waiting to be sent (always true for periodic msgs until cancelled)
+    pub fn is_pending(&self) -> bool {
+        self.shared.lock().unwrap().jobs.contains_key(&self.id)
+    }
+}
+
+impl MessageBus {
+    /// send the msg once the delay has passed on the bus clock
+    pub fn send_after(&self, delay: Duration, msg: (String, usize)) -> ScheduleHandle {
+        self.send_at(self.router.clock.now() + delay, msg)
+    }
+
+    /// send the msg at the given time on the bus clock (straight away if it's in the past)
+    pub fn send_at(&self, at: Instant, msg: (String, usize)) -> ScheduleHandle {
+        self.scheduler().add(at, Entry::Once(msg))
+    }
+
+    /// send a msg built by the factory every interval, first one after one interval
+    pub fn schedule_every<F>(&self, interval: Duration, msg_factory: F) -> ScheduleHandle
+    where F: FnMut() -> (String, usize) + Send + 'static {
+        self.scheduler().add(self.router.clock.now() + interval, Entry::Every(interval, Arc::new(Mutex::new(msg_factory))))
+    }
+
+    fn scheduler(&self) -> &Scheduler {
+        // started on first use so buses that don't schedule don't pay for the thread
+        // scheduled msgs go through the rate limit the bus had at that point
+        self.scheduler.get_or_init(|| Scheduler::new(self.downstream(), self.router.jobs.clone(), self.router.clock.clone()))
+    }
+}
+
+#[test]
+fn test_send_after() {
+    let mb = MessageBus::new(4);
+    let wrk = super::Worker::new("Worker 1".to_string(), &mb, false);
+    let h = mb.send_after(Duration::from_millis(200), ("this is the delayed message".to_string(), 1));
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(h.is_pending());
+    assert_eq!(wrk.get_cnt(), 0);
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(!h.is_pending());
+    assert_eq!(wrk.get_cnt(), 1);
+    wrk.stop();
+}
+
+#[test]
+fn test_send_at_in_order() {
+    let mb = MessageBus::new(4);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_ = seen.clone();
+    let wrk = super::Worker::with_handler("Worker 1".to_string(), &mb, move |msg| {
+        seen_.lock().unwrap().push(msg.1);
+        Ok(())
+    });
+    let now = Instant::now();
+    mb.send_at(now + Duration::from_millis(150), ("this is the send message".to_string(), 3));
+    mb.send_at(now + Duration::from_millis(50), ("this is the send message".to_string(), 1));
+    mb.send_at(now + Duration::from_millis(100), ("this is the send message".to_string(), 2));
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(*seen.lock().unwrap(), vec![1, 2, 3]);
+    wrk.stop();
+}
+
+#[test]
+fn test_cancel() {
+    let mb = MessageBus::new(4);
+    let wrk = super::Worker::new("Worker 1".to_string(), &mb, false);
+    let h = mb.send_after(Duration::from_millis(100), ("this is the delayed message".to_string(), 1));
+    assert!(h.cancel());
+    assert!(!h.cancel());
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(wrk.get_cnt(), 0);
+    wrk.stop();
+}
+
+#[test]
+fn test_schedule_every() {
+    let mb = MessageBus::new(4);
+    let wrk = super::Worker::new("Worker 1".to_string(), &mb, false);
+    let mut n = 0;
+    let h = mb.schedule_every(Duration::from_millis(50), move || {
+        n += 1;
+        ("this is the periodic message".to_string(), n)
+    });
+    std::thread::sleep(Duration::from_millis(275));
+    assert!(h.cancel());
+    let cnt = wrk.get_cnt();
+    assert!((4..=6).contains(&cnt), "expected about 5 periodic msgs, got {cnt}");
+    std::thread::sleep(Duration::from_millis(150));
+    assert_eq!(wrk.get_cnt(), cnt);
+    wrk.stop();
+}
+
+#[test]
+fn test_schedule_on_bus_clock() {
+    let clock = Arc::new(crate::clock::MockClock::new());
+    let mut mb = MessageBus::new(4);
+    mb.set_clock(clock.clone());
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let wrk = super::Worker::with_handler("Worker 1".to_string(), &mb, move |msg| {
+        tx.send(msg.1).unwrap();
+        Ok(())
+    });
+    let h = mb.send_after(Duration::from_secs(60), ("this is the delayed message".to_string(), 1));
+    clock.wait_for_timer(Duration::from_secs(60));
+    // a minute of real time never passes
+    assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+    assert!(h.is_pending());
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(1));
+    assert!(!h.is_pending());
+    wrk.stop();
+}
+
+#[test]
+fn test_factory_cancels_itself() {
+    let mb = MessageBus::new(4);
+    let wrk = super::Worker::new("Worker 1".to_string(), &mb, false);
+    let handle: Arc<Mutex<Option<ScheduleHandle>>> = Arc::default();
+    let handle_ = handle.clone();
+    let mut n = 0;
+    let h = mb.schedule_every(Duration::from_millis(20), move || {
+        n += 1;
+        if n == 2 {
+            // the timers aren't locked while the factory runs
+            assert!(handle_.lock().unwrap().as_ref().unwrap().cancel());
+        }
+        ("this is the periodic message".to_string(), n)
+    });
+    *handle.lock().unwrap() = Some(h);
+    std::thread::sleep(Duration::from_millis(150));
+    assert!(!handle.lock().unwrap().as_ref().unwrap().is_pending());
+    assert_eq!(wrk.get_cnt(), 2);
+    wrk.stop();
+}
+
+#[test]
+fn test_failed_due_send_reported() {
+    let recorder = crate::event::recorder();
+    let mb = MessageBus::new(4);
+    mb.close();
+    mb.send_after(Duration::from_millis(10), ("scheduled on closed bus".to_string(), 1));
+    std::thread::sleep(Duration::from_millis(100));
+    let failed = Event::SendFailed { msg: ("scheduled on closed bus".to_string(), 1), error: "scheduled msg dropped".to_string() };
+    assert!(recorder.events().contains(&failed));
+}
@@ -0,0 +1,172 @@
+use std::{io, net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs}, sync::Arc};
+
+use crate::net::{self, NetOptions};
+
+use super::{HandlerResult, MessageBus, Priority, Worker, wire::{Client, Server}};
+
+/// Exposes a bus over TCP to producers and workers on other hosts
+pub struct TcpServer {
+    server: Server,
+    addr: SocketAddr,
+}
+
+impl TcpServer {
+    /// listen on the address, port 0 picks a free port
+    pub fn bind(addr: impl ToSocketAddrs, mb: Arc<MessageBus>, opts: NetOptions) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let server = Server::start(mb, opts, move || net::tcp_accept(&listener));
+        Ok(Self { server, addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr { self.addr }
+
+    /// stop listening and drop every connection
+    pub fn stop(self) {
+        self.server.stop();
+    }
+}
+
+/// Sends msgs into a bus on another host over TCP
+pub struct TcpProducer {
+    client: Client<TcpStream>,
+}
+
+impl TcpProducer {
+    /// a lost connection is reopened with backoff on the next send,
+    /// a msg whose reply was lost is sent again so the bus can get it twice
+    pub fn connect(addr: SocketAddr, opts: NetOptions) -> io::Result<Self> {
+        let opts_ = opts.clone();
+        Ok(Self { client: Client::producer(Box::new(move || net::tcp_connect(addr, &opts_)), opts)? })
+    }
+
+    /// same as `MessageBus::send`, false if the bus had no room or couldn't be reached
+    pub fn send(&self, msg: (String, usize)) -> bool {
+        self.client.send(msg, Priority::Normal)
+    }
+
+    pub fn send_with_priority(&self, msg: (String, usize), priority: Priority) -> bool {
+        self.client.send(msg, priority)
+    }
+}
+
+impl Worker {
+    /// create a worker that takes msgs from a bus served over TCP
+    /// the connection is reopened with backoff whenever it is lost, until the worker is stopped
+    pub fn connect_tcp<F>(nm: String, addr: SocketAddr, opts: NetOptions, handler: F) -> io::Result<Self>
+    where F: Fn(&(String, usize)) -> HandlerResult + Send + 'static {
+        let first = net::tcp_connect(addr, &opts)?;
+        let opts_ = opts.clone();
+        Ok(Self::remote(nm, first, Box::new(move || net::tcp_connect(addr, &opts_)), opts, handler))
+    }
+}
+
+#[cfg(test)]
+fn fast_opts() -> NetOptions {
+    use std::time::Duration;
+
+    NetOptions {
+        heartbeat: Duration::from_millis(50),
+        dead_after: Duration::from_millis(300),
+        reconnect: super::Backoff::Fixed(Duration::from_millis(50)),
+    }
+}
+
+#[test]
+fn test_tcp_producer_and_worker() {
+    let mb = Arc::new(MessageBus::new(2));
+    let server = TcpServer::bind("127.0.0.1:0", mb.clone(), fast_opts()).unwrap();
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen_ = seen.clone();
+    let wrk = Worker::connect_tcp("Worker 1".to_string(), server.local_addr(), fast_opts(), move |msg| {
+        seen_.lock().unwrap().push(msg.1);
+        Ok(())
+    }).unwrap();
+    let producer = TcpProducer::connect(server.local_addr(), fast_opts()).unwrap();
+    for i in 1..=5 {
+        assert!(producer.send(("this is the send message".to_string(), i)));
+    }
+    assert!(mb.wait_drained(std::time::Duration::from_secs(2)));
+    assert_eq!(*seen.lock().unwrap(), vec![1, 2, 3, 4, 5]);
+    assert_eq!(wrk.get_cnt(), 5);
+    wrk.stop();
+    server.stop();
+}
+
+#[test]
+fn test_tcp_reconnect() {
+    use std::time::Duration;
+
+    let mb = Arc::new(MessageBus::new(2));
+    let server = TcpServer::bind("127.0.0.1:0", mb.clone(), fast_opts()).unwrap();
+    let addr = server.local_addr();
+    let wrk = Worker::connect_tcp("Worker 1".to_string(), addr, fast_opts(), |_| Ok(())).unwrap();
+    let producer = TcpProducer::connect(addr, fast_opts()).unwrap();
+    assert!(producer.send(("this is the send message".to_string(), 1)));
+    assert!(mb.wait_drained(Duration::from_secs(1)));
+    server.stop();
+    std::thread::sleep(Duration::from_millis(200));
+
+    // the server comes back on the same port and both ends find it again
+    let server = TcpServer::bind(addr, mb.clone(), fast_opts()).unwrap();
+    assert!(producer.send(("this is the send message".to_string(), 2)));
+    assert!(mb.wait_drained(Duration::from_secs(2)));
+    assert_eq!(wrk.get_cnt(), 2);
+    wrk.stop();
+    server.stop();
+}
+
+#[test]
+fn test_tcp_dead_worker() {
+    use std::{io::Write, time::{Duration, Instant}};
+
+    let mb = Arc::new(MessageBus::new(2));
+    let server = TcpServer::bind("127.0.0.1:0", mb.clone(), fast_opts()).unwrap();
+    // a worker that says hello and then hangs without ever sending a heartbeat
+    let mut hung = TcpStream::connect(server.local_addr()).unwrap();
+    hung.write_all(&net::Frame::HelloWorker("Worker 1".to_string()).encode()).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    let start = Instant::now();
+    assert!(mb.send_with_priority(("this is the send message".to_string(), 1), Priority::High));
+    std::thread::sleep(Duration::from_millis(100));
+    // the msg handed to the hung worker goes to a live one once it is found dead
+    let wrk = Worker::new("Worker 2".to_string(), &mb, false);
+    assert!(mb.wait_drained(Duration::from_secs(2)));
+    assert_eq!(wrk.get_cnt(), 1);
+    // put back as it was sent
+    assert_eq!(mb.priority_stats(Priority::Normal).received, 0);
+    assert!(start.elapsed() < Duration::from_secs(1));
+    wrk.stop();
+    server.stop();
+}
+
+#[test]
+fn test_tcp_late_result() {
+    use std::time::Duration;
+
+    use crate::event::{self, Event};
+
+    let recorder = event::recorder();
+    let mb = Arc::new(MessageBus::new(2));
+    let server = TcpServer::bind("127.0.0.1:0", mb.clone(), fast_opts()).unwrap();
+    let (started, on_started) = crossbeam_channel::unbounded();
+    let wrk = Worker::connect_tcp("Late Worker".to_string(), server.local_addr(), fast_opts(), move |msg| {
+        if msg.1 == 1 {
+            started.send(()).unwrap();
+            std::thread::sleep(Duration::from_millis(300));
+        }
+        Ok(())
+    }).unwrap();
+    let id = mb.submit(("this is the send message".to_string(), 1)).unwrap();
+    on_started.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert!(mb.cancel(id));
+    // the handler finishes after its job was given up on, which doesn't drop the worker
+    std::thread::sleep(Duration::from_millis(500));
+    assert!(mb.send(("this is the send message".to_string(), 2)));
+    assert!(mb.wait_drained(Duration::from_secs(1)));
+    assert_eq!(wrk.get_cnt(), 2);
+    assert!(!recorder.events_for("Late Worker").iter().any(|e| matches!(e, Event::Disconnected { .. })));
+    wrk.stop();
+    server.stop();
+}
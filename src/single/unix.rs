@@ -0,0 +1,165 @@
+use std::{io, os::unix::{fs::FileTypeExt, net::{UnixListener, UnixStream}}, path::{Path, PathBuf}, sync::Arc};
+
+use crate::net::NetOptions;
+
+use super::{HandlerResult, MessageBus, Priority, Worker, wire::{Client, Server}};
+
+/// Exposes a bus on a Unix domain socket to producers and workers in other processes
+pub struct UnixServer {
+    server: Server,
+    path: PathBuf,
+}
+
+impl UnixServer {
+    /// listen on the socket path, replacing a socket left behind by a server that is gone
+    /// fails with AddrInUse if something else is at the path or a server still answers on it
+    pub fn bind(path: impl AsRef<Path>, mb: Arc<MessageBus>, opts: NetOptions) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Ok(meta) = std::fs::symlink_metadata(&path) {
+            if !meta.file_type().is_socket() || UnixStream::connect(&path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", path.display())));
+            }
+            std::fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        let server = Server::start(mb, opts, move || match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                Ok(Some(stream))
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        });
+        Ok(Self { server, path })
+    }
+
+    /// stop listening and drop every connection, then remove the socket
+    pub fn stop(self) {
+        self.server.stop();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Sends msgs into a bus in another process over a Unix domain socket
+pub struct UnixProducer {
+    client: Client<UnixStream>,
+}
+
+impl UnixProducer {
+    /// a lost connection is reopened on the next send
+    pub fn connect(path: impl AsRef<Path>, opts: NetOptions) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        Ok(Self { client: Client::producer(Box::new(move || UnixStream::connect(&path)), opts)? })
+    }
+
+    /// same as `MessageBus::send`, false if the bus had no room or the connection failed
+    pub fn send(&self, msg: (String, usize)) -> bool {
+        self.client.send(msg, Priority::Normal)
+    }
+
+    pub fn send_with_priority(&self, msg: (String, usize), priority: Priority) -> bool {
+        self.client.send(msg, priority)
+    }
+}
+
+impl Worker {
+    /// create a worker that takes msgs from a bus served on a Unix domain socket
+    /// the bus hands over one msg at a time and applies its retry policy to failed ones
+    pub fn connect_unix<F>(nm: String, path: impl AsRef<Path>, opts: NetOptions, handler: F) -> io::Result<Self>
+    where F: Fn(&(String, usize)) -> HandlerResult + Send + 'static {
+        let path = path.as_ref().to_path_buf();
+        let first = UnixStream::connect(&path)?;
+        Ok(Self::remote(nm, first, Box::new(move || UnixStream::connect(&path)), opts, handler))
+    }
+}
+
+#[cfg(test)]
+use std::time::Duration;
+
+#[cfg(test)]
+fn socket_path(nm: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rust_chan_{nm}_{}.sock", std::process::id()))
+}
+
+#[test]
+fn test_remote_producer_backpressure() {
+    let path = socket_path("producer");
+    let mb = Arc::new(MessageBus::new(1));
+    let server = UnixServer::bind(&path, mb.clone(), NetOptions::default()).unwrap();
+    let producer = UnixProducer::connect(&path, NetOptions::default()).unwrap();
+    assert!(producer.send(("this is the send message".to_string(), 1)));
+    // no worker and the bus is full
+    assert!(!producer.send(("this is the send message".to_string(), 2)));
+    // the capacity is shared by every priority
+    assert!(!producer.send_with_priority(("this is the send message".to_string(), 3), Priority::High));
+    assert_eq!(mb.queue_depth(Priority::Normal), 1);
+    assert_eq!(mb.queue_depth(Priority::High), 0);
+    server.stop();
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_remote_worker() {
+    let path = socket_path("worker");
+    let mb = Arc::new(MessageBus::with_retry(4, super::RetryPolicy::new(1, super::Backoff::Fixed(Duration::ZERO))));
+    let server = UnixServer::bind(&path, mb.clone(), NetOptions::default()).unwrap();
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen_ = seen.clone();
+    let wrk = Worker::connect_unix("Worker 1".to_string(), &path, NetOptions::default(), move |msg| {
+        seen_.lock().unwrap().push(msg.1);
+        if msg.1 == 2 { Err("bad msg".to_string()) } else { Ok(()) }
+    }).unwrap();
+    let producer = UnixProducer::connect(&path, NetOptions::default()).unwrap();
+    for i in 1..=3 {
+        assert!(producer.send(("this is the send message".to_string(), i)));
+    }
+    assert!(mb.wait_drained(Duration::from_secs(2)));
+    // the failing msg was retried once and then dead-lettered on the bus
+    assert_eq!(*seen.lock().unwrap(), vec![1, 2, 2, 3]);
+    assert_eq!(wrk.get_cnt(), 4);
+    assert_eq!(mb.dead_letter_cnt(), 1);
+    wrk.stop();
+    server.stop();
+}
+
+#[test]
+fn test_remote_worker_hang_up() {
+    let path = socket_path("hangup");
+    let mb = Arc::new(MessageBus::new(4));
+    let server = UnixServer::bind(&path, mb.clone(), NetOptions::default()).unwrap();
+    let remote = Worker::connect_unix("Worker 1".to_string(), &path, NetOptions::default(), |_| Ok(())).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    remote.stop();
+    std::thread::sleep(Duration::from_millis(100));
+    // msgs sent after the remote worker went away go to the local one
+    let local = Worker::new("Worker 2".to_string(), &mb, false);
+    for i in 1..=3 {
+        assert!(mb.send(("this is the send message".to_string(), i)));
+    }
+    assert!(mb.wait_drained(Duration::from_secs(1)));
+    assert_eq!(local.get_cnt(), 3);
+    local.stop();
+    server.stop();
+}
+
+#[test]
+fn test_bind_in_use() {
+    let path = socket_path("in_use");
+    let mb = Arc::new(MessageBus::new(4));
+    // a plain file is left alone
+    std::fs::write(&path, "not a socket").unwrap();
+    let err = UnixServer::bind(&path, mb.clone(), NetOptions::default()).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+    std::fs::remove_file(&path).unwrap();
+    // so is the socket of a server still running
+    let server = UnixServer::bind(&path, mb.clone(), NetOptions::default()).unwrap();
+    let err = UnixServer::bind(&path, mb.clone(), NetOptions::default()).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    server.stop();
+    // while one nothing answers on is replaced
+    drop(UnixListener::bind(&path).unwrap());
+    let server = UnixServer::bind(&path, mb, NetOptions::default()).unwrap();
+    server.stop();
+}
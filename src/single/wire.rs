@@ -0,0 +1,228 @@
+use std::{io, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread::JoinHandle, time::Duration};
+
+use crate::{event::{self, Event}, net::{self, Caller, Conn, Frame, Link, NetOptions, POLL, invalid}, runtime::{Runtime, WorkerState, sync}, watchdog::Health};
+
+use super::{CancelToken, HandlerResult, JobOutcome, MessageBus, Priority, Worker};
+
+/*
+ * Serving a bus to producers and workers in other processes.
+ * A connection starts with a hello saying which it is, then
+ *   producer: send -> sent(ok), ok is false when the bus had no room like a local send
+ *   worker:   job -> done(result), the bus hands over one msg at a time
+ */
+
+/// Accepts remote producers and workers for a bus until stopped
+pub(super) struct Server {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Server {
+    /// `accept` returns the next waiting connection, None if there isn't one
+    pub(super) fn start<C, A>(mb: Arc<MessageBus>, opts: NetOptions, mut accept: A) -> Self
+    where C: Conn, A: FnMut() -> io::Result<Option<C>> + Send + 'static {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_ = stop.clone();
+        let handle = std::thread::spawn(move || {
+            let mut conns: Vec<JoinHandle<()>> = Vec::new();
+            while !stop_.load(Ordering::SeqCst) {
+                match accept() {
+                    Ok(Some(conn)) => {
+                        let (mb, opts, stop) = (mb.clone(), opts.clone(), stop_.clone());
+                        conns.push(std::thread::spawn(move || {
+                            if let Err(error) = serve_conn(conn, &mb, &opts, &stop) {
+                                event::emit(Event::Error { source: "server".to_string(), error: format!("connection error: {}", error) });
+                            }
+                        }));
+                    },
+                    Ok(None) => std::thread::sleep(POLL),
+                    Err(error) => {
+                        event::emit(Event::Error { source: "server".to_string(), error: format!("accept error: {}", error) });
+                        std::thread::sleep(POLL);
+                    },
+                }
+                conns.retain(|c| !c.is_finished());
+            }
+            for c in conns {
+                let _ = c.join();
+            }
+        });
+        Self { stop, handle }
+    }
+
+    /// stop accepting and drop every connection, remote workers' jobs are cancelled
+    pub(super) fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.handle.join().expect("Failed to join thread");
+    }
+}
+
+fn serve_conn<C: Conn>(conn: C, mb: &MessageBus, opts: &NetOptions, stop: &AtomicBool) -> io::Result<()> {
+    let mut link = Link::new(conn, opts)?;
+    let hello = loop {
+        if stop.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        if let Some(f) = link.recv(POLL)? {
+            break f;
+        }
+    };
+    match hello {
+        Frame::HelloProducer => {
+            while !stop.load(Ordering::SeqCst) {
+                match link.recv(POLL) {
+                    Ok(Some(Frame::Send(priority, msg))) => {
+                        // same backpressure as a local send
+                        let ok = mb.send_with_priority(msg, priority);
+                        link.send(&Frame::Sent(ok))?;
+                    },
+                    Ok(Some(_)) => return Err(invalid("expected a send")),
+                    Ok(None) => {},
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        },
+        Frame::HelloWorker(nm) => {
+            proxy_worker(nm, link, mb, stop);
+            Ok(())
+        },
+        _ => Err(invalid("expected a hello")),
+    }
+}
+
+/*
+ * A remote worker is stood in for by a local worker on the bus that hands each msg over the
+ * connection and waits for the result, so retries, dead letters, partitions and closing work
+ * the same as for a local worker. If the connection is lost the job is abandoned rather than
+ * retried, put back on the bus as it was sent for another worker, and the stand-in takes no more.
+ */
+fn proxy_worker<C: Conn>(nm: String, link: Link<C>, mb: &MessageBus, stop: &AtomicBool) {
+    event::emit(Event::Connected { worker: nm.clone() });
+    let caller = Arc::new(Mutex::new(Caller::new(link)));
+    let gone = Arc::new(sync::AtomicBool::new(false));
+    let waiting = Arc::new(AtomicBool::new(false)); // a job is waiting for the connection
+    let (caller_, gone_, waiting_, nm_) = (caller.clone(), gone.clone(), waiting.clone(), nm.clone());
+    let wrk = Worker::spawn(nm.clone(), mb, move |ctx, worker, job| {
+        let requeue = |job| {
+            worker.pause.pause();
+            ctx.requeue(job);
+            JobOutcome::Cancelled
+        };
+        if gone_.load(Ordering::SeqCst) {
+            return requeue(job);
+        }
+        // losing the connection cancels the job here
+        let token = CancelToken::new(vec![job.cancel.clone(), worker.interrupt.clone(), gone_.clone()], ctx.clock.clone());
+        let outcome = ctx.run_with_retry(worker, std::slice::from_ref(&job.env.msg), &token, || {
+            waiting_.store(true, Ordering::SeqCst);
+            let mut caller = caller_.lock().unwrap();
+            waiting_.store(false, Ordering::SeqCst);
+            match caller.call(&job.env.msg, || token.is_cancelled()) {
+                Ok(result) => result,
+                Err(error) => {
+                    event::emit(Event::Disconnected { worker: nm_.clone(), error: error.to_string() });
+                    gone_.store(true, Ordering::SeqCst);
+                    Err(error.to_string())
+                },
+            }
+        });
+        // unless it was cancelled for some other reason
+        let lost = gone_.load(Ordering::SeqCst) && !job.cancel.load(Ordering::SeqCst);
+        if lost && outcome != JobOutcome::Succeeded {
+            return requeue(job);
+        }
+        outcome
+    });
+    while !stop.load(Ordering::SeqCst) && !gone.load(Ordering::SeqCst) {
+        // the lock isn't fair, so leave the connection to a job waiting for it
+        if waiting.load(Ordering::SeqCst) {
+            std::thread::sleep(Duration::from_millis(1));
+            continue;
+        }
+        // between jobs only heartbeats should arrive, or the late result of a job given up on
+        match caller.try_lock() {
+            Ok(mut c) => {
+                let error = match c.link.recv(POLL) {
+                    Ok(None | Some(Frame::Done(..))) => continue,
+                    Ok(Some(_)) => "expected a heartbeat".to_string(),
+                    Err(error) => error.to_string(),
+                };
+                event::emit(Event::Disconnected { worker: nm.clone(), error });
+                gone.store(true, Ordering::SeqCst);
+            },
+            Err(_) => std::thread::sleep(POLL),
+        }
+    }
+    wrk.stop();
+}
+
+/// Opens a new connection to a bus
+pub(super) type Connect<C> = Box<dyn Fn() -> io::Result<C> + Send + Sync>;
+
+/// Remote producer side of a connection, reconnects when the connection is lost
+pub(super) struct Client<C: Conn> {
+    link: Mutex<Option<Link<C>>>,
+    connect: Connect<C>,
+    opts: NetOptions,
+}
+
+impl<C: Conn> Client<C> {
+    pub(super) fn producer(connect: Connect<C>, opts: NetOptions) -> io::Result<Self> {
+        let link = connect_producer(&connect, &opts, Duration::ZERO)?;
+        Ok(Self { link: Mutex::new(Some(link)), connect, opts })
+    }
+
+    /// a msg whose reply was lost with the connection is sent again once reconnected,
+    /// so the bus can get it twice
+    pub(super) fn send(&self, msg: (String, usize), priority: Priority) -> bool {
+        let mut link = self.link.lock().unwrap();
+        event::emit(Event::MessageSent { msg: msg.clone() });
+        // on the current connection and then once more on a new one
+        for _ in 0..2 {
+            if link.is_none() {
+                match connect_producer(&self.connect, &self.opts, self.opts.dead_after) {
+                    Ok(l) => *link = Some(l),
+                    Err(error) => {
+                        event::emit(Event::SendFailed { msg, error: error.to_string() });
+                        return false;
+                    },
+                }
+            }
+            let l = link.as_mut().unwrap();
+            match l.send(&Frame::Send(priority, msg.clone())).and_then(|_| l.recv_wait()) {
+                Ok(Frame::Sent(ok)) => return ok,
+                Ok(_) => event::emit(Event::Error { source: "producer".to_string(), error: "unexpected reply".to_string() }),
+                Err(error) => event::emit(Event::Error { source: "producer".to_string(), error: error.to_string() }),
+            }
+            *link = None;
+        }
+        event::emit(Event::SendFailed { msg, error: "connection lost".to_string() });
+        false
+    }
+}
+
+/// connect and say hello as a producer, retrying with backoff until `give_up` has passed
+fn connect_producer<C: Conn>(connect: &Connect<C>, opts: &NetOptions, give_up: Duration) -> io::Result<Link<C>> {
+    let link = net::connect_for(connect, opts, give_up)?;
+    link.send(&Frame::HelloProducer)?;
+    Ok(link)
+}
+
+impl Worker {
+    /// create a worker that takes msgs from a bus in another process,
+    /// reconnecting with backoff whenever the connection is lost
+    pub(super) fn remote<C, F>(nm: String, first: C, connect: Connect<C>, opts: NetOptions, handler: F) -> Self
+    where C: Conn, F: Fn(&(String, usize)) -> HandlerResult + Send + 'static {
+        // the handler gets no token so its jobs can't be cancelled
+        let state = WorkerState::new(Health::new(&nm, || {}));
+        let runtime = Runtime::spawn(state, move |state| {
+            net::reconnecting(&nm, first, connect, &opts, &state.interrupt, |mut link| {
+                link.send(&Frame::HelloWorker(nm.clone()))?;
+                net::serve_jobs(&mut link, state, &handler)
+            });
+        });
+        Self { runtime, memberships: Vec::new(), current: Arc::default() }
+    }
+}
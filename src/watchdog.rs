@@ -0,0 +1,361 @@
+use std::{sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread::JoinHandle, time::{Duration, Instant}};
+
+use crate::{clock::{self, Clock}, event::{self, Event}};
+
+/*
+ * A worker running a long job looks the same as a hung one from outside, so every worker
+ * keeps a Health saying since when it has been on its current msg. A Watchdog checks the
+ * Health of the workers it is given and steps in on those over its threshold.
+ */
+
+struct Busy {
+    since: Option<Instant>, // when the current msg was taken, None while idle
+    healthy: bool,
+}
+
+/// How long a worker has been on its current msg, shared between the worker and a watchdog
+pub struct Health {
+    nm: String,
+    busy: Mutex<Busy>,
+    clock: Arc<dyn Clock>, // times how long the worker has been busy
+    cancel: Box<dyn Fn() + Send + Sync>, // cancels the worker's current msg
+}
+
+impl Health {
+    pub(crate) fn new(nm: &str, cancel: impl Fn() + Send + Sync + 'static) -> Arc<Self> {
+        Self::with_clock(nm, clock::real(), cancel)
+    }
+
+    /// timed by the given clock, for workers on a bus with its own clock
+    pub(crate) fn with_clock(nm: &str, clock: Arc<dyn Clock>, cancel: impl Fn() + Send + Sync + 'static) -> Arc<Self> {
+        Arc::new(Self { nm: nm.to_string(), busy: Mutex::new(Busy { since: None, healthy: true }), clock, cancel: Box::new(cancel) })
+    }
+
+    pub fn nm(&self) -> &str { &self.nm }
+
+    /// the worker took a msg, a worker already busy (on a batch) keeps its earlier start
+    pub(crate) fn start(&self) {
+        let mut busy = self.busy.lock().unwrap();
+        busy.since.get_or_insert_with(|| self.clock.now());
+    }
+
+    /// the worker is done with its msg, which makes it healthy again
+    pub(crate) fn done(&self) {
+        let mut busy = self.busy.lock().unwrap();
+        busy.since = None;
+        busy.healthy = true;
+    }
+
+    /// how long the worker has been on its current msg, None if it is idle
+    pub fn busy_for(&self) -> Option<Duration> {
+        let since = self.busy.lock().unwrap().since?;
+        Some(self.clock.now().saturating_duration_since(since))
+    }
+
+    /// false once a watchdog found the worker over its threshold, until the msg is done
+    pub fn is_healthy(&self) -> bool {
+        self.busy.lock().unwrap().healthy
+    }
+
+    /// cancel the msg the worker is on, it carries on with the next one
+    pub fn cancel_job(&self) {
+        (self.cancel)();
+    }
+
+    /// mark the worker unhealthy if it went over the threshold,
+    /// returns how long it has been busy the first time only
+    fn check(&self, threshold: Duration) -> Option<Duration> {
+        let mut busy = self.busy.lock().unwrap();
+        let busy_for = self.clock.now().saturating_duration_since(busy.since?);
+        if busy_for < threshold || !busy.healthy {
+            return None;
+        }
+        busy.healthy = false;
+        Some(busy_for)
+    }
+}
+
+/// A worker a watchdog can look after
+pub trait Watched: Send + 'static {
+    fn health(&self) -> Arc<Health>;
+    /// tell the worker to exit without waiting for it, its current msg is cancelled
+    fn retire(&self);
+    /// the worker's thread has exited
+    fn is_finished(&self) -> bool;
+    /// signal the worker to stop and wait until it does
+    fn stop(self);
+}
+
+/// What a watchdog does about a worker over its threshold, on top of marking it unhealthy
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnStuck {
+    /// only call the watchdog's callback
+    Report,
+    /// cancel the msg the worker is on
+    CancelJob,
+    /// retire the worker and start a new one in its place, only for workers the watchdog owns
+    Replace,
+}
+
+/// Passed to the watchdog's callback when a worker goes over the threshold
+#[derive(Clone, Debug)]
+pub struct Stuck {
+    pub nm: String,
+    pub busy_for: Duration,
+    pub action: OnStuck,
+}
+
+/// A worker owned by the watchdog along with how to make another
+trait Supervised: Send {
+    fn health(&self) -> Arc<Health>;
+    /// retire the worker and make a new one
+    fn replace(&mut self);
+    /// stop retired workers that have exited since
+    fn reap(&mut self);
+    fn stop(self: Box<Self>);
+}
+
+struct Owned<W, F> {
+    wrk: W,
+    factory: F,
+    retired: Vec<W>, // replaced but possibly still stuck on their last msg
+}
+
+impl<W: Watched, F: Fn() -> W + Send> Supervised for Owned<W, F> {
+    fn health(&self) -> Arc<Health> { self.wrk.health() }
+
+    fn replace(&mut self) {
+        let old = std::mem::replace(&mut self.wrk, (self.factory)());
+        old.retire();
+        self.retired.push(old);
+    }
+
+    fn reap(&mut self) {
+        for old in std::mem::take(&mut self.retired) {
+            if old.is_finished() {
+                old.stop();
+            } else {
+                self.retired.push(old);
+            }
+        }
+    }
+
+    fn stop(self: Box<Self>) {
+        self.wrk.stop();
+        for old in self.retired {
+            old.stop();
+        }
+    }
+}
+
+struct Slot {
+    health: Arc<Health>,
+    on_stuck: OnStuck,
+    owned: Option<Box<dyn Supervised>>,
+}
+
+/// Checks on workers and steps in on any that stay on one msg longer than a threshold
+pub struct Watchdog {
+    slots: Arc<Mutex<Vec<Slot>>>,
+    handle: JoinHandle<()>,
+    interrupt: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    /// start checking, `on_stuck` is called once each time a worker goes over the threshold on a msg
+    pub fn start<F>(threshold: Duration, on_stuck: F) -> Self
+    where F: Fn(&Stuck) + Send + 'static {
+        Self::with_clock(threshold, clock::real(), on_stuck)
+    }
+
+    /// start checking every so often on the given clock, the workers' busy time is on their own clock
+    pub fn with_clock<F>(threshold: Duration, clock: Arc<dyn Clock>, on_stuck: F) -> Self
+    where F: Fn(&Stuck) + Send + 'static {
+        let slots: Arc<Mutex<Vec<Slot>>> = Arc::default();
+        let intr = Arc::new(AtomicBool::new(false));
+        let (slots_, interrupt) = (slots.clone(), intr.clone());
+        let every = (threshold / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        let handle = std::thread::spawn(move || {
+            'check: loop {
+                // wait on the clock but notice being stopped in real time
+                let timer = clock.after(every);
+                while timer.rx().recv_timeout(Duration::from_millis(10)).is_err() {
+                    if intr.load(Ordering::SeqCst) {
+                        break 'check;
+                    }
+                }
+                let mut stuck = Vec::new();
+                for slot in slots_.lock().unwrap().iter_mut() {
+                    if let Some(owned) = &mut slot.owned {
+                        owned.reap();
+                    }
+                    let Some(busy_for) = slot.health.check(threshold) else { continue };
+                    let nm = slot.health.nm().to_string();
+                    event::emit(Event::WorkerStuck { worker: nm.clone(), busy_for });
+                    match (slot.on_stuck, &mut slot.owned) {
+                        (OnStuck::Report, _) => {},
+                        (OnStuck::Replace, Some(owned)) => {
+                            owned.replace();
+                            slot.health = owned.health();
+                        },
+                        (OnStuck::CancelJob | OnStuck::Replace, _) => slot.health.cancel_job(),
+                    }
+                    stuck.push(Stuck { nm, busy_for, action: slot.on_stuck });
+                }
+                // outside the lock so the callback can use the watchdog
+                for s in stuck.iter() {
+                    on_stuck(s);
+                }
+            }
+        });
+        Self { slots, handle, interrupt }
+    }
+
+    /// watch a worker owned elsewhere, Replace is taken as CancelJob as the watchdog can't replace it
+    pub fn watch(&self, wrk: &impl Watched, on_stuck: OnStuck) {
+        self.slots.lock().unwrap().push(Slot { health: wrk.health(), on_stuck, owned: None });
+    }
+
+    /// own a worker made by the factory, which is called again for each replacement
+    /// the factory must make a worker that finds its own msgs, such as one on a single bus
+    /// or a broadcast log subscriber, as nothing else knows about the replacement
+    pub fn supervise<W, F>(&self, factory: F, on_stuck: OnStuck)
+    where W: Watched, F: Fn() -> W + Send + 'static {
+        let wrk = factory();
+        let health = wrk.health();
+        let owned = Owned { wrk, factory, retired: Vec::new() };
+        self.slots.lock().unwrap().push(Slot { health, on_stuck, owned: Some(Box::new(owned)) });
+    }
+
+    /// names of the workers currently over the threshold
+    pub fn unhealthy(&self) -> Vec<String> {
+        self.slots.lock().unwrap().iter().filter(|s| !s.health.is_healthy()).map(|s| s.health.nm().to_string()).collect()
+    }
+
+    /// stop checking and stop every worker the watchdog owns,
+    /// waiting for any still stuck on a msg that ignores being cancelled
+    pub fn stop(self) {
+        self.interrupt.store(true, Ordering::SeqCst);
+        self.handle.join().expect("Failed to join thread");
+        for slot in self.slots.lock().unwrap().drain(..) {
+            if let Some(owned) = slot.owned {
+                owned.stop();
+            }
+        }
+    }
+}
+
+#[test]
+fn test_watch_cancels_stuck_job() {
+    use crate::single::{MessageBus, Worker};
+
+    let mb = MessageBus::new(4);
+    let finished = Arc::new(Mutex::new(Vec::new()));
+    let finished_ = finished.clone();
+    let wrk = Worker::with_cancellable_handler("Worker 1".to_string(), &mb, move |msg, token| {
+        if token.sleep(Duration::from_millis(if msg.1 == 1 { 5000 } else { 10 })) {
+            finished_.lock().unwrap().push(msg.1);
+        }
+        Ok(())
+    });
+    let stuck = Arc::new(Mutex::new(Vec::new()));
+    let stuck_ = stuck.clone();
+    let dog = Watchdog::start(Duration::from_millis(100), move |s| stuck_.lock().unwrap().push(s.nm.clone()));
+    dog.watch(&wrk, OnStuck::CancelJob);
+    assert!(mb.send(("this is the send message".to_string(), 1)));
+    assert!(mb.send(("this is the send message".to_string(), 2)));
+    assert!(mb.wait_drained(Duration::from_secs(1)));
+    // reported once, and healthy again once the cancelled job was done
+    assert_eq!(*stuck.lock().unwrap(), vec!["Worker 1".to_string()]);
+    assert_eq!(*finished.lock().unwrap(), vec![2]);
+    assert!(wrk.health().is_healthy());
+    assert_eq!(wrk.health().busy_for(), None);
+    dog.stop();
+    wrk.stop();
+}
+
+#[test]
+fn test_report_marks_unhealthy() {
+    use crate::broadcast::Worker;
+
+    let wrk = Worker::new("Worker 1".to_string(), true);
+    let dog = Watchdog::start(Duration::from_millis(100), |_| {});
+    dog.watch(&wrk, OnStuck::Report);
+    assert!(wrk.send(("this is the msg".to_string(), 1)));
+    std::thread::sleep(Duration::from_millis(300));
+    // left to run, the worker stays unhealthy until its msg is done
+    assert_eq!(dog.unhealthy(), vec!["Worker 1".to_string()]);
+    assert!(!wrk.health().is_healthy());
+    assert!(wrk.health().busy_for().unwrap() >= Duration::from_millis(100));
+    wrk.health().cancel_job();
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(dog.unhealthy().is_empty());
+    dog.stop();
+    wrk.stop();
+}
+
+#[test]
+fn test_supervise_replaces_hung_worker() {
+    use crate::single::{MessageBus, Worker};
+
+    let mb = Arc::new(MessageBus::new(4));
+    let done = Arc::new(Mutex::new(Vec::new()));
+    let (mb_, done_) = (mb.clone(), done.clone());
+    let dog = Watchdog::start(Duration::from_millis(100), |_| {});
+    dog.supervise(move || {
+        let done = done_.clone();
+        Worker::with_handler("Worker 1".to_string(), &mb_, move |msg| {
+            if msg.1 == 1 {
+                // hung, deaf to being cancelled
+                std::thread::sleep(Duration::from_millis(500));
+            }
+            done.lock().unwrap().push(msg.1);
+            Ok(())
+        })
+    }, OnStuck::Replace);
+    assert!(mb.send(("this is the send message".to_string(), 1)));
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(mb.send(("this is the send message".to_string(), 2)));
+    // the replacement takes msg 2 while the hung worker is still on msg 1
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(*done.lock().unwrap(), vec![2]);
+    assert!(dog.unhealthy().is_empty());
+    dog.stop();
+    assert_eq!(*done.lock().unwrap(), vec![2, 1]);
+}
+
+#[test]
+fn test_watch_on_bus_clock() {
+    use crate::{clock::MockClock, single::{MessageBus, Worker}};
+
+    let clock = Arc::new(MockClock::new());
+    let mut mb = MessageBus::new(4);
+    mb.set_clock(clock.clone());
+    let wrk = Worker::with_cancellable_handler("Worker 1".to_string(), &mb, |_, token| {
+        while !token.is_cancelled() {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        Ok(())
+    });
+    let stuck = Arc::new(Mutex::new(Vec::new()));
+    let stuck_ = stuck.clone();
+    let dog = Watchdog::with_clock(Duration::from_secs(2), clock.clone(), move |s| stuck_.lock().unwrap().push(s.busy_for));
+    dog.watch(&wrk, OnStuck::CancelJob);
+    assert!(mb.send(("this is the send message".to_string(), 1)));
+    while wrk.health().busy_for().is_none() {
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    // real time passing doesn't make the worker stuck
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(stuck.lock().unwrap().is_empty());
+    assert_eq!(wrk.health().busy_for(), Some(Duration::ZERO));
+    // checked every 500ms on the clock
+    for n in 1..=4 {
+        clock.wait_for_timer(Duration::from_millis(500 * n));
+        clock.advance(Duration::from_millis(500));
+    }
+    assert!(mb.wait_drained(Duration::from_secs(1)));
+    assert_eq!(*stuck.lock().unwrap(), vec![Duration::from_secs(2)]);
+    dog.stop();
+    wrk.stop();
+}